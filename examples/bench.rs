@@ -0,0 +1,174 @@
+//! Time the decoder on audio held in memory, without sound hardware, for
+//! comparing the speed of the decode path before and after a change.
+//!
+//! Run with `cargo run --release --example bench -- [FILE...]`, or use
+//! `scripts/bench.sh` for FLAC and MP3 fixtures made with sox. Generated
+//! WAV tones in each sample format the output converts to are always
+//! timed, at 44.1 kHz and 192 kHz, each at full volume and at half volume
+//! to show the cost of applying a gain.
+//!
+//! Each case is decoded several times and the fastest run is reported, as
+//! seconds of audio decoded per second of wall time. Output is one tab
+//! separated line per case, so two runs on the same machine can be put
+//! side by side with e.g. `paste`.
+
+use std::{
+    f32::consts::PI,
+    fs,
+    io::Cursor,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use slimproto::proto::{Format, PcmChannels, PcmSampleRate};
+use symphonia::core::io::MediaSourceStream;
+use vibe::{
+    decode::{DecodeOptions, Decoder, DecoderError},
+    volume::Volume,
+};
+
+const RUNS: usize = 5;
+const TONE_SECS: u32 = 10;
+const CHUNK: usize = 64 * 1024;
+
+// The sample formats of generated tones, as the WAV format tag and bits
+// per sample
+const TONES: [(&str, u16, u16); 3] = [("s16", 1, 16), ("s32", 1, 32), ("f32", 3, 32)];
+
+// A stereo sine tone as a WAV file
+fn make_wav(rate: u32, format_tag: u16, bits: u16) -> Vec<u8> {
+    let channels = 2u16;
+    let frames = rate * TONE_SECS;
+    let block_align = channels * bits / 8;
+    let data_len = frames * block_align as u32;
+
+    let mut wav = Vec::with_capacity(44 + data_len as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&format_tag.to_le_bytes());
+    wav.extend_from_slice(&channels.to_le_bytes());
+    wav.extend_from_slice(&rate.to_le_bytes());
+    wav.extend_from_slice(&(rate * block_align as u32).to_le_bytes());
+    wav.extend_from_slice(&block_align.to_le_bytes());
+    wav.extend_from_slice(&bits.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_len.to_le_bytes());
+
+    for frame in 0..frames {
+        let sample = 0.5 * (2.0 * PI * 440.0 * frame as f32 / rate as f32).sin();
+        for _ in 0..channels {
+            match (format_tag, bits) {
+                (3, _) => wav.extend_from_slice(&sample.to_le_bytes()),
+                (_, 16) => {
+                    wav.extend_from_slice(&((sample * i16::MAX as f32) as i16).to_le_bytes())
+                }
+                _ => wav.extend_from_slice(&((sample * i32::MAX as f32) as i32).to_le_bytes()),
+            }
+        }
+    }
+    wav
+}
+
+fn open(data: &[u8], format: &Format) -> anyhow::Result<Decoder> {
+    let mss = MediaSourceStream::new(Box::new(Cursor::new(data.to_vec())), Default::default());
+    Ok(Decoder::try_new(
+        mss,
+        format,
+        &PcmSampleRate::SelfDescribing,
+        &PcmChannels::SelfDescribing,
+        &DecodeOptions::default(),
+    )?)
+}
+
+// Decode the whole of the data as the output would, returning how much
+// audio there was and how long it took
+fn decode_all(data: &[u8], format: &Format, gain: f32) -> anyhow::Result<(Duration, Duration)> {
+    let mut decoder = open(data, format)?;
+    let mut volume = Volume::new();
+    volume.set(gain, gain);
+    let volume = Arc::new(Mutex::new(volume));
+
+    let start = Instant::now();
+    let mut audio = Duration::ZERO;
+    loop {
+        let (result, decoded) = fill(&mut decoder, volume.clone());
+        audio += decoded;
+        match result {
+            Ok(()) | Err(DecoderError::Retry | DecoderError::Chained) => {}
+            Err(DecoderError::EndOfDecode) => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok((audio, start.elapsed()))
+}
+
+// Fill one output buffer, as pulse does, and say how much audio it held
+#[cfg(feature = "pulse")]
+fn fill(decoder: &mut Decoder, volume: Arc<Mutex<Volume>>) -> (Result<(), DecoderError>, Duration) {
+    let mut buffer = Vec::with_capacity(CHUNK);
+    let result = decoder.fill_raw_buffer(&mut buffer, Some(CHUNK), volume);
+    (result, decoder.bytes_to_dur(buffer.len()))
+}
+
+// Fill one output buffer, as rodio does, and say how much audio it held
+#[cfg(not(feature = "pulse"))]
+fn fill(decoder: &mut Decoder, volume: Arc<Mutex<Volume>>) -> (Result<(), DecoderError>, Duration) {
+    let mut buffer = Vec::<f32>::with_capacity(CHUNK);
+    let result = decoder.fill_sample_buffer(&mut buffer, Some(CHUNK), volume);
+    let samples_per_sec = decoder.sample_rate() as u64 * decoder.channels() as u64;
+    let decoded = Duration::from_micros(buffer.len() as u64 * 1_000_000 / samples_per_sec.max(1));
+    (result, decoded)
+}
+
+fn bench(name: &str, data: &[u8], format: &Format) -> anyhow::Result<()> {
+    for (gain, label) in [(1.0, "full"), (0.5, "half")] {
+        let mut best: Option<(Duration, Duration)> = None;
+        for _ in 0..RUNS {
+            let run = decode_all(data, format, gain).with_context(|| name.to_owned())?;
+            if !matches!(best, Some(best) if best.1 <= run.1) {
+                best = Some(run);
+            }
+        }
+        if let Some((audio, wall)) = best {
+            println!(
+                "{name}\t{label} volume\t{:.1}s\t{:.3}s\t{:.0}x",
+                audio.as_secs_f64(),
+                wall.as_secs_f64(),
+                audio.as_secs_f64() / wall.as_secs_f64().max(f64::EPSILON)
+            );
+        }
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    println!("case\tvolume\taudio\twall\tspeed");
+
+    for rate in [44_100, 192_000] {
+        for (name, format_tag, bits) in TONES {
+            let wav = make_wav(rate, format_tag, bits);
+            bench(&format!("wav {name} {rate} Hz"), &wav, &Format::Pcm)?;
+        }
+    }
+
+    for path in std::env::args().skip(1).map(PathBuf::from) {
+        let data = fs::read(&path).with_context(|| format!("Unable to read {}", path.display()))?;
+        let format = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("flac") => Format::Flac,
+            Some("mp3") => Format::Mp3,
+            Some("ogg") => Format::Ogg,
+            Some("m4a" | "aac") => Format::Aac,
+            _ => Format::Pcm,
+        };
+        let name = path.file_name().map_or_else(
+            || path.display().to_string(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        bench(&name, &data, &format)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,29 @@
+//! A sample plugin for `vibe --dsp-plugin` that halves the volume.
+//!
+//! Build it with `cargo build --release --example dsp_gain` and pass the
+//! path of the resulting `libdsp_gain.so` to vibe.
+
+/// Scale every sample in place. Returning non-zero tells vibe that the
+/// plugin has failed, after which it plays without it.
+///
+/// # Safety
+///
+/// `samples` must point to `len` samples that nothing else touches during
+/// the call, as vibe passes.
+#[no_mangle]
+pub unsafe extern "C" fn vibe_dsp_process(
+    samples: *mut f32,
+    len: usize,
+    _channels: u32,
+    _rate: u32,
+) -> i32 {
+    let samples = std::slice::from_raw_parts_mut(samples, len);
+    samples.iter_mut().for_each(|s| *s *= 0.5);
+    0
+}
+
+/// A plain gain adds no delay.
+#[no_mangle]
+pub extern "C" fn vibe_dsp_latency(_rate: u32) -> u32 {
+    0
+}
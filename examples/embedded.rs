@@ -0,0 +1,114 @@
+//! A minimal player built from the vibe library.
+//!
+//! Run with `cargo run --example embedded -- [SERVER_IP]`, the server is
+//! found by autodiscovery if no address is given.
+
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crossbeam::{
+    atomic::AtomicCell,
+    channel::{bounded, unbounded, Select},
+};
+use slimproto::{proto::SLIM_PORT, status::StatusData};
+use vibe::{
+    audio_out::{make_audio_output, Interrupts, OutputOptions, DEFAULT_SYSTEM},
+    decode::StreamOptions,
+    message::{process_slim_msg, process_stream_msg, Clock},
+    player_name::PlayerName,
+    proto::{self, ConnectionHistory, ConnectionInfo},
+    stats::Stats,
+    sync::DriftTracker,
+    volume::Volume,
+};
+
+fn main() -> anyhow::Result<()> {
+    let server = match std::env::args().nth(1) {
+        Some(ip) => Some(SocketAddrV4::new(ip.parse::<Ipv4Addr>()?, SLIM_PORT)),
+        None => None,
+    };
+
+    let interrupts = Interrupts::new();
+    let mut output = make_audio_output(
+        DEFAULT_SYSTEM,
+        &OutputOptions::default(),
+        interrupts.clone(),
+    )?;
+    let name = Arc::new(PlayerName::new());
+    name.set_from_cli("Embedded Vibe".to_owned());
+    let status = Arc::new(Mutex::new(StatusData::default()));
+    let volume = Arc::new(Mutex::new(Volume::new()));
+    let skip = Arc::new(AtomicCell::new(Duration::ZERO));
+    let mut drift = DriftTracker::new(false);
+    let stats = Arc::new(Stats::new());
+    let clock = Clock::new();
+    let stream_options = Arc::new(StreamOptions::default());
+    let mut server_default_ip = *server.unwrap_or(SocketAddrV4::new(0.into(), 0)).ip();
+
+    let (slim_tx_in, slim_tx_out) = bounded(32);
+    let (slim_rx_in, slim_rx_out) = bounded(8);
+    let history = Arc::new(Mutex::new(ConnectionHistory::new()));
+    let conn_info = Arc::new(Mutex::new(ConnectionInfo::new()));
+    proto::run(
+        name.clone(),
+        proto::Settings {
+            servers: server.into_iter().collect(),
+            ..Default::default()
+        },
+        slim_rx_in,
+        slim_tx_out,
+        history,
+        conn_info,
+        interrupts,
+    );
+
+    // Unbounded, as the output sends on it from the loop below
+    let (stream_in, stream_out) = unbounded();
+    let mut select = Select::new();
+    let slim_idx = select.recv(&slim_rx_out);
+    let stream_idx = select.recv(&stream_out);
+
+    loop {
+        match select.select() {
+            op if op.index() == slim_idx => match op.recv(&slim_rx_out)? {
+                Some(msg) => process_slim_msg(
+                    &mut output,
+                    msg,
+                    &mut server_default_ip,
+                    name.clone(),
+                    slim_tx_in.clone(),
+                    volume.clone(),
+                    status.clone(),
+                    stream_in.clone(),
+                    skip.clone(),
+                    stats.clone(),
+                    &mut drift,
+                    clock,
+                    stream_options.clone(),
+                )?,
+                None => {
+                    println!("Lost contact with server");
+                    output.stop();
+                    return Ok(());
+                }
+            },
+            op if op.index() == stream_idx => {
+                let msg = op.recv(&stream_out)?;
+                process_stream_msg(
+                    msg,
+                    status.clone(),
+                    slim_tx_in.clone(),
+                    &mut output,
+                    stream_in.clone(),
+                    clock,
+                    #[cfg(feature = "notify")]
+                    &true,
+                );
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Play a local file through one audio system and check that it went
+//! through the output as expected, for smoke testing a backend.
+//!
+//! Run with `cargo run --example smoke -- SYSTEM FILE`, e.g. against the
+//! null sink started by `scripts/smoke-audio.sh`. Exits with an error if
+//! the track does not start, finish and drain, or nothing was written.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use crossbeam::channel::unbounded;
+use vibe::{
+    audio_out::{make_audio_output, Interrupts, OutputOptions, WriteMarker},
+    decode::{self, DecodeOptions},
+    message::PlayerMsg,
+    volume::Volume,
+    StreamParams,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn name(msg: &PlayerMsg) -> &'static str {
+    match msg {
+        PlayerMsg::EndOfDecode => "EndOfDecode",
+        PlayerMsg::Drained => "Drained",
+        PlayerMsg::Pause => "Pause",
+        PlayerMsg::Unpause => "Unpause",
+        PlayerMsg::Resume => "Resume",
+        PlayerMsg::Connected => "Connected",
+        PlayerMsg::BufferThreshold => "BufferThreshold",
+        PlayerMsg::NotSupported => "NotSupported",
+        PlayerMsg::StreamEstablished => "StreamEstablished",
+        PlayerMsg::StreamStalled => "StreamStalled",
+        PlayerMsg::TrackStarted => "TrackStarted",
+        PlayerMsg::EndOfHold(_) => "EndOfHold",
+        PlayerMsg::Decoder(_) => "Decoder",
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let (Some(system), Some(path)) = (args.next(), args.next().map(PathBuf::from)) else {
+        bail!("Usage: smoke SYSTEM FILE");
+    };
+
+    let mut output = make_audio_output(&system, &OutputOptions::default(), Interrupts::new())
+        .with_context(|| format!("Unable to open {system} output"))?;
+    let decoder = decode::make_file_decoder(&path, &DecodeOptions::default())?;
+    let written = WriteMarker::new();
+    let (stream_in, stream_out) = unbounded();
+    output.enqueue_new_stream(
+        decoder,
+        stream_in,
+        StreamParams::local(Arc::new(Mutex::new(Volume::new())), written.clone()),
+    );
+
+    let mut seen = Vec::new();
+    while let Ok(msg) = stream_out.recv_timeout(TIMEOUT) {
+        seen.push(name(&msg));
+        if matches!(msg, PlayerMsg::Drained | PlayerMsg::NotSupported) {
+            break;
+        }
+    }
+    output.stop();
+
+    println!(
+        "{system}: {:.1}s written, {}",
+        written.written().as_secs_f32(),
+        seen.join(" ")
+    );
+    // A short file can be decoded to the end before the track starts, so
+    // the order of the two is not checked
+    if !seen.contains(&"TrackStarted")
+        || !seen.contains(&"EndOfDecode")
+        || seen.last() != Some(&"Drained")
+    {
+        bail!("{system}: unexpected messages");
+    }
+    if written.written().is_zero() {
+        bail!("{system}: nothing was written to the output");
+    }
+    Ok(())
+}
@@ -0,0 +1,18 @@
+#!/bin/sh
+# Time the decoder on FLAC and MP3 fixtures at 44.1 kHz and 192 kHz, as
+# well as the WAV tones generated by examples/bench.rs. Needs sox, with
+# MP3 support for the MP3 fixtures, which are skipped without it. Extra
+# cargo arguments, e.g. --no-default-features --features rodio, are
+# passed on. Run from the root of the repository.
+set -e
+
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+
+for rate in 44100 192000; do
+    sox -n -r $rate -c 2 -b 24 "$work/tone-$rate.flac" synth 30 sine 440 vol 0.5
+    sox -n -r $rate -c 2 "$work/tone-$rate.mp3" synth 30 sine 440 vol 0.5 2>/dev/null \
+        || echo "No MP3 support in sox, skipping the $rate Hz MP3 fixture" >&2
+done
+
+cargo run --release "$@" --example bench -- "$work"/tone-*
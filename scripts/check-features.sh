@@ -0,0 +1,23 @@
+#!/bin/sh
+# Check that every supported combination of feature flags compiles.
+# Run from the root of the repository.
+set -e
+
+check() {
+    echo "cargo check $*"
+    cargo check --all-targets "$@"
+}
+
+check
+check --features notify
+check --features rodio
+check --features rodio,notify
+check --features dsp
+check --features scrobble
+check --features notify,scrobble
+check --features tls
+check --no-default-features --features rodio
+check --no-default-features --features rodio,notify
+check --no-default-features --features minimal
+check --no-default-features --features desktop
+check --no-default-features --features full
@@ -0,0 +1,35 @@
+#!/bin/sh
+# Smoke test each audio output against a headless PulseAudio null sink,
+# checking that a short FLAC file plays through and reaches the sink.
+# Needs pulseaudio, pulseaudio-utils and sox, and for rodio the ALSA pulse
+# plugin (pulseaudio or alsa-plugins-pulseaudio, depending on the distro).
+# Run from the root of the repository, as a user without a running sound
+# server, e.g. in a CI container.
+set -e
+
+work=$(mktemp -d)
+trap 'pulseaudio --kill 2>/dev/null || true; rm -rf "$work"' EXIT
+
+pulseaudio --daemonize=yes --exit-idle-time=-1 --disallow-exit \
+    --load="module-null-sink sink_name=smoke" \
+    --load="module-native-protocol-unix"
+pactl set-default-sink smoke
+
+sox -n -r 44100 -c 2 "$work/tone.flac" synth 2 sine 440 vol 0.5
+
+smoke() {
+    system=$1
+    shift
+    parec --device=smoke.monitor --raw >"$work/$system.raw" &
+    capture=$!
+    cargo run --quiet --example smoke "$@" -- "$system" "$work/tone.flac"
+    kill $capture
+    # The monitor records silence when nothing is playing
+    if [ "$(tr -d '\000' <"$work/$system.raw" | wc -c)" -eq 0 ]; then
+        echo "$system: nothing reached the sink"
+        exit 1
+    fi
+}
+
+smoke pulse
+smoke rodio --features rodio
@@ -1,111 +1,668 @@
-use std::time::Duration;
+use std::{
+    mem,
+    ops::RangeInclusive,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow;
-use crossbeam::channel::Sender;
+use crossbeam::{
+    atomic::AtomicCell,
+    channel::{unbounded, Sender},
+};
+use log::info;
+use slimproto::proto::AutoStart;
 
-use crate::{decode::Decoder, message::PlayerMsg, StreamParams};
+use crate::{
+    decode::{self, DecodeOptions, Decoder},
+    message::PlayerMsg,
+    volume::Volume,
+    StreamParams,
+};
 
+#[cfg(test)]
+use crate::null_out;
 #[cfg(feature = "pulse")]
 use crate::pulse_out;
 #[cfg(feature = "rodio")]
 use crate::rodio_out;
 
-pub enum AudioOutput {
+/// How far into a stream the audio handed to the audio system reaches.
+/// Comparing this with the playback position gives the time a buffer
+/// takes from being written to being heard.
+#[derive(Clone, Default)]
+pub struct WriteMarker(Arc<AtomicCell<Duration>>);
+
+impl WriteMarker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Move the marker on by the duration of audio just written.
+    /// There must only be one writer.
+    pub fn advance(&self, dur: Duration) {
+        self.0.store(self.0.load() + dur);
+    }
+
+    pub fn written(&self) -> Duration {
+        self.0.load()
+    }
+}
+
+// After a pause at least this long the buffer is refilled before resuming
+const REFILL_AFTER: Duration = Duration::from_secs(60);
+
+/// By default no more than this much audio is decoded ahead of playback.
+pub const DEFAULT_MAX_DECODE_AHEAD: Duration = Duration::from_secs(30);
+
+/// How the output plays, as chosen when it is created.
+#[derive(Clone, Debug)]
+pub struct OutputOptions {
+    /// The device to play on, else the audio system's default.
+    pub device: Option<String>,
+    /// Switch the device to this port, e.g. HDMI rather than analog, where
+    /// the audio system allows.
+    pub device_port: Option<String>,
+    /// Play each track on the device of the first rule its sample rate
+    /// matches, or on the output's own device when none does.
+    pub device_map: Vec<DeviceRule>,
+    /// How far ahead of playback audio is decoded, which bounds memory use
+    /// however long the track.
+    pub max_decode_ahead: Duration,
+    /// Refill the buffer before resuming after a long pause, so that
+    /// playback does not stutter while it catches up.
+    pub refill_on_resume: bool,
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        Self {
+            device: None,
+            device_port: None,
+            device_map: Vec::new(),
+            max_decode_ahead: DEFAULT_MAX_DECODE_AHEAD,
+            refill_on_resume: true,
+        }
+    }
+}
+
+impl OutputOptions {
+    /// The device a track at `sample_rate` is to be played on, if a rule
+    /// says so.
+    pub(crate) fn mapped_device(&self, sample_rate: u32) -> Option<&str> {
+        self.device_map
+            .iter()
+            .find(|rule| rule.matches(sample_rate))
+            .map(|rule| rule.device.as_str())
+    }
+}
+
+/// What an output shares with the streams feeding it and with its audio
+/// system's callbacks, which run on threads of their own.
+#[derive(Default)]
+pub struct OutputState {
+    // Whether the playing stream is short of audio, so that streams
+    // prebuffering behind it can back off
+    starved: AtomicBool,
+    paused_at: AtomicCell<Option<Instant>>,
+    // Silence to play ahead of the rest of the audio, for pauses too short
+    // for the audio system to make in time
+    silence_owed: AtomicCell<Duration>,
+}
+
+impl OutputState {
+    pub(crate) fn set_starved(&self, starved: bool) {
+        self.starved.store(starved, Ordering::Relaxed);
+    }
+
+    pub(crate) fn starved(&self) -> bool {
+        self.starved.load(Ordering::Relaxed)
+    }
+
+    /// How long the output has been paused, if it is.
+    pub(crate) fn paused_for(&self) -> Option<Duration> {
+        self.paused_at.load().map(|at| at.elapsed())
+    }
+
+    /// Take the silence owed to the output, for the playing stream to write.
+    #[cfg(feature = "pulse")]
+    pub(crate) fn take_silence(&self) -> Duration {
+        self.silence_owed.take()
+    }
+
+    fn reset(&self) {
+        self.set_starved(false);
+        self.paused_at.store(None);
+        self.silence_owed.store(Duration::ZERO);
+    }
+}
+
+pub enum Interrupt {
+    Stop,
+    Pause,
+}
+
+#[derive(Default)]
+struct Counts {
+    stops: AtomicU64,
+    pauses: AtomicU64,
+}
+
+/// Counts of stops and pauses read from the server, which a prefill
+/// running on the main thread compares to notice one arriving behind it.
+/// Clones share the counts, so one is kept by the reader of the server's
+/// messages and one by each output it interrupts.
+#[derive(Clone, Default)]
+pub struct Interrupts(Arc<Counts>);
+
+impl Interrupts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tell any prefill in progress that the server has asked to stop or
+    /// pause, as the message itself waits behind the prefill.
+    pub fn interrupt_prefill(&self, interrupt: Interrupt) {
+        match interrupt {
+            Interrupt::Stop => self.0.stops.fetch_add(1, Ordering::Relaxed),
+            Interrupt::Pause => self.0.pauses.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    /// Start watching for stops and pauses.
+    #[cfg(feature = "pulse")]
+    pub(crate) fn watch(&self) -> PrefillWatch<'_> {
+        PrefillWatch {
+            interrupts: self,
+            stops: self.0.stops.load(Ordering::Relaxed),
+            pauses: self.0.pauses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Watches for stops and pauses arriving after a prefill started.
+#[cfg(feature = "pulse")]
+pub(crate) struct PrefillWatch<'a> {
+    interrupts: &'a Interrupts,
+    stops: u64,
+    pauses: u64,
+}
+
+#[cfg(feature = "pulse")]
+impl PrefillWatch<'_> {
+    pub(crate) fn interrupted(&self) -> Option<Interrupt> {
+        let counts = &self.interrupts.0;
+        if counts.stops.load(Ordering::Relaxed) != self.stops {
+            Some(Interrupt::Stop)
+        } else if counts.pauses.load(Ordering::Relaxed) != self.pauses {
+            Some(Interrupt::Pause)
+        } else {
+            None
+        }
+    }
+}
+
+/// A device for the tracks whose sample rate is in a range.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceRule {
+    rates: RangeInclusive<u32>,
+    device: String,
+}
+
+impl DeviceRule {
+    pub fn new(rates: RangeInclusive<u32>, device: String) -> Self {
+        Self { rates, device }
+    }
+
+    pub fn matches(&self, sample_rate: u32) -> bool {
+        self.rates.contains(&sample_rate)
+    }
+}
+
+/// The audio system used when none is chosen.
+#[cfg(feature = "pulse")]
+pub const DEFAULT_SYSTEM: &str = "pulse";
+#[cfg(not(feature = "pulse"))]
+pub const DEFAULT_SYSTEM: &str = "rodio";
+
+/// Every audio system compiled in.
+pub const AUDIO_SYSTEMS: &[&str] = &[
+    #[cfg(feature = "pulse")]
+    "pulse",
+    #[cfg(feature = "rodio")]
+    "rodio",
+];
+
+/// Create the output for the named audio system, either "pulse" or "rodio"
+/// depending on the features compiled in. A prefill is cut short by a stop
+/// or pause counted by `interrupts`.
+/// The device is checked here, whichever the audio system, so that a
+/// missing device is reported at once rather than with the first track.
+pub fn make_audio_output(
+    system: &str,
+    options: &OutputOptions,
+    interrupts: Interrupts,
+) -> anyhow::Result<AudioOutput> {
+    AudioOutput::try_new(system, options, interrupts)
+}
+
+/// Longest a local file is allowed to play for.
+const MAX_FILE_PLAY_TIME: Duration = Duration::from_secs(10);
+
+/// Play a short local audio file on the output, returning once it has
+/// finished. The output is stopped afterwards so should be idle to begin
+/// with.
+pub fn play_file(
+    output: &mut AudioOutput,
+    path: &Path,
+    volume: Arc<Mutex<Volume>>,
+    options: &DecodeOptions,
+) -> anyhow::Result<()> {
+    let decoder = decode::make_file_decoder(path, options)?;
+    let (stream_in, stream_out) = unbounded();
+    output.enqueue_new_stream(
+        decoder,
+        stream_in,
+        StreamParams::local(volume, WriteMarker::new()),
+    );
+
+    loop {
+        match stream_out.recv_timeout(MAX_FILE_PLAY_TIME) {
+            Ok(PlayerMsg::Drained) | Err(_) => break,
+            Ok(_) => {}
+        }
+    }
+    output.stop();
+    Ok(())
+}
+
+// Only the test output is much smaller than the others
+#[cfg_attr(test, allow(clippy::large_enum_variant))]
+enum Backend {
     #[cfg(feature = "pulse")]
     Pulse(pulse_out::AudioOutput),
     #[cfg(feature = "rodio")]
     Rodio(rodio_out::AudioOutput),
+    #[cfg(test)]
+    Null(null_out::AudioOutput),
+}
+
+pub struct AudioOutput {
+    backend: Backend,
+    refill_on_resume: bool,
+    state: Arc<OutputState>,
+    // Set when the server asks to resume before the stream it means has
+    // been enqueued, e.g. just after a seek, so that the stream starts
+    // once it is. Only the server's own resume sets it, see
+    // `start_when_ready`.
+    start_pending: bool,
+    // The drift hold under way, if any, kept apart from a real pause so
+    // that the end of a hold cannot undo a pause asked for during it
+    hold: Option<u64>,
+    holds: u64,
 }
 
 impl AudioOutput {
     pub fn try_new(
         system: &str,
-        #[cfg(feature = "rodio")] device: &Option<String>,
+        options: &OutputOptions,
+        interrupts: Interrupts,
     ) -> anyhow::Result<Self> {
-        Ok(match system {
+        let state = Arc::new(OutputState::default());
+        let backend = match system {
             #[cfg(feature = "pulse")]
-            "pulse" => Self::Pulse(pulse_out::AudioOutput::try_new()?),
+            "pulse" => Backend::Pulse(pulse_out::AudioOutput::try_new(
+                options,
+                state.clone(),
+                interrupts,
+            )?),
             #[cfg(feature = "rodio")]
-            "rodio" => Self::Rodio(rodio_out::AudioOutput::try_new(device)?),
+            "rodio" => {
+                let _ = interrupts;
+                Backend::Rodio(rodio_out::AudioOutput::try_new(options, state.clone())?)
+            }
             _ => unreachable!(),
-        })
+        };
+        let output = Self {
+            backend,
+            refill_on_resume: options.refill_on_resume,
+            state,
+            start_pending: false,
+            hold: None,
+            holds: 0,
+        };
+
+        if !options.device_map.is_empty() {
+            let names = output.get_output_device_names()?;
+            for rule in &options.device_map {
+                if !names.iter().any(|(name, _)| *name == rule.device) {
+                    anyhow::bail!("Cannot find device: {}", rule.device);
+                }
+            }
+        }
+        Ok(output)
+    }
+
+    /// An output with no audio system behind it, with a stream playing on
+    /// it or none.
+    #[cfg(test)]
+    pub(crate) fn null(playing: bool) -> Self {
+        Self {
+            backend: Backend::Null(null_out::AudioOutput::new(playing)),
+            refill_on_resume: false,
+            state: Arc::new(OutputState::default()),
+            start_pending: false,
+            hold: None,
+            holds: 0,
+        }
+    }
+
+    /// What the output shares with the streams feeding it.
+    pub fn state(&self) -> Arc<OutputState> {
+        self.state.clone()
     }
 
     pub fn enqueue_new_stream(
         &mut self,
         decoder: Decoder,
         stream_in: Sender<PlayerMsg>,
-        stream_params: StreamParams,
-        device: &Option<String>,
+        mut stream_params: StreamParams,
     ) {
-        match self {
+        if mem::take(&mut self.start_pending) {
+            info!("Starting stream at once, as resume was asked for while buffering");
+            stream_params.autostart = AutoStart::Auto;
+        }
+        match &mut self.backend {
             #[cfg(feature = "pulse")]
-            Self::Pulse(out) => out.enqueue_new_stream(decoder, stream_in, stream_params, device),
+            Backend::Pulse(out) => out.enqueue_new_stream(decoder, stream_in, stream_params),
             #[cfg(feature = "rodio")]
-            Self::Rodio(out) => out.enqueue_new_stream(decoder, stream_in, stream_params, device),
+            Backend::Rodio(out) => out.enqueue_new_stream(decoder, stream_in, stream_params),
+            #[cfg(test)]
+            Backend::Null(out) => out.enqueue_new_stream(decoder, stream_in, stream_params),
         }
     }
 
     pub fn unpause(&mut self) -> bool {
-        match self {
+        // Only pulse keeps a buffer of its own to refill
+        #[allow(unused)]
+        let refill = self.refill_on_resume
+            && matches!(self.state.paused_for(), Some(paused) if paused >= REFILL_AFTER);
+        // Cleared first, as a data stream held by the pause may be read
+        // from the audio system's own thread
+        self.state.paused_at.store(None);
+        self.hold = None;
+        match &mut self.backend {
             #[cfg(feature = "pulse")]
-            Self::Pulse(out) => out.unpause(),
+            Backend::Pulse(out) => out.unpause(refill),
             #[cfg(feature = "rodio")]
-            Self::Rodio(out) => out.unpause(),
+            Backend::Rodio(out) => out.unpause(),
+            #[cfg(test)]
+            Backend::Null(out) => out.unpause(),
         }
     }
 
+    /// Start the next stream enqueued as soon as it is ready, whatever its
+    /// autostart, for a resume that came before the stream it means. A
+    /// stop, flush or pause cancels it.
+    pub fn start_when_ready(&mut self) {
+        info!("Nothing to resume yet, the next stream will start when ready");
+        self.start_pending = true;
+    }
+
     pub fn pause(&mut self) -> bool {
-        match self {
+        self.start_pending = false;
+        // A pause during a hold outlasts it
+        self.hold = None;
+        let paused = match &mut self.backend {
+            #[cfg(feature = "pulse")]
+            Backend::Pulse(out) => out.pause(),
+            #[cfg(feature = "rodio")]
+            Backend::Rodio(out) => out.pause(),
+            #[cfg(test)]
+            Backend::Null(out) => out.pause(),
+        };
+        if paused && self.state.paused_at.load().is_none() {
+            self.state.paused_at.store(Some(Instant::now()));
+        }
+        paused
+    }
+
+    /// Briefly stop playback to correct drift, without it counting as a
+    /// pause. Returns the hold to pass to `end_hold`, or `None` if there
+    /// is nothing playing to hold.
+    pub fn hold(&mut self) -> Option<u64> {
+        if self.is_paused() || self.hold.is_some() {
+            return None;
+        }
+        let held = match &mut self.backend {
+            #[cfg(feature = "pulse")]
+            Backend::Pulse(out) => out.pause(),
+            #[cfg(feature = "rodio")]
+            Backend::Rodio(out) => out.pause(),
+            #[cfg(test)]
+            Backend::Null(out) => out.pause(),
+        };
+        if !held {
+            return None;
+        }
+        self.holds += 1;
+        self.hold = Some(self.holds);
+        self.hold
+    }
+
+    /// Carry on after a hold, unless it has since been ended by a pause,
+    /// unpause, stop or flush, or a later hold has taken its place.
+    pub fn end_hold(&mut self, hold: u64) {
+        if self.hold != Some(hold) {
+            return;
+        }
+        self.hold = None;
+        match &mut self.backend {
+            #[cfg(feature = "pulse")]
+            Backend::Pulse(out) => {
+                out.unpause(false);
+            }
+            #[cfg(feature = "rodio")]
+            Backend::Rodio(out) => {
+                out.unpause();
+            }
+            #[cfg(test)]
+            Backend::Null(out) => {
+                out.unpause();
+            }
+        }
+    }
+
+    /// Whether a pause of `interval` is better made by playing silence,
+    /// as pausing and resuming the audio system would take about as long.
+    /// The server sends such pauses to nudge players in a sync group.
+    pub fn can_micro_pause(&self, #[allow(unused)] interval: Duration) -> bool {
+        match &self.backend {
             #[cfg(feature = "pulse")]
-            Self::Pulse(out) => out.pause(),
+            Backend::Pulse(_) => interval <= pulse_out::MICRO_PAUSE_MAX,
+            // A sink pauses and resumes at once
             #[cfg(feature = "rodio")]
-            Self::Rodio(out) => out.pause(),
+            Backend::Rodio(_) => false,
+            #[cfg(test)]
+            Backend::Null(_) => false,
         }
     }
 
+    /// Delay playback by `interval` by playing that much silence ahead of
+    /// the audio still to come, without pausing.
+    pub fn micro_pause(&mut self, interval: Duration) {
+        let owed = &self.state.silence_owed;
+        owed.store(owed.load() + interval);
+    }
+
     pub fn stop(&mut self) {
-        match self {
+        self.state.reset();
+        self.start_pending = false;
+        self.hold = None;
+        match &mut self.backend {
+            #[cfg(feature = "pulse")]
+            Backend::Pulse(out) => out.stop(),
+            #[cfg(feature = "rodio")]
+            Backend::Rodio(out) => out.stop(),
+            #[cfg(test)]
+            Backend::Null(out) => out.stop(),
+        }
+    }
+
+    /// Stop, with a short fade first where the audio system allows it, so
+    /// that playback does not end with a click.
+    pub fn stop_with_fade(&mut self, #[allow(unused)] length: Duration) {
+        match &mut self.backend {
+            // Audio already sent to pulse plays at the volume it was
+            // written with, so there is nothing to fade
             #[cfg(feature = "pulse")]
-            Self::Pulse(out) => out.stop(),
+            Backend::Pulse(_) => {}
             #[cfg(feature = "rodio")]
-            Self::Rodio(out) => out.stop(),
+            Backend::Rodio(out) => out.fade_out(length),
+            #[cfg(test)]
+            Backend::Null(_) => {}
         }
+        self.stop();
     }
 
     pub fn flush(&mut self) {
-        match self {
+        self.state.reset();
+        self.start_pending = false;
+        self.hold = None;
+        match &mut self.backend {
+            #[cfg(feature = "pulse")]
+            Backend::Pulse(out) => out.flush(),
+            #[cfg(feature = "rodio")]
+            Backend::Rodio(out) => out.flush(),
+            #[cfg(test)]
+            Backend::Null(out) => out.stop(),
+        }
+    }
+
+    /// Whether there is a stream on the output, playing or paused.
+    pub fn is_playing(&self) -> bool {
+        match &self.backend {
+            #[cfg(feature = "pulse")]
+            Backend::Pulse(out) => out.is_playing(),
+            #[cfg(feature = "rodio")]
+            Backend::Rodio(out) => out.is_playing(),
+            #[cfg(test)]
+            Backend::Null(out) => out.is_playing(),
+        }
+    }
+
+    /// Whether the connection to the audio system has gone, e.g. because
+    /// its server was restarted, so that a new output is needed.
+    pub fn is_lost(&self) -> bool {
+        match &self.backend {
+            #[cfg(feature = "pulse")]
+            Backend::Pulse(out) => out.is_lost(),
+            #[cfg(feature = "rodio")]
+            Backend::Rodio(_) => false,
+            #[cfg(test)]
+            Backend::Null(out) => out.is_lost(),
+        }
+    }
+
+    /// Act as if the audio system had gone away, for an output with none
+    /// behind it.
+    #[cfg(test)]
+    pub(crate) fn lose(&mut self) {
+        if let Backend::Null(out) = &mut self.backend {
+            out.lose();
+        }
+    }
+
+    /// Whether playback was last paused rather than unpaused. A drift hold
+    /// does not count.
+    pub fn is_paused(&self) -> bool {
+        if self.hold.is_some() {
+            return false;
+        }
+        match &self.backend {
             #[cfg(feature = "pulse")]
-            Self::Pulse(out) => out.flush(),
+            Backend::Pulse(out) => out.is_paused(),
             #[cfg(feature = "rodio")]
-            Self::Rodio(out) => out.flush(),
+            Backend::Rodio(out) => out.is_paused(),
+            #[cfg(test)]
+            Backend::Null(out) => out.is_paused(),
         }
     }
 
     pub fn shift(&mut self) {
-        match self {
+        match &mut self.backend {
             #[cfg(feature = "pulse")]
-            Self::Pulse(out) => out.shift(),
+            Backend::Pulse(out) => out.shift(),
             #[cfg(feature = "rodio")]
-            Self::Rodio(out) => out.shift(),
+            Backend::Rodio(out) => out.shift(),
+            #[cfg(test)]
+            Backend::Null(_) => {}
         }
     }
 
     pub fn get_dur(&self) -> Duration {
-        match self {
+        match &self.backend {
             #[cfg(feature = "pulse")]
-            Self::Pulse(out) => out.get_dur(),
+            Backend::Pulse(out) => out.get_dur(),
             #[cfg(feature = "rodio")]
-            Self::Rodio(out) => out.get_dur(),
+            Backend::Rodio(out) => out.get_dur(),
+            #[cfg(test)]
+            Backend::Null(out) => out.get_dur(),
+        }
+    }
+
+    /// How much of the playing stream has been written to the audio system.
+    pub fn get_written(&self) -> Duration {
+        match &self.backend {
+            #[cfg(feature = "pulse")]
+            Backend::Pulse(out) => out.get_written(),
+            #[cfg(feature = "rodio")]
+            Backend::Rodio(out) => out.get_written(),
+            #[cfg(test)]
+            Backend::Null(out) => out.get_dur(),
         }
     }
 
     pub fn get_output_device_names(&self) -> anyhow::Result<Vec<(String, Option<String>)>> {
-        match self {
+        match &self.backend {
+            #[cfg(feature = "pulse")]
+            Backend::Pulse(out) => out.get_output_device_names(),
+            #[cfg(feature = "rodio")]
+            Backend::Rodio(out) => out.get_output_device_names(),
+            #[cfg(test)]
+            Backend::Null(_) => Ok(Vec::new()),
+        }
+    }
+
+    /// Details of the audio system for bug reports, as name and value pairs.
+    pub fn get_system_info(&self) -> Vec<(String, String)> {
+        match &self.backend {
+            #[cfg(feature = "pulse")]
+            Backend::Pulse(out) => out.get_system_info(),
+            #[cfg(feature = "rodio")]
+            Backend::Rodio(out) => out.get_system_info(),
+            #[cfg(test)]
+            Backend::Null(_) => Vec::new(),
+        }
+    }
+
+    /// The ports of the named device, with whether each is active. Empty
+    /// where the audio system has no ports.
+    pub fn get_output_device_ports(&self, device: &str) -> Vec<(String, bool)> {
+        match &self.backend {
             #[cfg(feature = "pulse")]
-            Self::Pulse(out) => out.get_output_device_names(),
+            Backend::Pulse(out) => out.get_output_device_ports(device),
             #[cfg(feature = "rodio")]
-            Self::Rodio(out) => out.get_output_device_names(),
+            Backend::Rodio(_) => {
+                let _ = device;
+                Vec::new()
+            }
+            #[cfg(test)]
+            Backend::Null(_) => Vec::new(),
         }
     }
 }
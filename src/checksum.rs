@@ -0,0 +1,106 @@
+//! Checksums of each track's audio, for checking that playback is
+//! bit-perfect.
+//!
+//! Two CRC-32s are kept for every track: one of the samples as decoded,
+//! before anything is applied to them, and one of the samples as they
+//! leave the decoder for the output, after the volume, transitions,
+//! downmixing, loudness compensation and DSP. When the two match nothing
+//! touched the audio. Both are over the 32-bit float samples, one channel
+//! after another for each buffer, so they are the same from one run to the
+//! next and can be compared across runs with different settings.
+//!
+//! A line is appended to the log as each track ends, with the tags, the
+//! format, the number of frames and both checksums.
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use crc32fast::Hasher;
+use symphonia::core::audio::{AudioBuffer, Signal};
+
+use crate::{paths::Persisted, track_info::TrackInfo};
+
+static LOG_WRITES: Persisted = Persisted::new("checksums");
+
+/// The checksums of one track, logged when it is dropped.
+pub(crate) struct Tap {
+    log: PathBuf,
+    track: String,
+    format: String,
+    raw: Hasher,
+    output: Hasher,
+    frames: u64,
+    // Reused for the bytes of each channel
+    scratch: Vec<u8>,
+}
+
+impl Tap {
+    /// Checksums for a track to append to the file at `log`, unless
+    /// logging has stopped as the log cannot be written.
+    pub(crate) fn new(log: &Path, info: Option<&TrackInfo>, format: String) -> Option<Self> {
+        (!LOG_WRITES.is_disabled()).then(|| Self {
+            log: log.to_owned(),
+            track: info.map_or_else(|| "(untagged)".to_owned(), TrackInfo::summary),
+            format,
+            raw: Hasher::new(),
+            output: Hasher::new(),
+            frames: 0,
+            scratch: Vec::new(),
+        })
+    }
+
+    /// Add a buffer as it was decoded.
+    pub(crate) fn raw(&mut self, buffer: &AudioBuffer<f32>) {
+        self.frames += buffer.frames() as u64;
+        update(&mut self.raw, &mut self.scratch, buffer);
+    }
+
+    /// Add a buffer as it is handed on to the output.
+    pub(crate) fn output(&mut self, buffer: &AudioBuffer<f32>) {
+        update(&mut self.output, &mut self.scratch, buffer);
+    }
+
+    /// The file the checksums are appended to.
+    pub(crate) fn log(&self) -> &Path {
+        &self.log
+    }
+}
+
+impl Drop for Tap {
+    fn drop(&mut self) {
+        let path = &self.log;
+        let raw = self.raw.clone().finalize();
+        let output = self.output.clone().finalize();
+        let line = format!(
+            "{}\t{}\t{} frames\traw {raw:08x}\toutput {output:08x}\t{}\n",
+            self.track,
+            self.format,
+            self.frames,
+            if raw == output {
+                "unchanged"
+            } else {
+                "changed"
+            },
+        );
+        LOG_WRITES.write(|| {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| path.display().to_string())?;
+            Ok(file.write_all(line.as_bytes())?)
+        });
+    }
+}
+
+fn update(hasher: &mut Hasher, scratch: &mut Vec<u8>, buffer: &AudioBuffer<f32>) {
+    for chan in 0..buffer.spec().channels.count() {
+        scratch.clear();
+        scratch.extend(buffer.chan(chan).iter().flat_map(|s| s.to_le_bytes()));
+        hasher.update(scratch);
+    }
+}
@@ -1,51 +1,80 @@
 use std::{
-    io::Write,
+    fs::File,
+    io::{Read, Write},
     mem,
     net::{Ipv4Addr, TcpStream},
+    os::fd::AsRawFd,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+#[cfg(feature = "tls")]
+use std::io::{BufRead, BufReader};
+
 use anyhow::{bail, Context};
-#[cfg(not(feature = "pulse"))]
-use crossbeam::channel::Sender;
-#[cfg(feature = "pulse")]
 use crossbeam::{atomic::AtomicCell, channel::Sender};
 
-use log::warn;
+use log::{info, warn};
 use slimproto::{
     buffer::SlimBuffer,
-    proto::{PcmChannels, PcmSampleRate},
+    proto::{AutoStart, Format, PcmChannels, PcmSampleRate},
     status::StatusData,
+    Capability,
 };
 
 use symphonia::core::{
-    audio::{AudioBuffer, Signal},
-    codecs::{Decoder as SymDecoder, DecoderOptions},
+    audio::{AudioBuffer, Channels, Signal},
+    codecs::{
+        CodecParameters, Decoder as SymDecoder, DecoderOptions, CODEC_TYPE_AAC, CODEC_TYPE_ALAC,
+        CODEC_TYPE_FLAC, CODEC_TYPE_MP3, CODEC_TYPE_PCM_S16LE, CODEC_TYPE_VORBIS,
+    },
     conv::FromSample,
-    formats::FormatOptions,
+    formats::{Cue, FormatOptions, FormatReader, Packet, SeekMode, SeekTo, SeekedTo, Track},
     io::{MediaSourceStream, ReadOnlySource},
-    meta::MetadataOptions,
+    meta::{Metadata, MetadataLog, MetadataOptions},
     probe::{Hint, ProbeResult},
     sample::SampleFormat,
 };
 
 #[cfg(feature = "pulse")]
-use symphonia::core::audio::{RawSample, RawSampleBuffer};
+use symphonia::core::{
+    audio::{RawSample, RawSampleBuffer},
+    sample::i24,
+};
 
 #[cfg(feature = "rodio")]
 use symphonia::core::{audio::SampleBuffer, sample::Sample};
 
-#[cfg(feature = "notify")]
-use symphonia::core::meta::MetadataRevision;
+use crate::{
+    audio_out::{OutputState, WriteMarker},
+    checksum::Tap,
+    downmix,
+    levels::Levels,
+    loudness::Loudness,
+    message::PlayerMsg,
+    output_format::OutputFormat,
+    proto::DEFAULT_HTTP_PORT,
+    resample::Resampler,
+    stats::Stats,
+    threads,
+    track_info::TrackInfo,
+    transition::Transition,
+    volume::Volume,
+    StreamParams,
+};
 
-use crate::{message::PlayerMsg, StreamParams};
+#[cfg(feature = "tls")]
+use crate::tls;
 
 #[derive(Debug)]
 pub enum DecoderError {
     EndOfDecode,
     // Unhandled,
     Retry,
+    // The stream carries on with another track
+    Chained,
     StreamError(symphonia::core::errors::Error),
 }
 
@@ -55,6 +84,7 @@ impl std::fmt::Display for DecoderError {
             DecoderError::EndOfDecode => write!(f, "End of decode stream"),
             // DecoderError::Unhandled => write!(f, "Unhandled format"),
             DecoderError::Retry => write!(f, "Decoder reset required"),
+            DecoderError::Chained => write!(f, "Next track in stream"),
             DecoderError::StreamError(e) => write!(f, "{}", e),
         }
     }
@@ -62,6 +92,33 @@ impl std::fmt::Display for DecoderError {
 
 impl std::error::Error for DecoderError {}
 
+/// Why a stream could not be opened for decoding, separating data that is
+/// not what it claims to be from a codec this build cannot decode.
+#[derive(Debug)]
+pub enum OpenError {
+    ProbeFailed(symphonia::core::errors::Error),
+    NoDefaultTrack,
+    BadParameters(String),
+    CodecUnsupported(String),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for OpenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OpenError::ProbeFailed(e) => write!(f, "Unrecognised container format: {}", e),
+            OpenError::NoDefaultTrack => write!(f, "Unable to find default track"),
+            OpenError::BadParameters(what) => write!(f, "Unplayable stream parameters: {}", what),
+            OpenError::CodecUnsupported(codec) => {
+                write!(f, "This build has no decoder for {}", codec)
+            }
+            OpenError::Io(e) => write!(f, "Error reading stream: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for OpenError {}
+
 #[derive(Clone, Copy)]
 pub enum AudioFormat {
     F32,
@@ -69,6 +126,8 @@ pub enum AudioFormat {
     U32,
     I16,
     U16,
+    // Only ever output, when the output format is forced
+    I24,
 }
 
 impl AudioFormat {
@@ -79,6 +138,7 @@ impl AudioFormat {
             Self::U32 => mem::size_of::<u32>(),
             Self::I16 => mem::size_of::<i16>(),
             Self::U16 => mem::size_of::<u16>(),
+            Self::I24 => 3,
         }
     }
 }
@@ -95,25 +155,186 @@ impl From<SampleFormat> for AudioFormat {
     }
 }
 
+const MIN_SAMPLE_RATE: u32 = 8_000;
+const MAX_SAMPLE_RATE: u32 = 384_000;
+
+/// The formats this build can decode, as capabilities to advertise to the
+/// server. A format is left out when its codec is not compiled in, so the
+/// server transcodes it rather than sending what cannot be played.
+pub fn supported_formats() -> Vec<Capability> {
+    let codecs = symphonia::default::get_codecs();
+    [
+        (Capability::Pcm, CODEC_TYPE_PCM_S16LE),
+        (Capability::Mp3, CODEC_TYPE_MP3),
+        (Capability::Aac, CODEC_TYPE_AAC),
+        (Capability::Alc, CODEC_TYPE_ALAC),
+        (Capability::Ogg, CODEC_TYPE_VORBIS),
+        (Capability::Flc, CODEC_TYPE_FLAC),
+    ]
+    .into_iter()
+    .filter(|(_, codec)| codecs.get_codec(*codec).is_some())
+    .map(|(capability, _)| capability)
+    .collect()
+}
+
+// Readers that know of an encoder's delay and padding, e.g. from a LAME
+// tag, mark them on each packet for the codec to trim, so that tracks on
+// a gapless album run into one another without a click
+fn format_options() -> FormatOptions {
+    FormatOptions {
+        enable_gapless: true,
+        ..Default::default()
+    }
+}
+
+// The codec, rate and channels of a stream, for the checksum log
+fn describe_format(probed: &ProbeResult, sample_rate: u32, layout: Channels) -> String {
+    let codec = probed
+        .format
+        .default_track()
+        .and_then(|track| symphonia::default::get_codecs().get_codec(track.codec_params.codec))
+        .map_or("unknown codec", |descriptor| descriptor.short_name);
+    format!("{codec} {sample_rate} Hz {} ch", layout.count())
+}
+
 struct AudioSpec {
     channels: u8,
+    // The channels played, and those decoded when they are mixed down
+    layout: Channels,
+    source: Channels,
+    // The rate played, and the rate decoded when it is converted
     sample_rate: u32,
+    source_rate: u32,
     format: AudioFormat,
 }
 
+// The channels of a stream, as the codec gives them or else from its layout
+fn stream_channels(params: &CodecParameters) -> Option<Channels> {
+    params
+        .channels
+        .or_else(|| params.channel_layout.map(|layout| layout.into_channels()))
+}
+
+// Stands in for the format reader of a stream that has ended, while what
+// follows it is probed
+struct Finished {
+    source: MediaSourceStream,
+    metadata: MetadataLog,
+}
+
+impl Finished {
+    fn new() -> Self {
+        Self {
+            source: MediaSourceStream::new(
+                Box::new(std::io::Cursor::new(Vec::new())),
+                Default::default(),
+            ),
+            metadata: MetadataLog::default(),
+        }
+    }
+}
+
+impl FormatReader for Finished {
+    fn try_new(
+        source: MediaSourceStream,
+        _options: &FormatOptions,
+    ) -> symphonia::core::errors::Result<Self> {
+        Ok(Self {
+            source,
+            metadata: MetadataLog::default(),
+        })
+    }
+
+    fn cues(&self) -> &[Cue] {
+        &[]
+    }
+
+    fn metadata(&mut self) -> Metadata<'_> {
+        self.metadata.metadata()
+    }
+
+    fn seek(&mut self, _mode: SeekMode, _to: SeekTo) -> symphonia::core::errors::Result<SeekedTo> {
+        symphonia::core::errors::seek_error(symphonia::core::errors::SeekErrorKind::Unseekable)
+    }
+
+    fn tracks(&self) -> &[Track] {
+        &[]
+    }
+
+    fn next_packet(&mut self) -> symphonia::core::errors::Result<Packet> {
+        Err(symphonia::core::errors::Error::IoError(
+            std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "end of stream"),
+        ))
+    }
+
+    fn into_inner(self: Box<Self>) -> MediaSourceStream {
+        self.source
+    }
+}
+
+/// What is done to the audio as it is decoded, beyond the volume and the
+/// transition sent with each stream.
+#[derive(Clone, Debug, Default)]
+pub struct DecodeOptions {
+    /// Mix every stream with more than two channels down to stereo.
+    pub downmix_stereo: bool,
+    /// Convert every stream to this format.
+    pub output_format: Option<OutputFormat>,
+    /// Compensate for low volume with this strength, where 1.0 is the usual
+    /// amount and zero turns compensation off.
+    pub loudness: f32,
+    /// Append the checksums of each track to this file.
+    pub checksum_log: Option<PathBuf>,
+    /// Check the level of each track.
+    pub check_levels: bool,
+}
+
 pub struct Decoder {
     pub probed: ProbeResult,
     pub decoder: Box<dyn SymDecoder>,
     spec: AudioSpec,
+    transition: Transition,
+    loudness: Option<Loudness>,
+    frames_decoded: u64,
+    // Set once the codec has panicked, after which the stream is over
+    poisoned: bool,
+    // The packets are already samples in the output's format, so can be
+    // copied straight to it when there is nothing to apply to them
+    #[cfg(feature = "pulse")]
+    raw_pcm: bool,
+    // Read from the tags when the stream is opened, on the thread opening
+    // it, so that the message loop does not wait on it
+    #[cfg(any(feature = "notify", feature = "scrobble"))]
+    info: Option<TrackInfo>,
+    checksum: Option<Tap>,
+    levels: Option<Levels>,
+    resampler: Option<Resampler>,
+}
+
+// What the tags in the container, else those found while probing, say
+// about the track. Only the tags are read, nothing is copied wholesale.
+fn read_info(probed: &mut ProbeResult) -> Option<TrackInfo> {
+    let info = probed
+        .format
+        .metadata()
+        .current()
+        .map(TrackInfo::from_metadata);
+    info.or_else(|| {
+        probed
+            .metadata
+            .get()
+            .and_then(|m| m.current().map(TrackInfo::from_metadata))
+    })
 }
 
 impl Decoder {
     pub fn try_new(
         mss: MediaSourceStream,
-        format: slimproto::proto::Format,
-        pcmsamplerate: slimproto::proto::PcmSampleRate,
-        pcmchannels: slimproto::proto::PcmChannels,
-    ) -> anyhow::Result<Self> {
+        format: &slimproto::proto::Format,
+        pcmsamplerate: &slimproto::proto::PcmSampleRate,
+        pcmchannels: &slimproto::proto::PcmChannels,
+        options: &DecodeOptions,
+    ) -> Result<Self, OpenError> {
         // Create a hint to help the format registry guess what format reader is appropriate.
         let mut hint = Hint::new();
         hint.mime_type({
@@ -127,19 +348,17 @@ impl Decoder {
             }
         });
 
-        let probed = symphonia::default::get_probe()
-            .format(
-                &hint,
-                mss,
-                &FormatOptions::default(),
-                &MetadataOptions::default(),
-            )
-            .context("Unrecognised container format")?;
+        let mut probed = symphonia::default::get_probe()
+            .format(&hint, mss, &format_options(), &MetadataOptions::default())
+            .map_err(|e| match e {
+                symphonia::core::errors::Error::IoError(e) => OpenError::Io(e),
+                e => OpenError::ProbeFailed(e),
+            })?;
 
         let track = match probed.format.default_track() {
             Some(track) => track,
             None => {
-                bail!("Unable to find default track");
+                return Err(OpenError::NoDefaultTrack);
             }
         };
 
@@ -149,37 +368,103 @@ impl Decoder {
         };
 
         let sample_rate = match pcmsamplerate {
-            PcmSampleRate::Rate(rate) => rate,
+            PcmSampleRate::Rate(rate) => *rate,
             PcmSampleRate::SelfDescribing => track.codec_params.sample_rate.unwrap_or(44100),
         };
 
-        let channels = match pcmchannels {
-            PcmChannels::Mono => 1u8,
-            PcmChannels::Stereo => 2,
-            PcmChannels::SelfDescribing => match track.codec_params.channel_layout {
-                Some(symphonia::core::audio::Layout::Mono) => 1,
-                Some(symphonia::core::audio::Layout::Stereo) => 2,
-                None => match track.codec_params.channels {
-                    Some(channels) => channels.count() as u8,
-                    _ => 2,
-                },
-                _ => 2,
-            },
+        let source = match pcmchannels {
+            PcmChannels::Mono => Channels::FRONT_LEFT,
+            PcmChannels::Stereo => Channels::FRONT_LEFT | Channels::FRONT_RIGHT,
+            PcmChannels::SelfDescribing => stream_channels(&track.codec_params)
+                .unwrap_or(Channels::FRONT_LEFT | Channels::FRONT_RIGHT),
+        };
+        // Garbage data can claim anything, and a zero rate or channel count
+        // upsets the buffer arithmetic and the audio systems
+        if !(MIN_SAMPLE_RATE..=MAX_SAMPLE_RATE).contains(&sample_rate) {
+            return Err(OpenError::BadParameters(format!(
+                "sample rate {sample_rate} Hz"
+            )));
+        }
+        if source.is_empty() {
+            return Err(OpenError::BadParameters("no channels".to_owned()));
+        }
+
+        let (layout, rate, output) = match options.output_format {
+            Some(forced) => (forced.layout(), forced.rate, forced.audio_format()),
+            None => (
+                downmix::output_channels(source, options.downmix_stereo),
+                sample_rate,
+                sample_format,
+            ),
         };
+        if layout.count() < source.count() {
+            let to = if layout.count() == 1 {
+                "mono"
+            } else {
+                "stereo"
+            };
+            info!("Mixing {} channels down to {to}", source.count());
+        } else if layout.count() > source.count() {
+            info!("Playing mono on both channels");
+        }
+        let resampler = (rate != sample_rate).then(|| {
+            info!("Resampling from {sample_rate} Hz to {rate} Hz");
+            Resampler::new(sample_rate, rate, layout)
+        });
 
-        // Create a decoder for the track.
-        let decoder = symphonia::default::get_codecs()
+        // Create a decoder for the track, naming the codec if it is missing.
+        // The registry only knows the names of codecs it can decode so fall
+        // back to the format the server asked for.
+        let codecs = symphonia::default::get_codecs();
+        let decoder = codecs
             .make(&track.codec_params, &DecoderOptions::default())
-            .context("Unable to find suitable decoder")?;
+            .map_err(|_| {
+                OpenError::CodecUnsupported(match codecs.get_codec(track.codec_params.codec) {
+                    Some(descriptor) => descriptor.long_name.to_owned(),
+                    None => format!("{:?} ({})", format, track.codec_params.codec),
+                })
+            })?;
+
+        #[cfg(feature = "pulse")]
+        let raw_pcm = cfg!(target_endian = "little")
+            && track.codec_params.codec == CODEC_TYPE_PCM_S16LE
+            && matches!(sample_format, AudioFormat::I16)
+            && matches!(output, AudioFormat::I16)
+            && rate == sample_rate
+            && layout == source;
+
+        let info = read_info(&mut probed);
+        let checksum = options.checksum_log.as_deref().and_then(|log| {
+            Tap::new(
+                log,
+                info.as_ref(),
+                describe_format(&probed, sample_rate, layout),
+            )
+        });
+        let levels = options.check_levels.then(|| Levels::new(info.as_ref()));
 
         Ok(Decoder {
             probed,
             decoder,
             spec: AudioSpec {
-                channels,
-                sample_rate,
-                format: sample_format,
+                channels: layout.count() as u8,
+                layout,
+                source,
+                sample_rate: rate,
+                source_rate: sample_rate,
+                format: output,
             },
+            transition: Transition::default(),
+            loudness: Loudness::new(sample_rate, options.loudness),
+            frames_decoded: 0,
+            poisoned: false,
+            #[cfg(feature = "pulse")]
+            raw_pcm,
+            #[cfg(any(feature = "notify", feature = "scrobble"))]
+            info,
+            checksum,
+            levels,
+            resampler,
         })
     }
 
@@ -191,42 +476,139 @@ impl Decoder {
         self.spec.sample_rate
     }
 
+    /// Which speaker each channel is for, in the order they are interleaved.
+    pub fn channel_layout(&self) -> Channels {
+        self.spec.layout
+    }
+
     #[cfg(feature = "pulse")]
     pub fn format(&self) -> AudioFormat {
         self.spec.format
     }
 
+    fn next_packet(&mut self) -> Result<Packet, DecoderError> {
+        match self.probed.format.next_packet().map_err(|err| match err {
+            symphonia::core::errors::Error::IoError(err)
+                if err.kind() == std::io::ErrorKind::UnexpectedEof
+                    && err.to_string() == "end of stream" =>
+            {
+                DecoderError::EndOfDecode
+            }
+            symphonia::core::errors::Error::ResetRequired => {
+                self.decoder.reset();
+                DecoderError::Retry
+            }
+            error => DecoderError::StreamError(error),
+        }) {
+            Err(DecoderError::EndOfDecode) if self.chain_next() => Err(DecoderError::Chained),
+            packet => packet,
+        }
+    }
+
+    // The next buffer for the output, at the output's rate
     fn get_audio_buffer(
         &mut self,
-        volume: Arc<Mutex<Vec<f32>>>,
+        volume: Arc<Mutex<Volume>>,
     ) -> Result<AudioBuffer<f32>, DecoderError> {
-        let decoded = loop {
-            let packet = self.probed.format.next_packet().map_err(|err| match err {
-                symphonia::core::errors::Error::IoError(err)
-                    if err.kind() == std::io::ErrorKind::UnexpectedEof
-                        && err.to_string() == "end of stream" =>
-                {
-                    DecoderError::EndOfDecode
-                }
-                symphonia::core::errors::Error::ResetRequired => {
-                    self.decoder.reset();
-                    DecoderError::Retry
-                }
-                error => DecoderError::StreamError(error),
-            })?;
+        let audio_buffer = match (self.decode_audio_buffer(volume), &mut self.resampler) {
+            (Ok(audio_buffer), Some(resampler)) => resampler.process(&audio_buffer),
+            (Ok(audio_buffer), None) => audio_buffer,
+            // The resampler still holds the last few samples
+            (Err(DecoderError::EndOfDecode), Some(resampler)) => {
+                resampler.finish().ok_or(DecoderError::EndOfDecode)?
+            }
+            (Err(e), _) => return Err(e),
+        };
+
+        if let Some(ref mut checksum) = self.checksum {
+            checksum.output(&audio_buffer);
+        }
+
+        Ok(audio_buffer)
+    }
 
-            match self.decoder.decode(&packet) {
-                Ok(decoded) => break decoded,
-                Err(symphonia::core::errors::Error::DecodeError(_)) => continue,
-                Err(e) => return Err(DecoderError::StreamError(e)),
+    fn decode_audio_buffer(
+        &mut self,
+        volume: Arc<Mutex<Volume>>,
+    ) -> Result<AudioBuffer<f32>, DecoderError> {
+        if self.poisoned {
+            return Err(DecoderError::EndOfDecode);
+        }
+
+        let mut audio_buffer = loop {
+            let packet = self.next_packet()?;
+
+            // A codec can panic on malformed data, which ends this stream
+            // rather than the player
+            let decoded = panic::catch_unwind(AssertUnwindSafe(|| {
+                self.decoder.decode(&packet).map(|decoded| {
+                    let mut audio_buffer = decoded.make_equivalent();
+                    decoded.convert::<f32>(&mut audio_buffer);
+                    audio_buffer
+                })
+            }));
+            match decoded {
+                Ok(Ok(audio_buffer)) => break audio_buffer,
+                Ok(Err(symphonia::core::errors::Error::DecodeError(_))) => continue,
+                Ok(Err(e)) => return Err(DecoderError::StreamError(e)),
+                Err(payload) => {
+                    warn!("Decoder panicked: {}", threads::panic_message(&*payload));
+                    self.poisoned = true;
+                    return Err(DecoderError::StreamError(
+                        symphonia::core::errors::Error::DecodeError("decoder panicked"),
+                    ));
+                }
             }
         };
 
-        let vol = volume.lock().map(|v| v[0]).unwrap_or_default();
+        if let Some(ref mut checksum) = self.checksum {
+            checksum.raw(&audio_buffer);
+        }
+        if let Some(ref mut levels) = self.levels {
+            levels.add(&audio_buffer);
+        }
+
+        let volume = volume.lock().map(|v| *v).unwrap_or_default();
+
+        let channels = audio_buffer.spec().channels.count();
+        for chan in 0..channels {
+            let gain = volume.gain(chan, channels);
+            audio_buffer
+                .chan_mut(chan)
+                .iter_mut()
+                .for_each(|s| *s *= gain);
+        }
+
+        if !self.transition.is_none() {
+            let frames = self
+                .probed
+                .format
+                .default_track()
+                .and_then(|track| track.codec_params.n_frames);
+            for chan in 0..channels {
+                for (frame, sample) in audio_buffer.chan_mut(chan).iter_mut().enumerate() {
+                    *sample *= self.transition.gain(
+                        self.frames_decoded + frame as u64,
+                        frames,
+                        self.spec.source_rate,
+                    );
+                }
+            }
+        }
+        self.frames_decoded += audio_buffer.frames() as u64;
+
+        if self.spec.layout != self.spec.source {
+            audio_buffer = downmix::to_channels(&audio_buffer, self.spec.layout);
+        }
+
+        // After the volume, as the compensation depends on it
+        if let Some(ref mut loudness) = self.loudness {
+            loudness.process(&mut audio_buffer, volume.gain(0, 1));
+        }
+
+        #[cfg(feature = "dsp")]
+        crate::dsp::process_buffer(&mut audio_buffer, self.spec.source_rate);
 
-        let mut audio_buffer = decoded.make_equivalent();
-        decoded.convert::<f32>(&mut audio_buffer);
-        audio_buffer.transform(|s| s * vol);
         Ok(audio_buffer)
     }
 
@@ -235,7 +617,7 @@ impl Decoder {
         &mut self,
         buffer: &mut Vec<T>,
         limit: Option<usize>,
-        volume: Arc<Mutex<Vec<f32>>>,
+        volume: Arc<Mutex<Volume>>,
     ) -> Result<(), DecoderError>
     where
         T: Sample + FromSample<f32>,
@@ -264,7 +646,7 @@ impl Decoder {
         &mut self,
         buffer: &mut Vec<u8>,
         limit: Option<usize>,
-        volume: Arc<Mutex<Vec<f32>>>,
+        volume: Arc<Mutex<Volume>>,
     ) -> Result<(), DecoderError> {
         let limit = limit.unwrap_or_else(|| {
             if buffer.capacity() > 0 {
@@ -275,6 +657,13 @@ impl Decoder {
         });
 
         while buffer.len() < limit {
+            if self.can_pass_through(&volume) {
+                let packet = self.next_packet()?;
+                self.frames_decoded += packet.dur;
+                buffer.extend_from_slice(packet.buf());
+                continue;
+            }
+
             let audio_buffer = self.get_audio_buffer(volume.clone())?;
 
             match self.spec.format {
@@ -289,11 +678,40 @@ impl Decoder {
                 AudioFormat::I16 | AudioFormat::U16 => {
                     self.audio_to_raw::<i16>(audio_buffer, buffer);
                 }
+
+                AudioFormat::I24 => {
+                    self.audio_to_raw::<i24>(audio_buffer, buffer);
+                }
             };
         }
         Ok(())
     }
 
+    // Whether the packets can be copied to the output untouched, as every
+    // step that would change the samples leaves them as they are
+    #[cfg(feature = "pulse")]
+    fn can_pass_through(&self, volume: &Arc<Mutex<Volume>>) -> bool {
+        let channels = self.spec.channels as usize;
+        let unity = match volume.lock() {
+            Ok(volume) => (0..channels).all(|chan| volume.gain(chan, channels) == 1.0),
+            Err(_) => false,
+        };
+
+        #[cfg(feature = "dsp")]
+        if crate::dsp::is_active() {
+            return false;
+        }
+
+        // The checksums and levels are of decoded samples, so there must
+        // be some
+        self.raw_pcm
+            && self.checksum.is_none()
+            && self.levels.is_none()
+            && unity
+            && self.transition.is_none()
+            && !matches!(self.loudness, Some(ref loudness) if !loudness.is_flat())
+    }
+
     #[cfg(feature = "pulse")]
     fn audio_to_raw<T>(&self, audio_buffer: AudioBuffer<f32>, buffer: &mut Vec<u8>)
     where
@@ -305,20 +723,20 @@ impl Decoder {
         buffer.extend_from_slice(raw_sample_buffer.as_bytes());
     }
 
-    #[cfg(feature = "notify")]
-    pub fn metadata(&mut self) -> Option<MetadataRevision> {
-        self.probed
-            .format
-            .metadata()
-            .current()
-            .cloned()
-            .or_else(|| {
-                self.probed
-                    .metadata
-                    .get()
-                    .as_ref()
-                    .and_then(|m| m.current().cloned())
-            })
+    /// What the tags said about the track when the stream was opened,
+    /// which can be taken once.
+    #[cfg(any(feature = "notify", feature = "scrobble"))]
+    pub fn take_info(&mut self) -> Option<TrackInfo> {
+        self.info.take()
+    }
+
+    /// The length of the track, if the container says.
+    #[cfg(feature = "scrobble")]
+    pub fn duration(&self) -> Option<Duration> {
+        let params = &self.probed.format.default_track()?.codec_params;
+        let frames = params.n_frames?;
+        let rate = params.sample_rate?.max(1) as u64;
+        Some(Duration::from_millis(frames * 1000 / rate))
     }
 
     // pub fn samples_to_dur(&self, samples: u64) -> Duration {
@@ -331,6 +749,68 @@ impl Decoder {
     //     )
     // }
 
+    // The server can send tracks one after another over one connection.
+    // Once the container ends, probe what follows it and carry on if it is
+    // another track that the output can play as it is.
+    fn chain_next(&mut self) -> bool {
+        let format = mem::replace(&mut self.probed.format, Box::new(Finished::new()));
+        let probed = match symphonia::default::get_probe().format(
+            &Hint::new(),
+            format.into_inner(),
+            &format_options(),
+            &MetadataOptions::default(),
+        ) {
+            Ok(probed) => probed,
+            Err(_) => return false,
+        };
+
+        let Some(track) = probed.format.default_track() else {
+            return false;
+        };
+        if track.codec_params.sample_rate != Some(self.spec.source_rate)
+            || stream_channels(&track.codec_params) != Some(self.spec.source)
+        {
+            warn!("Next track in stream has a different format, ending stream");
+            return false;
+        }
+        let Ok(decoder) =
+            symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())
+        else {
+            warn!("No decoder for next track in stream, ending stream");
+            return false;
+        };
+
+        info!("Stream continues with another track");
+        let mut probed = probed;
+        if self.checksum.is_some() || self.levels.is_some() {
+            // Reports on the track just ended
+            let info = read_info(&mut probed);
+            let format = describe_format(&probed, self.spec.source_rate, self.spec.layout);
+            self.checksum = self
+                .checksum
+                .as_ref()
+                .and_then(|tap| Tap::new(tap.log(), info.as_ref(), format));
+            self.levels = self.levels.is_some().then(|| Levels::new(info.as_ref()));
+        }
+        self.probed = probed;
+        self.decoder = decoder;
+        self.frames_decoded = 0;
+        true
+    }
+
+    /// Fade this stream in and out as set by the transition.
+    pub fn set_transition(&mut self, transition: Transition) {
+        self.transition = transition;
+    }
+
+    /// The duration of a number of bytes of raw audio in this stream's format.
+    pub fn bytes_to_dur(&self, bytes: usize) -> Duration {
+        let bytes_per_sec = self.spec.sample_rate as u64
+            * self.spec.channels as u64
+            * self.spec.format.size_of() as u64;
+        Duration::from_micros(bytes as u64 * 1_000_000 / bytes_per_sec.max(1))
+    }
+
     pub fn dur_to_samples(&self, dur: Duration) -> u64 {
         self.spec.sample_rate as u64
             * self.spec.channels as u64
@@ -340,30 +820,290 @@ impl Decoder {
     }
 }
 
+/// Create a decoder for a local audio file, guessing the format from its
+/// extension.
+pub fn make_file_decoder(path: &Path, options: &DecodeOptions) -> anyhow::Result<Decoder> {
+    let file = File::open(path).with_context(|| format!("Unable to open {}", path.display()))?;
+    let format = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("flac") => Format::Flac,
+        Some("mp3") => Format::Mp3,
+        Some("ogg") => Format::Ogg,
+        Some("m4a" | "aac") => Format::Aac,
+        _ => Format::Pcm,
+    };
+
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    Decoder::try_new(
+        mss,
+        &format,
+        &PcmSampleRate::SelfDescribing,
+        &PcmChannels::SelfDescribing,
+        options,
+    )
+    .with_context(|| format!("Unable to decode {}", path.display()))
+}
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Streams on this port are taken to be served over TLS
+const HTTPS_PORT: u16 = 443;
+
+/// How streams are fetched from the server.
+#[derive(Clone, Debug)]
+pub struct StreamOptions {
+    /// Always fetch streams from the server the player is connected to,
+    /// not the address the server asks for.
+    pub host_override: bool,
+    /// Ask for a receive buffer of this many bytes on each data stream,
+    /// which can smooth high bitrate streams over wifi. Zero leaves the
+    /// system default.
+    pub rcvbuf: usize,
+    /// Stop reading data streams once playback has been paused for this
+    /// long, leaving TCP flow control to hold back the server, and carry
+    /// on when it resumes.
+    pub pause_stops_streaming: Option<Duration>,
+    /// The value of the Authorization header sent with each request for a
+    /// stream the server serves itself, see [`basic_authorization`]. It is
+    /// never sent to other hosts, such as a radio station's.
+    pub authorization: Option<String>,
+    /// The port of the server's web interface, from which it serves its
+    /// own streams.
+    pub web_port: u16,
+    /// The transition for streams the server sends without one.
+    pub transition: Transition,
+    /// What is done to each stream's audio as it is decoded.
+    pub decode: DecodeOptions,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            host_override: false,
+            rcvbuf: 0,
+            pause_stops_streaming: None,
+            authorization: None,
+            web_port: DEFAULT_HTTP_PORT,
+            transition: Transition::default(),
+            decode: DecodeOptions::default(),
+        }
+    }
+}
+
+impl StreamOptions {
+    // Whether a stream at `ip` and `port` is served by the server itself,
+    // at `server_ip`, rather than by e.g. a radio station's own host
+    fn is_server(&self, ip: Ipv4Addr, port: u16, server_ip: Ipv4Addr) -> bool {
+        ip == server_ip && port == self.web_port
+    }
+
+    // The credentials to send with a request, which only the server gets
+    fn authorization_for(&self, from_server: bool) -> Option<&str> {
+        self.authorization.as_deref().filter(|_| from_server)
+    }
+}
+
+/// The Authorization header value that logs in to a password protected
+/// server with these credentials, given as `user:password`.
+pub fn basic_authorization(credentials: &str) -> String {
+    format!("Basic {}", base64(credentials.as_bytes()))
+}
+
+fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                output.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+// Returns the size the kernel settled on, which may be clamped or, on
+// Linux, doubled for bookkeeping
+fn set_rcvbuf(stream: &TcpStream, bytes: usize) -> std::io::Result<usize> {
+    let fd = stream.as_raw_fd();
+    let size = bytes.min(libc::c_int::MAX as usize) as libc::c_int;
+    let optlen = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    if unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUF,
+            &size as *const libc::c_int as *const libc::c_void,
+            optlen,
+        )
+    } != 0
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let mut effective: libc::c_int = 0;
+    let mut len = optlen;
+    if unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUF,
+            &mut effective as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    } != 0
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(effective as usize)
+}
+
+/// A stream the server asks for with a `Stream` message, as the decoder
+/// needs it.
+pub struct StreamRequest {
+    /// Where to fetch the stream from, unspecified for the server itself.
+    pub server_ip: Ipv4Addr,
+    pub server_port: u16,
+    pub http_headers: String,
+    /// How much of the stream to buffer, in KiB, before the server is told
+    /// the threshold is reached.
+    pub threshold: u32,
+    pub format: Format,
+    pub pcmsamplerate: PcmSampleRate,
+    pub pcmchannels: PcmChannels,
+    pub autostart: AutoStart,
+    pub output_threshold: Duration,
+    pub transition: Transition,
+}
+
+// slimproto's types for the fields of a Stream message are not Clone
+impl Clone for StreamRequest {
+    fn clone(&self) -> Self {
+        Self {
+            server_ip: self.server_ip,
+            server_port: self.server_port,
+            http_headers: self.http_headers.clone(),
+            threshold: self.threshold,
+            format: match self.format {
+                Format::Pcm => Format::Pcm,
+                Format::Mp3 => Format::Mp3,
+                Format::Flac => Format::Flac,
+                Format::Wma => Format::Wma,
+                Format::Ogg => Format::Ogg,
+                Format::Aac => Format::Aac,
+                Format::Alac => Format::Alac,
+            },
+            pcmsamplerate: match self.pcmsamplerate {
+                PcmSampleRate::Rate(rate) => PcmSampleRate::Rate(rate),
+                PcmSampleRate::SelfDescribing => PcmSampleRate::SelfDescribing,
+            },
+            pcmchannels: match self.pcmchannels {
+                PcmChannels::Mono => PcmChannels::Mono,
+                PcmChannels::Stereo => PcmChannels::Stereo,
+                PcmChannels::SelfDescribing => PcmChannels::SelfDescribing,
+            },
+            autostart: match self.autostart {
+                AutoStart::None => AutoStart::None,
+                AutoStart::Auto => AutoStart::Auto,
+                AutoStart::Direct => AutoStart::Direct,
+                AutoStart::AutoDirect => AutoStart::AutoDirect,
+            },
+            output_threshold: self.output_threshold,
+            transition: self.transition,
+        }
+    }
+}
+
+/// What a stream's decoder shares with the rest of the player.
+pub struct StreamContext {
+    /// The address of the server the player is connected to.
+    pub default_ip: Ipv4Addr,
+    pub stream_in: Sender<PlayerMsg>,
+    pub status: Arc<Mutex<StatusData>>,
+    pub volume: Arc<Mutex<Volume>>,
+    pub skip: Arc<AtomicCell<Duration>>,
+    pub stats: Arc<Stats>,
+    pub output: Arc<OutputState>,
+}
+
 pub fn make_decoder(
-    server_ip: Ipv4Addr,
-    default_ip: Ipv4Addr,
-    server_port: u16,
-    http_headers: String,
-    stream_in: Sender<PlayerMsg>,
-    status: Arc<Mutex<StatusData>>,
-    threshold: u32,
-    format: slimproto::proto::Format,
-    pcmsamplerate: slimproto::proto::PcmSampleRate,
-    pcmchannels: slimproto::proto::PcmChannels,
-    autostart: slimproto::proto::AutoStart,
-    volume: Arc<Mutex<Vec<f32>>>,
-    #[cfg(feature = "pulse")] skip: Arc<AtomicCell<Duration>>,
-    output_threshold: Duration,
+    request: StreamRequest,
+    context: StreamContext,
+    options: &StreamOptions,
 ) -> anyhow::Result<(Decoder, StreamParams)> {
+    let StreamRequest {
+        server_ip,
+        server_port,
+        http_headers,
+        threshold,
+        format,
+        pcmsamplerate,
+        pcmchannels,
+        autostart,
+        output_threshold,
+        transition,
+    } = request;
+    let StreamContext {
+        default_ip,
+        stream_in,
+        status,
+        volume,
+        skip,
+        stats,
+        output,
+    } = context;
+
     let ip = if server_ip.is_unspecified() {
         default_ip
+    } else if options.host_override && server_ip != default_ip {
+        info!("Fetching stream from {default_ip} instead of {server_ip} as overridden");
+        default_ip
     } else {
         server_ip
     };
 
-    let data_stream = match make_connection(ip, server_port, http_headers) {
+    #[cfg(not(feature = "tls"))]
+    if server_port == HTTPS_PORT {
+        bail!("The stream is served over HTTPS, which needs Vibe built with the tls feature");
+    }
+
+    let data_stream = match make_connection(
+        ip,
+        server_port,
+        &http_headers,
+        options.is_server(ip, server_port, default_ip),
+        options,
+    ) {
         Ok(data_s) => data_s,
+        Err(e) if refused_certificate(&e) => return Err(e),
+        // The server may give an address that can only be reached from its
+        // own network, e.g. when it is reached over a VPN, so fall back to
+        // the address of the control connection
+        Err(e) if ip != default_ip && !default_ip.is_unspecified() => {
+            warn!("Unable to connect to data stream at {ip} ({e}), trying {default_ip}");
+            match make_connection(
+                default_ip,
+                server_port,
+                &http_headers,
+                options.is_server(default_ip, server_port, default_ip),
+                options,
+            ) {
+                Ok(data_s) => {
+                    info!("Fetching stream from {default_ip}, see --stream-host-override");
+                    data_s
+                }
+                Err(e) => {
+                    warn!("Unable to connect to data stream at {}", default_ip);
+                    return Err(e);
+                }
+            }
+        }
         Err(e) => {
             warn!("Unable to connect to data stream at {}", ip);
             return Err(e);
@@ -372,37 +1112,539 @@ pub fn make_decoder(
 
     stream_in.send(PlayerMsg::Connected).ok();
 
-    let mss = MediaSourceStream::new(
-        Box::new(ReadOnlySource::new(SlimBuffer::with_capacity(
-            threshold as usize * 1024,
+    let written = WriteMarker::new();
+
+    let source = ProbeSource::new(SlimBuffer::with_capacity(
+        threshold as usize * 1024,
+        DataStreamReader::new(
             data_stream,
-            status,
-            threshold,
-            None,
-        ))),
-        Default::default(),
-    );
-    stream_in.send(PlayerMsg::BufferThreshold).ok();
+            threshold as usize * 1024,
+            stream_in.clone(),
+            written.clone(),
+            output,
+            options.pause_stops_streaming,
+        ),
+        status,
+        threshold,
+        None,
+    ));
+
+    let open = |source: &ProbeSource<_>| {
+        let mss = MediaSourceStream::new(
+            Box::new(ReadOnlySource::new(source.clone())),
+            Default::default(),
+        );
+        Decoder::try_new(mss, &format, &pcmsamplerate, &pcmchannels, &options.decode)
+    };
+
+    // The start of a stream may not hold enough to recognise the format,
+    // e.g. behind a large tag, so try again from the start with more of it
+    let mut decoder = match open(&source) {
+        Err(OpenError::ProbeFailed(e)) => {
+            let extra = source.rewind(PROBE_RETRY_BYTES);
+            info!("Unable to recognise stream format ({e}), trying again with {extra} more bytes");
+            open(&source)?
+        }
+        result => result?,
+    };
+    source.release();
+    decoder.set_transition(transition);
 
     Ok((
-        Decoder::try_new(mss, format, pcmsamplerate, pcmchannels)?,
+        decoder,
         StreamParams {
             autostart,
             volume,
-            #[cfg(feature = "pulse")]
             skip,
             output_threshold,
+            stats,
+            written,
         },
     ))
 }
 
-fn make_connection(ip: Ipv4Addr, port: u16, http_headers: String) -> anyhow::Result<TcpStream> {
-    let mut data_stream = TcpStream::connect((ip, port))?;
-    let mut headers = Vec::new();
-    headers.push(http_headers.trim());
-    // headers.push("Icy-Metadata: 1");
-    data_stream.write(headers.join("\r\n").as_bytes())?;
-    data_stream.write("\r\n\r\n".as_bytes())?;
+// Longest a single read is held back for a starved output, in case the
+// playing stream ends without clearing the signal
+const MAX_PREBUFFER_HOLD: Duration = Duration::from_secs(1);
+const PAUSED_POLL: Duration = Duration::from_millis(100);
+const STALL_AFTER: Duration = Duration::from_secs(5);
+const PROBE_RETRY_BYTES: usize = 256 * 1024;
+
+// Keeps what has been read from a stream until released, so that a failed
+// probe can be retried from the start of the stream. The probe takes its
+// source by value and drops it on failure, hence the shared state.
+struct ProbeSource<R> {
+    inner: Arc<Mutex<ProbeState<R>>>,
+}
+
+struct ProbeState<R> {
+    reader: R,
+    kept: Vec<u8>,
+    pos: usize,
+    keeping: bool,
+}
+
+impl<R> Clone for ProbeSource<R> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<R: Read> ProbeSource<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(ProbeState {
+                reader,
+                kept: Vec::new(),
+                pos: 0,
+                keeping: true,
+            })),
+        }
+    }
+
+    // Read up to `extra` more bytes, or to the end of the stream, then go
+    // back to the start. Returns how many more bytes were read.
+    fn rewind(&self, extra: usize) -> usize {
+        let Ok(mut state) = self.inner.lock() else {
+            return 0;
+        };
+        let start = state.kept.len();
+        let mut chunk = [0u8; 8192];
+        while state.kept.len() - start < extra && !threads::shutting_down() {
+            let want = chunk.len().min(extra - (state.kept.len() - start));
+            match state.reader.read(&mut chunk[..want]) {
+                Ok(0) | Err(_) => break,
+                Ok(len) => state.kept.extend_from_slice(&chunk[..len]),
+            }
+        }
+        state.pos = 0;
+        state.kept.len() - start
+    }
+
+    // Stop keeping what is read, once the format is known
+    fn release(&self) {
+        if let Ok(mut state) = self.inner.lock() {
+            state.keeping = false;
+        }
+    }
+}
+
+impl<R: Read> Read for ProbeSource<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut state = self
+            .inner
+            .lock()
+            .map_err(|_| std::io::Error::other("probe source poisoned"))?;
+        let state = &mut *state;
+
+        if state.pos < state.kept.len() {
+            let len = buf.len().min(state.kept.len() - state.pos);
+            buf[..len].copy_from_slice(&state.kept[state.pos..state.pos + len]);
+            state.pos += len;
+            return Ok(len);
+        }
+
+        let len = state.reader.read(buf)?;
+        if state.keeping {
+            state.kept.extend_from_slice(&buf[..len]);
+            state.pos = state.kept.len();
+        } else if !state.kept.is_empty() {
+            state.kept = Vec::new();
+            state.pos = 0;
+        }
+        Ok(len)
+    }
+}
+
+// Reads the data stream for a decoder. Tells the server the buffer
+// threshold has been reached once the given number of bytes has arrived,
+// or the stream ends short of it, so that autostart waits for the data
+// the server asked for. Past the threshold, a stream that is not playing
+// yet holds back while the playing one is short of audio, so that
+// prebuffering the next track does not starve the current one. A playing
+// stream that stalls without ending is reported once until data flows
+// again, so that the server can show it as buffering.
+struct DataStreamReader<R> {
+    inner: R,
+    remaining: usize,
+    stream_in: Option<Sender<PlayerMsg>>,
+    stall_in: Sender<PlayerMsg>,
+    written: WriteMarker,
+    output: Arc<OutputState>,
+    pause_stops_streaming: Option<Duration>,
+}
+
+impl<R: Read> DataStreamReader<R> {
+    fn new(
+        inner: R,
+        threshold: usize,
+        stream_in: Sender<PlayerMsg>,
+        written: WriteMarker,
+        output: Arc<OutputState>,
+        pause_stops_streaming: Option<Duration>,
+    ) -> Self {
+        Self {
+            inner,
+            remaining: threshold,
+            stall_in: stream_in.clone(),
+            stream_in: Some(stream_in),
+            written,
+            output,
+            pause_stops_streaming,
+        }
+    }
+
+    // Waits out read timeouts, which mean the stream has stalled
+    fn read_through_stalls(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut stalled = false;
+        loop {
+            match self.inner.read(buf) {
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) && !threads::shutting_down() =>
+                {
+                    if !stalled && !self.written.written().is_zero() {
+                        warn!("No data from stream for {STALL_AFTER:?}");
+                        self.stall_in.send(PlayerMsg::StreamStalled).ok();
+                        stalled = true;
+                    }
+                }
+                result => {
+                    if stalled {
+                        info!("Stream data is flowing again");
+                    }
+                    return result;
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for DataStreamReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.stream_in.is_none() && self.written.written().is_zero() {
+            let start = Instant::now();
+            while self.output.starved()
+                && start.elapsed() < MAX_PREBUFFER_HOLD
+                && !threads::shutting_down()
+            {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+
+        if let Some(after) = self.pause_stops_streaming {
+            let mut held = false;
+            while self
+                .output
+                .paused_for()
+                .is_some_and(|paused| paused >= after)
+                && !threads::shutting_down()
+            {
+                if !held {
+                    info!("Paused for over {after:?}, no longer reading the data stream");
+                    held = true;
+                }
+                std::thread::sleep(PAUSED_POLL);
+            }
+            if held {
+                info!("Reading the data stream again");
+            }
+        }
+
+        let len = self.read_through_stalls(buf)?;
+        self.remaining = self.remaining.saturating_sub(len);
+        if self.remaining == 0 || len == 0 {
+            if let Some(stream_in) = self.stream_in.take() {
+                stream_in.send(PlayerMsg::BufferThreshold).ok();
+            }
+        }
+        Ok(len)
+    }
+}
+
+// The connection a stream's data arrives on
+enum DataStream {
+    Plain(TcpStream),
+    // Buffered, so that the start of the response can be looked at without
+    // taking it from the stream
+    #[cfg(feature = "tls")]
+    Tls(Box<BufReader<tls::TlsStream>>),
+}
+
+impl DataStream {
+    // The server gives no sign of TLS other than the port, and the request
+    // names the host, which the certificate is checked against
+    #[cfg(feature = "tls")]
+    fn tls(http_headers: &str, ip: Ipv4Addr, socket: TcpStream) -> anyhow::Result<Self> {
+        let host = request_host(http_headers).map_or_else(|| ip.to_string(), str::to_owned);
+        Ok(Self::Tls(Box::new(BufReader::new(tls::connect(
+            &host, socket,
+        )?))))
+    }
+
+    // Read the start of the response, leaving it in the stream
+    fn peek(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.peek(buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => {
+                let start = stream.fill_buf()?;
+                let len = start.len().min(buf.len());
+                buf[..len].copy_from_slice(&start[..len]);
+                Ok(len)
+            }
+        }
+    }
+}
+
+impl Read for DataStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for DataStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => stream.get_mut().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Plain(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => stream.get_mut().flush(),
+        }
+    }
+}
+
+// The host named by the request's Host header, without its port
+#[cfg(feature = "tls")]
+fn request_host(http_headers: &str) -> Option<&str> {
+    let value = http_headers
+        .lines()
+        .skip(1)
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.trim().eq_ignore_ascii_case("host").then_some(value)
+        })?
+        .trim();
+    let host = match value.rsplit_once(':') {
+        Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+        _ => value,
+    };
+    (!host.is_empty()).then_some(host)
+}
+
+// A refused certificate is reported as it is rather than as a failure to
+// connect, and would be no different at another address
+#[cfg(feature = "tls")]
+fn refused_certificate(e: &anyhow::Error) -> bool {
+    e.is::<tls::CertificateRefused>()
+}
+
+#[cfg(not(feature = "tls"))]
+fn refused_certificate(_: &anyhow::Error) -> bool {
+    false
+}
+
+// Credentials are only sent to the server itself, which `from_server`
+// tells, never to the other hosts it may send the player to
+fn make_connection(
+    ip: Ipv4Addr,
+    port: u16,
+    http_headers: &str,
+    from_server: bool,
+    options: &StreamOptions,
+) -> anyhow::Result<DataStream> {
+    let socket = TcpStream::connect_timeout(&(ip, port).into(), CONNECT_TIMEOUT)?;
+    socket.set_read_timeout(Some(STALL_AFTER))?;
+    let rcvbuf = options.rcvbuf;
+    if rcvbuf > 0 {
+        match set_rcvbuf(&socket, rcvbuf) {
+            Ok(effective) => info!(
+                "Data stream receive buffer: asked for {} KiB, got {} KiB",
+                rcvbuf / 1024,
+                effective / 1024
+            ),
+            Err(e) => warn!("Unable to set data stream receive buffer: {e}"),
+        }
+    }
+    let mut data_stream = match port {
+        #[cfg(feature = "tls")]
+        HTTPS_PORT => DataStream::tls(http_headers, ip, socket)?,
+        _ => DataStream::Plain(socket),
+    };
+    let host = match port {
+        80 | HTTPS_PORT => ip.to_string(),
+        _ => format!("{ip}:{port}"),
+    };
+    let authorization = options.authorization_for(from_server);
+    let request = make_request(http_headers, &host, authorization);
+    data_stream.write_all(request.as_bytes())?;
     data_stream.flush()?;
+    check_response(&mut data_stream, from_server, authorization.is_some())?;
     Ok(data_stream)
 }
+
+// Look at the status line of the response, without taking it from the
+// stream, so that a refusal is reported as such rather than as audio that
+// cannot be decoded
+fn check_response(
+    data_stream: &mut DataStream,
+    from_server: bool,
+    authorized: bool,
+) -> anyhow::Result<()> {
+    let mut start = [0u8; 12];
+    // Anything unusual, e.g. a slow start, is left to the reads that follow
+    let Ok(len) = data_stream.peek(&mut start) else {
+        return Ok(());
+    };
+    let status = std::str::from_utf8(&start[..len])
+        .ok()
+        .filter(|line| line.starts_with("HTTP/"))
+        .and_then(|line| line.split(' ').nth(1));
+    match status {
+        Some("401") if authorized => {
+            bail!("The server refused the username and password given with --auth")
+        }
+        Some("401") if from_server => {
+            bail!("The server needs a username and password, give them with --auth")
+        }
+        Some("401") => bail!("The stream's host needs a login, which is not supported"),
+        Some("403") => bail!("The server refused access to the stream"),
+        _ => Ok(()),
+    }
+}
+
+// The server's header block may be anything from a bare request line to a
+// complete request with its own terminating blank line. Normalise it so that
+// there is always a Host header and exactly one blank line at the end,
+// leaving every other line as the server sent it.
+fn make_request(http_headers: &str, host: &str, authorization: Option<&str>) -> String {
+    let mut lines = http_headers
+        .trim()
+        .split('\n')
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<&str>>();
+
+    let has_host = lines
+        .iter()
+        .skip(1)
+        .any(|line| line.to_ascii_lowercase().starts_with("host:"));
+
+    let host_line = format!("Host: {host}");
+    if !has_host && !lines.is_empty() {
+        lines.insert(1, &host_line);
+    }
+
+    // Only sent when credentials are given, so public servers see the
+    // request as before
+    let auth_line = authorization.map(|auth| format!("Authorization: {auth}"));
+    let has_auth = lines
+        .iter()
+        .skip(1)
+        .any(|line| line.to_ascii_lowercase().starts_with("authorization:"));
+    if let Some(ref auth_line) = auth_line {
+        if !has_auth && !lines.is_empty() {
+            lines.insert(1, auth_line);
+        }
+    }
+
+    // lines.push("Icy-Metadata: 1");
+    let mut request = lines.join("\r\n");
+    request.push_str("\r\n\r\n");
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUEST: &str = "GET /stream.mp3?player=02:00:00:00:00:01 HTTP/1.0\r\n";
+
+    fn credentials() -> StreamOptions {
+        StreamOptions {
+            authorization: Some(basic_authorization("user:secret")),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn request_gets_a_host_header() {
+        let request = make_request(REQUEST, "192.168.1.10:9000", None);
+        assert_eq!(
+            request,
+            "GET /stream.mp3?player=02:00:00:00:00:01 HTTP/1.0\r\n\
+             Host: 192.168.1.10:9000\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn request_keeps_the_servers_headers() {
+        // As sent by the server, with bare line ends and its own Host
+        let headers = "GET /stream.flac HTTP/1.0\nHost: radio.example\nIcy-MetaData: 1\n\n";
+        let request = make_request(headers, "203.0.113.5", None);
+        assert_eq!(
+            request,
+            "GET /stream.flac HTTP/1.0\r\nHost: radio.example\r\nIcy-MetaData: 1\r\n\r\n"
+        );
+        assert_eq!(request.matches("Host:").count(), 1);
+    }
+
+    #[test]
+    fn request_gets_authorization_when_given() {
+        let authorization = basic_authorization("user:secret");
+        assert_eq!(authorization, "Basic dXNlcjpzZWNyZXQ=");
+        let request = make_request(REQUEST, "192.168.1.10:9000", Some(&authorization));
+        assert!(request.starts_with(REQUEST));
+        assert!(request.contains("\r\nAuthorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+        assert!(request.contains("\r\nHost: 192.168.1.10:9000\r\n"));
+        assert!(request.ends_with("\r\n\r\n"));
+
+        // The server's own Authorization wins
+        let headers = format!("{REQUEST}Authorization: Basic b3duOmF1dGg=\r\n");
+        let request = make_request(&headers, "192.168.1.10:9000", Some(&authorization));
+        assert_eq!(request.matches("Authorization:").count(), 1);
+        assert!(request.contains("Basic b3duOmF1dGg="));
+    }
+
+    #[test]
+    fn request_has_no_authorization_unless_given() {
+        let request = make_request(REQUEST, "192.168.1.10:9000", None);
+        assert!(!request.to_ascii_lowercase().contains("authorization:"));
+    }
+
+    #[test]
+    fn credentials_go_only_to_the_server() {
+        let options = credentials();
+        let server = Ipv4Addr::new(192, 168, 1, 10);
+        assert!(options.is_server(server, DEFAULT_HTTP_PORT, server));
+        // A radio station's host, or another service on the server's host
+        assert!(!options.is_server(Ipv4Addr::new(203, 0, 113, 5), DEFAULT_HTTP_PORT, server));
+        assert!(!options.is_server(server, 80, server));
+    }
+
+    #[test]
+    fn foreign_host_gets_no_authorization() {
+        let options = credentials();
+        let server = Ipv4Addr::new(192, 168, 1, 10);
+        let foreign = Ipv4Addr::new(203, 0, 113, 5);
+        let authorization = options.authorization_for(options.is_server(foreign, 80, server));
+        assert!(authorization.is_none());
+        let request = make_request(REQUEST, "203.0.113.5", authorization);
+        assert!(!request.to_ascii_lowercase().contains("authorization:"));
+    }
+}
@@ -0,0 +1,81 @@
+//! Finding a server by sending the discovery request to chosen addresses,
+//! for servers the usual broadcast to 255.255.255.255 does not reach, e.g.
+//! in another subnet reached by a directed broadcast the router forwards.
+//!
+//! Without chosen addresses, the discovery in slimproto is used as is.
+//! Either way, how long each attempt waits for a server can be chosen.
+
+use std::{
+    io::{self, ErrorKind},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use slimproto::proto::{Server, SLIM_PORT};
+
+// Asks for the same details as other players do, only the reply's type and
+// where it came from are used
+const REQUEST: &[u8] = b"eIPAD\0NAME\0JSON\0VERS\0";
+// How long to wait for a reply to each address before trying the next
+const WAIT_EACH: Duration = Duration::from_secs(2);
+
+/// How long each attempt at discovery waits for a server, unless another
+/// time is chosen.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Look for a server for up to `timeout`, or until one is found if `None`,
+/// by sending the request to each of `addresses` in turn, or by
+/// broadcasting if there are none.
+pub fn discover(
+    addresses: &[SocketAddrV4],
+    timeout: Option<Duration>,
+) -> io::Result<Option<Server>> {
+    if addresses.is_empty() {
+        return slimproto::discovery::discover(timeout);
+    }
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    // A short timeout is kept to for each address too
+    let wait_each = timeout.map_or(WAIT_EACH, |timeout| timeout.min(WAIT_EACH));
+    socket.set_read_timeout(Some(wait_each))?;
+
+    let start = Instant::now();
+    loop {
+        for address in addresses {
+            if let Some(ip) = ask(&socket, *address, wait_each)? {
+                info!("Found server at {ip}, in reply to discovery sent to {address}");
+                return Ok(Some(Server::from(SocketAddrV4::new(ip, SLIM_PORT))));
+            }
+            if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+// Send the request to one address and wait for a server to reply. A
+// failure to send is logged, as the next address may do better.
+fn ask(socket: &UdpSocket, address: SocketAddrV4, wait: Duration) -> io::Result<Option<Ipv4Addr>> {
+    if let Err(e) = socket.send_to(REQUEST, address) {
+        warn!("Unable to send discovery to {address}: {e}");
+        return Ok(None);
+    }
+
+    let mut buf = [0u8; 1500];
+    let until = Instant::now() + wait;
+    while Instant::now() < until {
+        match socket.recv_from(&mut buf) {
+            // Only a server's reply starts with 'E', anything else, e.g.
+            // another player's request, is ignored
+            Ok((len, SocketAddr::V4(from))) if len > 0 && buf[0] == b'E' => {
+                return Ok(Some(*from.ip()))
+            }
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
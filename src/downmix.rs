@@ -0,0 +1,113 @@
+//! Mix streams with more than two channels down to stereo, for outputs
+//! that only have a left and a right speaker.
+//!
+//! Front left and right go to their own side at full level, centre
+//! channels to both sides and other left or right channels to their side,
+//! all at -3 dB. The low frequency channels are left out. Each side is
+//! scaled so that it cannot clip.
+//!
+//! An output locked to mono gets the average of the two sides, and one
+//! locked to stereo plays a mono stream on both.
+
+use symphonia::core::audio::{AudioBuffer, Channels, Signal, SignalSpec};
+
+/// Streams with more channels than this are always mixed down to stereo.
+pub const MAX_CHANNELS: usize = 8;
+
+const SIDE_GAIN: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// The channels to play a stream with these channels on, mixing every
+/// stream with more than two channels down to stereo if `stereo`.
+pub(crate) fn output_channels(source: Channels, stereo: bool) -> Channels {
+    if source.count() > 2 && (stereo || source.count() > MAX_CHANNELS) {
+        Channels::FRONT_LEFT | Channels::FRONT_RIGHT
+    } else {
+        source
+    }
+}
+
+// The share of a channel sent to the left and right
+fn weights(channel: Channels) -> (f32, f32) {
+    let lefts = Channels::REAR_LEFT
+        | Channels::FRONT_LEFT_CENTRE
+        | Channels::SIDE_LEFT
+        | Channels::TOP_FRONT_LEFT
+        | Channels::TOP_REAR_LEFT
+        | Channels::REAR_LEFT_CENTRE
+        | Channels::FRONT_LEFT_WIDE
+        | Channels::FRONT_LEFT_HIGH;
+    let rights = Channels::REAR_RIGHT
+        | Channels::FRONT_RIGHT_CENTRE
+        | Channels::SIDE_RIGHT
+        | Channels::TOP_FRONT_RIGHT
+        | Channels::TOP_REAR_RIGHT
+        | Channels::REAR_RIGHT_CENTRE
+        | Channels::FRONT_RIGHT_WIDE
+        | Channels::FRONT_RIGHT_HIGH;
+
+    if channel == Channels::FRONT_LEFT {
+        (1.0, 0.0)
+    } else if channel == Channels::FRONT_RIGHT {
+        (0.0, 1.0)
+    } else if channel.intersects(Channels::LFE1 | Channels::LFE2) {
+        (0.0, 0.0)
+    } else if lefts.contains(channel) {
+        (SIDE_GAIN, 0.0)
+    } else if rights.contains(channel) {
+        (0.0, SIDE_GAIN)
+    } else {
+        (SIDE_GAIN, SIDE_GAIN)
+    }
+}
+
+/// Mix a buffer down to front left and right.
+pub(crate) fn to_stereo(buffer: &AudioBuffer<f32>) -> AudioBuffer<f32> {
+    let spec = buffer.spec();
+    let weights = spec.channels.iter().map(weights).collect::<Vec<_>>();
+    let left_total = weights.iter().map(|w| w.0).sum::<f32>().max(1.0);
+    let right_total = weights.iter().map(|w| w.1).sum::<f32>().max(1.0);
+
+    let mut stereo = AudioBuffer::<f32>::new(
+        buffer.capacity() as u64,
+        SignalSpec::new(spec.rate, Channels::FRONT_LEFT | Channels::FRONT_RIGHT),
+    );
+    stereo.render_reserved(Some(buffer.frames()));
+
+    for (chan, (left, right)) in weights.iter().enumerate() {
+        let source = buffer.chan(chan);
+        for (out, sample) in stereo.chan_mut(0).iter_mut().zip(source) {
+            *out += sample * left / left_total;
+        }
+        for (out, sample) in stereo.chan_mut(1).iter_mut().zip(source) {
+            *out += sample * right / right_total;
+        }
+    }
+    stereo
+}
+
+/// Mix a buffer to `channels`, which is mono or stereo.
+pub(crate) fn to_channels(buffer: &AudioBuffer<f32>, channels: Channels) -> AudioBuffer<f32> {
+    let mixed = (buffer.spec().channels.count() > 2).then(|| to_stereo(buffer));
+    let source = mixed.as_ref().unwrap_or(buffer);
+    let count = source.spec().channels.count();
+
+    let mut output = AudioBuffer::<f32>::new(
+        source.capacity() as u64,
+        SignalSpec::new(source.spec().rate, channels),
+    );
+    output.render_reserved(Some(source.frames()));
+
+    for chan in 0..channels.count() {
+        let out = output.chan_mut(chan);
+        if channels.count() == 1 {
+            for from in 0..count {
+                for (out, sample) in out.iter_mut().zip(source.chan(from)) {
+                    *out += sample / count as f32;
+                }
+            }
+        } else {
+            out.copy_from_slice(source.chan(chan.min(count - 1)));
+        }
+    }
+    output
+}
@@ -0,0 +1,200 @@
+//! A hook for external processing of decoded audio, such as room
+//! correction, so that it applies the same way whatever the audio system.
+//!
+//! The hook is either a shared library exporting [`PROCESS_SYMBOL`], and
+//! optionally [`LATENCY_SYMBOL`], or a command that reads interleaved
+//! little-endian f32 samples on stdin and writes the same number back on
+//! stdout. The command is started with `VIBE_RATE` and `VIBE_CHANNELS` set
+//! and is restarted when either changes.
+
+use std::{
+    io::{Read, Write},
+    path::Path,
+    process::{Child, Command, Stdio},
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use libloading::Library;
+use log::{info, warn};
+use symphonia::core::audio::{AudioBuffer, Signal};
+
+/// Process `len` interleaved samples in place, returning zero on success.
+pub type ProcessFn =
+    unsafe extern "C" fn(samples: *mut f32, len: usize, channels: u32, rate: u32) -> i32;
+
+/// The delay added by the processing, in frames at the given rate.
+pub type LatencyFn = unsafe extern "C" fn(rate: u32) -> u32;
+
+pub const PROCESS_SYMBOL: &[u8] = b"vibe_dsp_process";
+pub const LATENCY_SYMBOL: &[u8] = b"vibe_dsp_latency";
+
+static HOOK: OnceLock<Mutex<Option<Hook>>> = OnceLock::new();
+
+pub enum Hook {
+    Plugin {
+        // Kept loaded for as long as the function pointers are in use
+        _library: Library,
+        process: ProcessFn,
+        latency: Option<LatencyFn>,
+    },
+    Exec {
+        command: String,
+        child: Option<(Child, u32, u32)>,
+    },
+}
+
+impl Hook {
+    pub fn load_plugin(path: &Path) -> anyhow::Result<Self> {
+        let library = unsafe { Library::new(path) }
+            .with_context(|| format!("Unable to load DSP plugin {}", path.display()))?;
+        let process = unsafe { *library.get::<ProcessFn>(PROCESS_SYMBOL)? };
+        let latency = unsafe { library.get::<LatencyFn>(LATENCY_SYMBOL) }
+            .ok()
+            .map(|latency| *latency);
+        info!("Loaded DSP plugin {}", path.display());
+        Ok(Self::Plugin {
+            _library: library,
+            process,
+            latency,
+        })
+    }
+
+    pub fn exec(command: String) -> Self {
+        Self::Exec {
+            command,
+            child: None,
+        }
+    }
+
+    fn process(&mut self, samples: &mut [f32], channels: u32, rate: u32) -> anyhow::Result<()> {
+        match self {
+            Self::Plugin { process, .. } => {
+                match unsafe { process(samples.as_mut_ptr(), samples.len(), channels, rate) } {
+                    0 => Ok(()),
+                    code => bail!("plugin returned {code}"),
+                }
+            }
+
+            Self::Exec { command, child } => {
+                if !matches!(child, Some((_, c, r)) if *c == channels && *r == rate) {
+                    if let Some((mut old, ..)) = child.take() {
+                        old.kill().ok();
+                        old.wait().ok();
+                    }
+                    info!("Starting DSP command for {rate} Hz {channels}ch: {command}");
+                    let new = Command::new("sh")
+                        .arg("-c")
+                        .arg(command.as_str())
+                        .env("VIBE_RATE", rate.to_string())
+                        .env("VIBE_CHANNELS", channels.to_string())
+                        .stdin(Stdio::piped())
+                        .stdout(Stdio::piped())
+                        .spawn()
+                        .context("Unable to start command")?;
+                    *child = Some((new, channels, rate));
+                }
+
+                let Some((process, ..)) = child else {
+                    bail!("command not running");
+                };
+                let bytes = samples
+                    .iter()
+                    .flat_map(|s| s.to_le_bytes())
+                    .collect::<Vec<u8>>();
+                process
+                    .stdin
+                    .as_mut()
+                    .context("No stdin")?
+                    .write_all(&bytes)?;
+
+                let mut out = vec![0u8; bytes.len()];
+                process
+                    .stdout
+                    .as_mut()
+                    .context("No stdout")?
+                    .read_exact(&mut out)?;
+                for (sample, b) in samples.iter_mut().zip(out.chunks_exact(4)) {
+                    *sample = f32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn latency(&self, rate: u32) -> Duration {
+        match self {
+            Self::Plugin {
+                latency: Some(latency),
+                ..
+            } if rate > 0 => Duration::from_secs_f64(unsafe { latency(rate) } as f64 / rate as f64),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+impl Drop for Hook {
+    fn drop(&mut self) {
+        if let Self::Exec {
+            child: Some((child, ..)),
+            ..
+        } = self
+        {
+            child.kill().ok();
+            child.wait().ok();
+        }
+    }
+}
+
+/// Run all decoded audio through `hook`. Only the first call has any
+/// effect.
+pub fn set_hook(hook: Hook) {
+    HOOK.set(Mutex::new(Some(hook))).ok();
+}
+
+/// How much the hook delays the audio at the given rate, for delay
+/// compensation. Zero when the delay is unknown or there is no hook.
+pub fn latency(rate: u32) -> Duration {
+    match HOOK.get().map(|hook| hook.lock()) {
+        Some(Ok(hook)) => hook.as_ref().map(|h| h.latency(rate)).unwrap_or_default(),
+        _ => Duration::ZERO,
+    }
+}
+
+// Whether there is a hook for the audio to go through
+pub(crate) fn is_active() -> bool {
+    matches!(HOOK.get().map(|hook| hook.lock()), Some(Ok(hook)) if hook.is_some())
+}
+
+// Run the hook over a decoded buffer. A hook that fails is dropped with a
+// warning so that playback carries on without it.
+pub(crate) fn process_buffer(buffer: &mut AudioBuffer<f32>, rate: u32) {
+    let Some(Ok(mut hook)) = HOOK.get().map(|hook| hook.lock()) else {
+        return;
+    };
+    let Some(ref mut active) = *hook else {
+        return;
+    };
+
+    let channels = buffer.spec().channels.count();
+    let frames = buffer.frames();
+    let mut samples = Vec::with_capacity(frames * channels);
+    for frame in 0..frames {
+        for chan in 0..channels {
+            samples.push(buffer.chan(chan)[frame]);
+        }
+    }
+
+    if let Err(e) = active.process(&mut samples, channels as u32, rate) {
+        warn!("DSP hook failed, playing without it: {e:#}");
+        *hook = None;
+        return;
+    }
+
+    for chan in 0..channels {
+        for (frame, sample) in buffer.chan_mut(chan).iter_mut().enumerate() {
+            *sample = samples[frame * channels + chan];
+        }
+    }
+}
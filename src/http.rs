@@ -0,0 +1,23 @@
+//! One HTTP client shared by everything that makes HTTP requests, so that
+//! connections are kept alive between tracks rather than made afresh for
+//! each request, and every request has a timeout.
+
+use std::{sync::OnceLock, time::Duration};
+
+use ureq::{Agent, AgentBuilder};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const TIMEOUT: Duration = Duration::from_secs(15);
+const IDLE_CONNECTIONS_PER_HOST: usize = 2;
+
+static AGENT: OnceLock<Agent> = OnceLock::new();
+
+pub(crate) fn agent() -> &'static Agent {
+    AGENT.get_or_init(|| {
+        AgentBuilder::new()
+            .timeout_connect(CONNECT_TIMEOUT)
+            .timeout(TIMEOUT)
+            .max_idle_connections_per_host(IDLE_CONNECTIONS_PER_HOST)
+            .build()
+    })
+}
@@ -0,0 +1,142 @@
+//! Measure how long audio takes from being written to the audio system to
+//! being played, using a generated click track sent through the normal
+//! output path.
+
+use std::{
+    f32::consts::PI,
+    io::Cursor,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use crossbeam::channel::unbounded;
+use slimproto::proto::{Format, PcmChannels, PcmSampleRate};
+use symphonia::core::io::MediaSourceStream;
+
+use crate::{
+    audio_out::{AudioOutput, WriteMarker},
+    decode::{DecodeOptions, Decoder},
+    volume::Volume,
+    StreamParams,
+};
+
+const SAMPLE_RATE: u32 = 44100;
+const NUM_CLICKS: u32 = 10;
+const LEAD_IN: Duration = Duration::from_millis(500);
+const CLICK_INTERVAL: Duration = Duration::from_millis(500);
+const CLICK_LENGTH: Duration = Duration::from_millis(5);
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Play the click track on the output and print the minimum, average and
+/// maximum time between each click being written and being played.
+pub fn measure(
+    output: &mut AudioOutput,
+    system: &str,
+    device: &Option<String>,
+    options: &DecodeOptions,
+) -> anyhow::Result<()> {
+    let mss = MediaSourceStream::new(Box::new(Cursor::new(click_track())), Default::default());
+    let decoder = Decoder::try_new(
+        mss,
+        &Format::Pcm,
+        &PcmSampleRate::SelfDescribing,
+        &PcmChannels::SelfDescribing,
+        options,
+    )?;
+
+    let written = WriteMarker::new();
+    let stream_params = StreamParams::local(Arc::new(Mutex::new(Volume::new())), written.clone());
+
+    // Nothing acts on the player messages here, they are just kept
+    // from blocking the output
+    let (stream_in, _stream_out) = unbounded();
+    output.enqueue_new_stream(decoder, stream_in, stream_params);
+
+    let mut latencies = Vec::with_capacity(NUM_CLICKS as usize);
+    for click in 0..NUM_CLICKS {
+        let position = LEAD_IN + CLICK_INTERVAL * click;
+        let written_at = wait_for(|| written.written() >= position)?;
+        let played_at = wait_for(|| output.get_dur() >= position)?;
+        latencies.push(played_at - written_at);
+    }
+    output.stop();
+
+    let min = latencies.iter().min().copied().unwrap_or_default();
+    let max = latencies.iter().max().copied().unwrap_or_default();
+    let avg = latencies.iter().sum::<Duration>() / NUM_CLICKS;
+
+    println!(
+        "{:<8} {:<24} {:>8} {:>8} {:>8}",
+        "System", "Device", "Min ms", "Avg ms", "Max ms"
+    );
+    println!(
+        "{:<8} {:<24} {:>8.1} {:>8.1} {:>8.1}",
+        system,
+        device.as_deref().unwrap_or("System default device"),
+        min.as_secs_f64() * 1000.0,
+        avg.as_secs_f64() * 1000.0,
+        max.as_secs_f64() * 1000.0,
+    );
+
+    // Delay within the DSP hook happens before audio is written so is not
+    // seen above
+    #[cfg(feature = "dsp")]
+    {
+        let dsp = crate::dsp::latency(SAMPLE_RATE);
+        if !dsp.is_zero() {
+            println!("The DSP hook adds {:.1} ms", dsp.as_secs_f64() * 1000.0);
+        }
+    }
+
+    Ok(())
+}
+
+fn wait_for(mut reached: impl FnMut() -> bool) -> anyhow::Result<Instant> {
+    let start = Instant::now();
+    while !reached() {
+        if start.elapsed() > TIMEOUT {
+            bail!("Timed out waiting for playback to progress");
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    Ok(Instant::now())
+}
+
+// A 16 bit stereo WAV file of short 1 kHz clicks, after a lead-in so the
+// first click is not lost while the stream starts
+fn click_track() -> Vec<u8> {
+    let frames = |dur: Duration| (SAMPLE_RATE as u128 * dur.as_micros() / 1_000_000) as usize;
+    let lead_in = frames(LEAD_IN);
+    let interval = frames(CLICK_INTERVAL);
+    let click = frames(CLICK_LENGTH);
+    let num_frames = lead_in + interval * NUM_CLICKS as usize;
+
+    let mut data = Vec::with_capacity(num_frames * 4);
+    for n in 0..num_frames {
+        let sample = if n >= lead_in && (n - lead_in) % interval < click {
+            ((2.0 * PI * 1000.0 * n as f32 / SAMPLE_RATE as f32).sin() * 16384.0) as i16
+        } else {
+            0
+        };
+        data.extend_from_slice(&sample.to_le_bytes());
+        data.extend_from_slice(&sample.to_le_bytes());
+    }
+
+    let mut wav = Vec::with_capacity(44 + data.len());
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&2u16.to_le_bytes()); // channels
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * 4).to_le_bytes()); // bytes per second
+    wav.extend_from_slice(&4u16.to_le_bytes()); // bytes per frame
+    wav.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    wav.extend(data);
+    wav
+}
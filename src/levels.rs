@@ -0,0 +1,110 @@
+//! Checks on the level of each track as decoded, for telling a bad
+//! transcode from a fault in the player.
+//!
+//! Before the volume or anything else is applied, the peak of each track is
+//! kept, with how many samples are at full scale and the mean of each
+//! channel. As the track ends, a single warning is logged if too many
+//! samples were clipped or a channel sits off centre. Both usually come
+//! from the server's transcoding settings, e.g. a resampler or a gain that
+//! overshoots, rather than from the track or the player.
+
+use log::{debug, warn};
+use symphonia::core::audio::{AudioBuffer, Signal};
+
+use crate::track_info::TrackInfo;
+
+// Within half a step of the largest 16 bit sample, so that 16 bit sources
+// reach full scale whatever rounding their conversion to float has
+const CLIP_LEVEL: f32 = 32766.5 / 32768.0;
+// More than one sample in a thousand at full scale is more than mastering
+// leaves
+const CLIPPED_MAX: f64 = 0.001;
+// A mean of -40 dBFS
+const OFFSET_MAX: f64 = 0.01;
+
+/// The levels of one track, checked when it is dropped.
+pub(crate) struct Levels {
+    track: String,
+    peak: f32,
+    clipped: u64,
+    frames: u64,
+    // The sum of each channel's samples
+    sums: Vec<f64>,
+}
+
+impl Levels {
+    pub(crate) fn new(info: Option<&TrackInfo>) -> Self {
+        Self {
+            track: info.map_or_else(|| "untagged track".to_owned(), TrackInfo::summary),
+            peak: 0.0,
+            clipped: 0,
+            frames: 0,
+            sums: Vec::new(),
+        }
+    }
+
+    /// Add a buffer as it was decoded.
+    pub(crate) fn add(&mut self, buffer: &AudioBuffer<f32>) {
+        let channels = buffer.spec().channels.count();
+        self.sums.resize(channels, 0.0);
+        for (chan, sum) in self.sums.iter_mut().enumerate() {
+            for sample in buffer.chan(chan) {
+                let level = sample.abs();
+                self.peak = self.peak.max(level);
+                if level >= CLIP_LEVEL {
+                    self.clipped += 1;
+                }
+                *sum += *sample as f64;
+            }
+        }
+        self.frames += buffer.frames() as u64;
+    }
+
+    // What is wrong with the track, if anything
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let samples = self.frames * self.sums.len() as u64;
+        let clipped = self.clipped as f64 / samples as f64;
+        if clipped > CLIPPED_MAX {
+            problems.push(format!(
+                "{} samples ({:.2}%) clipped",
+                self.clipped,
+                clipped * 100.0
+            ));
+        }
+        for (chan, sum) in self.sums.iter().enumerate() {
+            let offset = sum / self.frames as f64;
+            if offset.abs() > OFFSET_MAX {
+                problems.push(format!(
+                    "a DC offset of {:.0} dBFS on channel {}",
+                    20.0 * offset.abs().log10(),
+                    chan + 1
+                ));
+            }
+        }
+        problems
+    }
+}
+
+impl Drop for Levels {
+    fn drop(&mut self) {
+        if self.frames == 0 || self.sums.is_empty() {
+            return;
+        }
+
+        debug!(
+            "Levels of {}: peak {:.1} dBFS, {} samples clipped",
+            self.track,
+            20.0 * self.peak.max(f32::MIN_POSITIVE).log10(),
+            self.clipped
+        );
+        let problems = self.problems();
+        if !problems.is_empty() {
+            warn!(
+                "Decoded audio of {} has {}, check the server's transcoding settings for this format",
+                self.track,
+                problems.join(" and ")
+            );
+        }
+    }
+}
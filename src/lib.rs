@@ -0,0 +1,104 @@
+//! The player machinery behind the `vibe` command, for embedding in
+//! other programs.
+//!
+//! A player is made from three parts that talk over channels:
+//! - [`proto::run`] connects to a Lyrion Music Server and passes server
+//!   messages in one direction and client messages in the other,
+//! - [`message::process_slim_msg`] acts on each server message, starting a
+//!   [`decode::make_decoder`] thread for each new stream,
+//! - [`message::process_stream_msg`] acts on [`message::PlayerMsg`] events
+//!   from the decoder and the [`audio_out::AudioOutput`].
+//!
+//! See `src/main.rs` or `examples/embedded.rs` for how they fit together.
+//!
+//! Each part is given its settings and shared state when it is made, e.g.
+//! [`proto::Settings`], [`decode::StreamOptions`] and
+//! [`audio_out::OutputOptions`], so that more than one player can run in
+//! a process. Only what the process has one of is global: the helper
+//! threads and shutdown, the state directory, the DSP hook, the TLS and
+//! HTTP clients, and the notification and scrobbling workers.
+
+#[cfg(not(any(feature = "pulse", feature = "rodio")))]
+compile_error!("Vibe needs an audio output, enable the pulse or rodio feature");
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crossbeam::atomic::AtomicCell;
+
+pub mod audio_out;
+pub mod checksum;
+pub mod decode;
+pub mod discovery;
+pub mod downmix;
+#[cfg(feature = "dsp")]
+pub mod dsp;
+#[cfg(feature = "scrobble")]
+mod http;
+pub mod latency;
+pub mod levels;
+pub mod loudness;
+pub mod message;
+pub mod monitor;
+#[cfg(feature = "notify")]
+mod notify;
+#[cfg(test)]
+mod null_out;
+pub mod output_format;
+pub mod paths;
+pub mod player_mac;
+pub mod player_name;
+pub mod proto;
+#[cfg(feature = "pulse")]
+mod pulse_loop;
+#[cfg(feature = "pulse")]
+mod pulse_out;
+mod resample;
+#[cfg(feature = "rodio")]
+mod rodio_out;
+#[cfg(feature = "scrobble")]
+pub mod scrobble;
+pub mod stats;
+pub mod suspend;
+pub mod sync;
+pub mod threads;
+#[cfg(feature = "tls")]
+mod tls;
+pub mod track_info;
+pub mod transition;
+pub mod volume;
+
+use audio_out::WriteMarker;
+use stats::Stats;
+use volume::Volume;
+
+/// Per-stream settings handed to the audio output along with the decoder.
+pub struct StreamParams {
+    autostart: slimproto::proto::AutoStart,
+    // Always holds the latest gain from the server, even when nothing is
+    // playing. Outputs read it as each buffer is decoded and never keep a
+    // copy, so a new stream starts at the current volume.
+    volume: Arc<Mutex<Volume>>,
+    skip: Arc<AtomicCell<Duration>>,
+    output_threshold: Duration,
+    stats: Arc<Stats>,
+    written: WriteMarker,
+}
+
+impl StreamParams {
+    /// Settings for audio generated or read locally rather than streamed
+    /// from the server, which starts at once and is not counted in the
+    /// session statistics.
+    pub fn local(volume: Arc<Mutex<Volume>>, written: WriteMarker) -> Self {
+        Self {
+            autostart: slimproto::proto::AutoStart::Auto,
+            volume,
+            skip: Arc::new(AtomicCell::new(Duration::ZERO)),
+            output_threshold: Duration::from_millis(100),
+            stats: Arc::new(Stats::new()),
+            written,
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Loudness compensation, which lifts the bass and, less so, the treble as
+//! the volume is turned down, as the ear loses them first at low levels.
+//!
+//! The lift follows the attenuation set by the server volume, loosely after
+//! the ISO 226 equal loudness contours: at full strength the bass shelf is
+//! raised by half the attenuation and the treble shelf by a quarter of it.
+//! The lift never exceeds the attenuation, so nothing is louder than it
+//! would be at full volume, and at full volume the audio is left untouched.
+
+use std::f32::consts::PI;
+
+use symphonia::core::audio::{AudioBuffer, Signal};
+
+const BASS_HZ: f32 = 100.0;
+const TREBLE_HZ: f32 = 10_000.0;
+const MAX_BASS_DB: f32 = 15.0;
+const MAX_TREBLE_DB: f32 = 6.0;
+// The lift moves by no more than this per buffer, so that a volume change
+// does not zipper
+const MAX_STEP_DB: f32 = 1.0;
+
+// A second order shelving filter, from the Audio EQ Cookbook
+#[derive(Clone, Copy, Default)]
+struct Biquad {
+    b: [f32; 3],
+    a: [f32; 2],
+}
+
+impl Biquad {
+    fn shelf(low: bool, freq: f32, gain_db: f32, sample_rate: u32) -> Self {
+        let a = 10f32.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * freq.min(sample_rate as f32 * 0.45) / sample_rate as f32;
+        let (sin, cos) = w0.sin_cos();
+        // A shelf slope of 1
+        let alpha = sin / 2.0 * 2f32.sqrt();
+        let root = 2.0 * a.sqrt() * alpha;
+
+        let sign = if low { 1.0 } else { -1.0 };
+        let b0 = a * ((a + 1.0) - sign * (a - 1.0) * cos + root);
+        let b1 = sign * 2.0 * a * ((a - 1.0) - sign * (a + 1.0) * cos);
+        let b2 = a * ((a + 1.0) - sign * (a - 1.0) * cos - root);
+        let a0 = (a + 1.0) + sign * (a - 1.0) * cos + root;
+        let a1 = -sign * 2.0 * ((a - 1.0) + sign * (a + 1.0) * cos);
+        let a2 = (a + 1.0) + sign * (a - 1.0) * cos - root;
+
+        Self {
+            b: [b0 / a0, b1 / a0, b2 / a0],
+            a: [a1 / a0, a2 / a0],
+        }
+    }
+}
+
+// The last two inputs and outputs of one filter on one channel
+#[derive(Clone, Copy, Default)]
+struct History {
+    x: [f32; 2],
+    y: [f32; 2],
+}
+
+impl History {
+    fn run(&mut self, filter: &Biquad, x: f32) -> f32 {
+        let y = filter.b[0] * x + filter.b[1] * self.x[0] + filter.b[2] * self.x[1]
+            - filter.a[0] * self.y[0]
+            - filter.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// The compensation for one stream, which keeps the filter state of each
+/// channel between buffers.
+pub struct Loudness {
+    sample_rate: u32,
+    strength: f32,
+    bass_db: f32,
+    filters: [Biquad; 2],
+    history: Vec<[History; 2]>,
+}
+
+impl Loudness {
+    /// Compensation for a stream with this strength, where 1.0 is the
+    /// usual amount and zero turns compensation off.
+    pub fn new(sample_rate: u32, strength: f32) -> Option<Self> {
+        (strength > 0.0).then(|| Self {
+            sample_rate,
+            strength,
+            bass_db: 0.0,
+            filters: Default::default(),
+            history: Vec::new(),
+        })
+    }
+
+    // The bass lift in dB for a volume gain
+    fn bass_lift_db(&self, gain: f32) -> f32 {
+        let attenuation = if gain > 0.0 {
+            (-20.0 * gain.log10()).max(0.0)
+        } else {
+            f32::INFINITY
+        };
+        (attenuation * 0.5 * self.strength)
+            .min(attenuation)
+            .min(MAX_BASS_DB)
+    }
+
+    /// Whether the compensation currently leaves the audio as it is.
+    #[cfg(feature = "pulse")]
+    pub(crate) fn is_flat(&self) -> bool {
+        self.bass_db <= 0.0
+    }
+
+    /// Apply the compensation for the volume gain `gain` to a buffer the
+    /// volume has already been applied to.
+    pub fn process(&mut self, buffer: &mut AudioBuffer<f32>, gain: f32) {
+        let target = self.bass_lift_db(gain);
+        let bass_db = self.bass_db + (target - self.bass_db).clamp(-MAX_STEP_DB, MAX_STEP_DB);
+        if bass_db <= 0.0 {
+            self.bass_db = 0.0;
+            self.history.clear();
+            return;
+        }
+
+        if bass_db != self.bass_db || self.history.is_empty() {
+            let treble_db = (bass_db / 2.0).min(MAX_TREBLE_DB);
+            self.filters = [
+                Biquad::shelf(true, BASS_HZ, bass_db, self.sample_rate),
+                Biquad::shelf(false, TREBLE_HZ, treble_db, self.sample_rate),
+            ];
+            self.bass_db = bass_db;
+        }
+
+        let channels = buffer.spec().channels.count();
+        self.history.resize(channels, Default::default());
+        for (chan, history) in self.history.iter_mut().enumerate() {
+            for sample in buffer.chan_mut(chan) {
+                let bass = history[0].run(&self.filters[0], *sample);
+                *sample = history[1].run(&self.filters[1], bass);
+            }
+        }
+    }
+}
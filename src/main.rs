@@ -1,7 +1,10 @@
 use std::{
-    net::{Ipv4Addr, SocketAddrV4},
+    fs::OpenOptions,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpStream, ToSocketAddrs},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
     str::FromStr,
-    sync::{Arc, Mutex, RwLock},
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 
@@ -11,28 +14,53 @@ use clap::{
 };
 use crossbeam::{
     atomic::AtomicCell,
-    channel::{bounded, Select},
+    channel::{bounded, unbounded, Receiver, Select, Sender},
 };
 
-use audio_out::AudioOutput;
-use log::info;
-use message::{process_slim_msg, process_stream_msg};
+use anyhow::{bail, Context};
+use log::{info, warn};
+use mac_address::MacAddress;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM, SIGUSR2},
+    iterator::Signals,
+};
 use simple_logger::SimpleLogger;
 use slimproto::{
     proto::{ClientMessage, SLIM_PORT},
-    status::StatusData,
+    status::{StatusCode, StatusData},
+};
+use symphonia::core::codecs::{
+    CODEC_TYPE_AAC, CODEC_TYPE_ALAC, CODEC_TYPE_FLAC, CODEC_TYPE_MP3, CODEC_TYPE_PCM_S16LE,
+    CODEC_TYPE_VORBIS,
+};
+#[cfg(feature = "dsp")]
+use vibe::dsp;
+#[cfg(feature = "scrobble")]
+use vibe::scrobble;
+use vibe::{
+    audio_out::{
+        make_audio_output, play_file, AudioOutput, DeviceRule, Interrupts, OutputOptions,
+        AUDIO_SYSTEMS, DEFAULT_MAX_DECODE_AHEAD, DEFAULT_SYSTEM,
+    },
+    decode::{self, DecodeOptions, StreamOptions},
+    discovery::{self, discover},
+    latency,
+    message::{coalesce_events, coalesce_gain, process_stream_msg, Clock, PlayerMsg, SlimHandler},
+    monitor,
+    output_format::OutputFormat,
+    paths, player_mac,
+    player_name::PlayerName,
+    proto::{
+        self, ConnectionHistory, ConnectionInfo, DEFAULT_HTTP_PORT, DEFAULT_KEEPALIVE,
+        DEFAULT_MAX_SAMPLE_RATE, DEFAULT_MODELNAME,
+    },
+    stats::Stats,
+    suspend,
+    sync::DriftTracker,
+    threads,
+    transition::{Transition, TransitionKind},
+    volume::{fade_out, Volume},
 };
-
-mod audio_out;
-mod decode;
-mod message;
-#[cfg(feature = "notify")]
-mod notify;
-mod proto;
-#[cfg(feature = "pulse")]
-mod pulse_out;
-#[cfg(feature = "rodio")]
-mod rodio_out;
 
 #[derive(Parser)]
 #[command(name = "Vibe", author, version, about, long_about = None)]
@@ -41,8 +69,30 @@ struct Cli {
         short,
         name = "SERVER[:PORT]",
         value_parser = cli_server_parser,
-        help = "Connect to the specified server, otherwise use autodiscovery")]
-    server: Option<SocketAddrV4>,
+        value_delimiter = ',',
+        help = "Connect to the specified server, or the server at a web URL, otherwise use autodiscovery. \
+            Servers given more than once, or separated by commas, are tried in turn")]
+    server: Vec<ServerArg>,
+
+    #[arg(
+        long,
+        value_name = "ADDR[:PORT]",
+        value_parser = cli_address_parser,
+        conflicts_with = "SERVER[:PORT]",
+        alias = "discover-addr",
+        help = "Send discovery here rather than broadcasting, may be given more than once"
+    )]
+    discover_address: Vec<SocketAddrV4>,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = discovery::DEFAULT_TIMEOUT.as_secs(),
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "SERVER[:PORT]",
+        help = "Wait this long for a server on each attempt at discovery"
+    )]
+    discover_timeout: u64,
 
     #[arg(
         short = 'o',
@@ -51,14 +101,266 @@ struct Cli {
     )]
     device: Option<String>,
 
+    #[arg(
+        long,
+        value_name = "NAME",
+        help = "Switch the output device to this port, e.g. HDMI rather than analog (pulse only)"
+    )]
+    device_port: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "RULES",
+        value_delimiter = ',',
+        value_parser = cli_device_rule_parser,
+        help = "Play on a device chosen by sample rate, e.g. \"<=96000:usb-dac,>96000:hdmi\", the first matching rule wins"
+    )]
+    device_map: Vec<DeviceRule>,
+
     #[arg(short, help = "List output devices")]
     list: bool,
 
-    #[arg(short, default_value = "Vibe", help = "Set the player name")]
-    name: String,
+    #[arg(
+        long,
+        help = "Check the server, audio system and output device then exit"
+    )]
+    dry_run: bool,
+
+    #[arg(
+        long,
+        help = "Print how Vibe was built and what it finds on this system then exit"
+    )]
+    diagnose: bool,
+
+    #[arg(
+        long,
+        help = "Play a click track to measure the output latency then exit"
+    )]
+    measure_latency: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["list", "dry_run", "measure_latency"],
+        help = "Connect and report on the server without opening an audio output"
+    )]
+    monitor: bool,
+
+    #[arg(
+        short,
+        help = "Set the player name [default: the name last set on the server, else Vibe@HOSTNAME]"
+    )]
+    name: Option<String>,
+
+    #[arg(
+        long,
+        default_value = DEFAULT_MODELNAME,
+        value_parser = cli_helo_value_parser,
+        help = "Set the model name presented to the server"
+    )]
+    modelname: String,
+
+    #[arg(
+        long,
+        value_name = "VERSION",
+        default_value = env!("CARGO_PKG_VERSION"),
+        value_parser = cli_helo_value_parser,
+        help = "Set the firmware version presented to the server"
+    )]
+    firmware: String,
+
+    #[arg(
+        long,
+        value_name = "AA:BB:CC:DD:EE:FF",
+        value_parser = cli_mac_parser,
+        help = "Set the MAC address the server knows the player by [default: one made from the host and player names, kept in the state directory]"
+    )]
+    mac: Option<MacAddress>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Play this short audio file once connected to the server"
+    )]
+    chime: Option<PathBuf>,
+
+    #[arg(
+        long,
+        requires = "chime",
+        help = "Also play the chime after reconnecting"
+    )]
+    chime_on_reconnect: bool,
+
+    #[cfg(feature = "dsp")]
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with = "dsp_exec",
+        help = "Process decoded audio with this plugin library"
+    )]
+    dsp_plugin: Option<PathBuf>,
+
+    #[cfg(feature = "dsp")]
+    #[arg(
+        long,
+        value_name = "CMD",
+        help = "Process decoded audio by piping it through this command"
+    )]
+    dsp_exec: Option<String>,
+
+    #[cfg(feature = "scrobble")]
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        help = "Submit played tracks to ListenBrainz with this user token"
+    )]
+    listenbrainz_token: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Append checksums of each track's audio, before and after processing, to this file"
+    )]
+    checksum_log: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Warn of tracks that arrive clipped or offset, e.g. from a bad transcode"
+    )]
+    check_levels: bool,
+
+    #[arg(
+        long,
+        value_name = "STRENGTH",
+        num_args = 0..=1,
+        default_missing_value = "1",
+        value_parser = cli_loudness_parser,
+        help = "Lift bass and treble as the volume is turned down, 1 is the usual strength"
+    )]
+    loudness: Option<f32>,
+
+    #[arg(long,
+        default_value = "stop",
+        value_parser = PossibleValuesParser::new(["stop", "drain", "fade"]),
+        help = "What to do with buffered audio when the server is lost")]
+    on_disconnect: String,
+
+    #[arg(long,
+        value_name = "TYPE",
+        value_parser = PossibleValuesParser::new(["none", "crossfade", "fadein", "fadeout", "fadeinout"])
+            .map(|s| TransitionKind::parse(&s).unwrap()),
+        help = "Transition between tracks when the server sets none")]
+    transition: Option<TransitionKind>,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 5,
+        requires = "transition",
+        help = "How long the transition lasts"
+    )]
+    transition_period: u64,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = DEFAULT_MAX_DECODE_AHEAD.as_secs(),
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Decode no more than this much audio ahead of playback"
+    )]
+    max_decode_ahead_secs: u64,
+
+    #[arg(
+        long,
+        value_name = "KB",
+        help = "Receive buffer size for audio data streams [default: system default]"
+    )]
+    stream_rcvbuf_kb: Option<usize>,
+
+    #[arg(long, help = "Mix streams with more than two channels down to stereo")]
+    downmix_stereo: bool,
+
+    #[arg(
+        long,
+        value_name = "RATE:BITS:CHANNELS",
+        value_parser = cli_output_format_parser,
+        help = "Convert every stream to this format, e.g. 48000:24:2"
+    )]
+    force_output_format: Option<OutputFormat>,
+
+    #[arg(
+        long,
+        value_name = "BOOL",
+        default_value_t = true,
+        action = clap::ArgAction::Set,
+        help = "Refill the output buffer before resuming after a long pause"
+    )]
+    refill_on_resume: bool,
+
+    #[arg(long,
+        value_name = "HZ",
+        value_parser = PossibleValuesParser::new(["44100", "48000", "88200", "96000", "176400", "192000", "384000"])
+            .map(|s| s.parse::<u32>().unwrap()),
+        help = "Highest sample rate to ask the server for, higher rates are resampled by the server [default: 192000]")]
+    max_sample_rate: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = DEFAULT_KEEPALIVE.as_secs(),
+        help = "Take the server as lost after this long without a message from it, and probe an idle connection after this long with TCP keepalive. 0 turns off the wait and leaves TCP keepalive to the system, which may take hours to notice"
+    )]
+    keepalive_secs: u64,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        help = "Stop reading the data stream once paused for this long [default: never]"
+    )]
+    pause_stops_streaming: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Fetch streams from the server connected to, whatever address it gives"
+    )]
+    stream_host_override: bool,
+
+    #[arg(
+        long,
+        value_name = "USER:PASSWORD",
+        value_parser = cli_auth_parser,
+        help = "Log in to fetch streams from a password protected server, sent only to the server itself"
+    )]
+    auth: Option<String>,
+
+    #[arg(
+        long,
+        help = "Correct drift between the audio and system clocks, for sync groups"
+    )]
+    drift_correction: bool,
+
+    #[arg(
+        long,
+        value_name = "MINS",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Release the audio output once nothing has played for this long"
+    )]
+    idle_timeout_mins: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Exit once the playlist has played out and nothing more is sent"
+    )]
+    exit_when_done: bool,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Keep persisted files here [default: XDG state and cache directories]"
+    )]
+    state_dir: Option<PathBuf>,
 
     #[cfg(all(feature = "pulse", feature = "rodio"))]
-    #[arg(long, short = 'a', default_value = "pulse", value_parser = PossibleValuesParser::new([
+    #[arg(long, short = 'a', default_value = DEFAULT_SYSTEM, value_parser = PossibleValuesParser::new([
         "pulse", "rodio" ]),
         help = "Which audio system to use"
     )]
@@ -76,22 +378,520 @@ struct Cli {
     loglevel: log::LevelFilter,
 }
 
-fn cli_server_parser(value: &str) -> anyhow::Result<SocketAddrV4> {
+// How long audio left playing after losing the server is kept going when
+// fading, and how long the fade at its end lasts
+const DISCONNECT_GRACE: Duration = Duration::from_secs(10);
+const DISCONNECT_FADE: Duration = Duration::from_secs(1);
+
+// How often to try for a new audio output once the old one is lost
+const OUTPUT_RETRY: Duration = Duration::from_secs(5);
+
+// How long to wait on exit for the writer to the server and the helpers
+const SHUTDOWN_WAIT: Duration = Duration::from_secs(2);
+// How long playing audio takes to fade out on shutdown
+const SHUTDOWN_FADE: Duration = Duration::from_millis(200);
+
+// How often to look for the output being idle when nothing else happens,
+// and how long it must be idle for the playlist to count as done
+const IDLE_CHECK: Duration = Duration::from_secs(5);
+const DONE_AFTER: Duration = Duration::from_secs(10);
+
+// Tracks how long the output has had nothing to play, going by whether the
+// play position moves. A paused output is not idle.
+struct IdleWatch {
+    pos: Duration,
+    since: Instant,
+    played: bool,
+    released: bool,
+}
+
+impl IdleWatch {
+    fn new() -> Self {
+        Self {
+            pos: Duration::ZERO,
+            since: Instant::now(),
+            played: false,
+            released: false,
+        }
+    }
+
+    fn idle_for(&mut self, output: &AudioOutput) -> Duration {
+        let pos = output.get_dur();
+        if pos != self.pos || output.is_paused() {
+            self.played |= !pos.is_zero();
+            self.released = false;
+            self.pos = pos;
+            self.since = Instant::now();
+        }
+        self.since.elapsed()
+    }
+
+    // Whether something has played and then come to an end
+    fn done(&mut self, output: &AudioOutput) -> bool {
+        self.idle_for(output) >= DONE_AFTER && self.played
+    }
+
+    // True once for each time the output has been idle for `timeout`
+    fn release_due(&mut self, output: &AudioOutput, timeout: Duration) -> bool {
+        if self.idle_for(output) >= timeout && !self.released {
+            self.released = true;
+            return true;
+        }
+        false
+    }
+}
+
+/// The server given on the command line, and the port of its web interface
+/// if it was given as a URL.
+#[derive(Clone, Copy, Debug)]
+struct ServerArg {
+    addr: SocketAddrV4,
+    http_port: Option<u16>,
+}
+
+// Either a URL as copied from the browser, whose host is used with the
+// slimproto port, or an address as taken by `cli_address_parser`
+fn cli_server_parser(value: &str) -> anyhow::Result<ServerArg> {
+    let url = ["http://", "https://"].iter().find_map(|scheme| {
+        value
+            .get(..scheme.len())
+            .filter(|start| start.eq_ignore_ascii_case(scheme))
+            .map(|_| &value[scheme.len()..])
+    });
+    let Some(url) = url else {
+        if value.contains("://") {
+            bail!("only http and https URLs are accepted");
+        }
+        return Ok(ServerArg {
+            addr: cli_address_parser(value)?,
+            http_port: None,
+        });
+    };
+
+    let host_port = url.split('/').next().unwrap_or_default();
+    if host_port.starts_with('[') {
+        bail!("IPv6 addresses are not supported, give an IPv4 address or host name");
+    }
+    let (host, http_port) = match host_port.split_once(':') {
+        Some((host, "")) => (host, DEFAULT_HTTP_PORT),
+        Some((host, port)) => (host, port.parse().context("invalid port")?),
+        None => (host_port, DEFAULT_HTTP_PORT),
+    };
+    Ok(ServerArg {
+        addr: resolve(host, SLIM_PORT)?,
+        http_port: Some(http_port),
+    })
+}
+
+fn cli_address_parser(value: &str) -> anyhow::Result<SocketAddrV4> {
+    // The slimproto connection is IPv4 only
+    if value.starts_with('[') || value.matches(':').count() > 1 {
+        bail!("IPv6 addresses are not supported, give an IPv4 address or host name");
+    }
+    let (host, port) = match value.split_once(':') {
+        Some((host, "")) => (host, SLIM_PORT),
+        Some((host, port)) => (host, port.parse().context("invalid port")?),
+        None => (value, SLIM_PORT),
+    };
+    resolve(host, port)
+}
+
+// An IPv4 address, or else the first IPv4 address of a host name
+fn resolve(host: &str, port: u16) -> anyhow::Result<SocketAddrV4> {
+    if let Ok(ip) = Ipv4Addr::from_str(host) {
+        return Ok(SocketAddrV4::new(ip, port));
+    }
+    (host, port)
+        .to_socket_addrs()
+        .with_context(|| format!("unable to resolve {host:?}"))?
+        .find_map(|addr| match addr {
+            SocketAddr::V4(addr) => Some(addr),
+            SocketAddr::V6(_) => None,
+        })
+        .with_context(|| format!("{host} has no IPv4 address"))
+}
+
+// Values sent in HELO, such as the model name and firmware, are part of a
+// comma separated list of key=value pairs so must not contain either
+// separator
+fn cli_helo_value_parser(value: &str) -> anyhow::Result<String> {
+    if value.is_empty() || value.len() > 32 {
+        bail!("must be between 1 and 32 characters");
+    }
+    if !value
+        .chars()
+        .all(|c| c.is_ascii_graphic() && c != ',' && c != '=')
+    {
+        bail!("must be printable ASCII without spaces, commas or equals signs");
+    }
+    Ok(value.to_owned())
+}
+
+// Six pairs of hex digits separated by colons, for a unicast address as
+// the server treats any other as no player at all
+fn cli_mac_parser(value: &str) -> anyhow::Result<MacAddress> {
+    let pairs: Vec<_> = value.split(':').collect();
+    if pairs.len() != 6
+        || !pairs
+            .iter()
+            .all(|pair| pair.len() == 2 && pair.chars().all(|c| c.is_ascii_hexdigit()))
+    {
+        bail!("must be six pairs of hex digits separated by colons, e.g. 02:00:00:00:00:01");
+    }
+    let mac: MacAddress = value.parse()?;
+    if !player_mac::is_usable(mac) {
+        bail!("must be a unicast address and not all zeros");
+    }
+    Ok(mac)
+}
+
+// The user name is everything before the first colon, as in HTTP basic
+// authentication
+fn cli_auth_parser(value: &str) -> anyhow::Result<String> {
     match value.split_once(':') {
-        Some((ip_str, port_str)) if port_str.len() == 0 => {
-            Ok(SocketAddrV4::new(Ipv4Addr::from_str(ip_str)?, SLIM_PORT))
+        Some((user, _)) if !user.is_empty() => Ok(value.to_owned()),
+        _ => bail!("must be a user name and password separated by a colon"),
+    }
+}
+
+// A rule is a rate or range of rates, then a colon and the device name,
+// which may itself contain colons
+fn cli_device_rule_parser(value: &str) -> anyhow::Result<DeviceRule> {
+    let Some((rates, device)) = value.split_once(':').filter(|(_, d)| !d.is_empty()) else {
+        bail!("must be a sample rate or range, a colon and a device name");
+    };
+    let rate = |n: &str| {
+        n.trim()
+            .parse::<u32>()
+            .map_err(|_| anyhow::anyhow!("{n} is not a sample rate"))
+    };
+    let none = || anyhow::anyhow!("{value} matches no sample rate");
+    let rates = if let Some(n) = rates.strip_prefix("<=") {
+        0..=rate(n)?
+    } else if let Some(n) = rates.strip_prefix('<') {
+        0..=rate(n)?.checked_sub(1).ok_or_else(none)?
+    } else if let Some(n) = rates.strip_prefix(">=") {
+        rate(n)?..=u32::MAX
+    } else if let Some(n) = rates.strip_prefix('>') {
+        rate(n)?.checked_add(1).ok_or_else(none)?..=u32::MAX
+    } else if let Some((low, high)) = rates.split_once('-') {
+        rate(low)?..=rate(high)?
+    } else {
+        let n = rate(rates.strip_prefix('=').unwrap_or(rates))?;
+        n..=n
+    };
+    if rates.is_empty() {
+        return Err(none());
+    }
+    Ok(DeviceRule::new(rates, device.to_owned()))
+}
+
+fn cli_output_format_parser(value: &str) -> anyhow::Result<OutputFormat> {
+    let [rate, bits, channels] = value.split(':').collect::<Vec<_>>()[..] else {
+        bail!("must be a sample rate, bits per sample and channels separated by colons");
+    };
+    let rate = match rate.parse::<u32>() {
+        Ok(rate) if (8_000..=384_000).contains(&rate) => rate,
+        _ => bail!("{rate} is not a sample rate from 8000 to 384000"),
+    };
+    let bits = match bits.parse::<u8>() {
+        Ok(bits) if OutputFormat::BITS.contains(&bits) => bits,
+        _ => bail!("{bits} is not 16, 24 or 32 bits per sample"),
+    };
+    let channels = match channels.parse::<u8>() {
+        Ok(channels) if OutputFormat::CHANNELS.contains(&channels) => channels,
+        _ => bail!("{channels} is not 1 or 2 channels"),
+    };
+    Ok(OutputFormat {
+        rate,
+        bits,
+        channels,
+    })
+}
+
+fn cli_loudness_parser(value: &str) -> anyhow::Result<f32> {
+    match value.parse::<f32>() {
+        Ok(strength) if (0.0..=2.0).contains(&strength) => Ok(strength),
+        _ => bail!("must be a number from 0 to 2"),
+    }
+}
+
+impl Cli {
+    fn server_addrs(&self) -> Vec<SocketAddrV4> {
+        self.server.iter().map(|server| server.addr).collect()
+    }
+
+    fn output_options(&self) -> OutputOptions {
+        OutputOptions {
+            device: self.device.clone(),
+            device_port: self.device_port.clone(),
+            device_map: self.device_map.clone(),
+            max_decode_ahead: Duration::from_secs(self.max_decode_ahead_secs),
+            refill_on_resume: self.refill_on_resume,
+        }
+    }
+
+    fn proto_settings(&self) -> proto::Settings {
+        proto::Settings {
+            servers: self.server_addrs(),
+            modelname: self.modelname.clone(),
+            firmware: self.firmware.clone(),
+            mac: Some(
+                self.mac
+                    .unwrap_or_else(|| player_mac::kept_or_made(self.name.as_deref())),
+            ),
+            max_sample_rate: self.max_sample_rate.unwrap_or(DEFAULT_MAX_SAMPLE_RATE),
+            keepalive: Duration::from_secs(self.keepalive_secs),
+            discover_addresses: self.discover_address.clone(),
+            discover_timeout: Duration::from_secs(self.discover_timeout),
+        }
+    }
+
+    fn stream_options(&self) -> StreamOptions {
+        StreamOptions {
+            host_override: self.stream_host_override,
+            rcvbuf: self.stream_rcvbuf_kb.unwrap_or_default() * 1024,
+            pause_stops_streaming: self.pause_stops_streaming.map(Duration::from_secs),
+            authorization: self.auth.as_deref().map(decode::basic_authorization),
+            web_port: self
+                .server
+                .iter()
+                .find_map(|server| server.http_port)
+                .unwrap_or(DEFAULT_HTTP_PORT),
+            transition: self
+                .transition
+                .map_or_else(Transition::default, |kind| Transition {
+                    kind,
+                    period: Duration::from_secs(self.transition_period),
+                }),
+            decode: self.decode_options(),
+        }
+    }
+
+    fn decode_options(&self) -> DecodeOptions {
+        DecodeOptions {
+            downmix_stereo: self.downmix_stereo,
+            output_format: self.force_output_format,
+            loudness: self.loudness.unwrap_or_default(),
+            checksum_log: self.checksum_log.clone(),
+            check_levels: self.check_levels,
         }
-        Some(_) => Ok(value.parse()?),
-        None => Ok(SocketAddrV4::new(Ipv4Addr::from_str(value)?, SLIM_PORT)),
     }
 }
 
-pub struct StreamParams {
-    autostart: slimproto::proto::AutoStart,
-    volume: Arc<Mutex<Vec<f32>>>,
-    #[cfg(feature = "pulse")]
-    skip: Arc<AtomicCell<Duration>>,
-    output_threshold: Duration,
+// Starts out knowing the server's web port, if it was given. Only one web
+// port is kept, that of the first server given as a URL.
+fn connection_info(cli: &Cli) -> Arc<Mutex<ConnectionInfo>> {
+    let mut info = ConnectionInfo::new();
+    if let Some(port) = cli.server.iter().find_map(|server| server.http_port) {
+        info.set_http_port(port);
+    }
+    Arc::new(Mutex::new(info))
+}
+
+fn output_system(#[allow(unused)] cli: &Cli) -> &str {
+    #[cfg(all(feature = "pulse", feature = "rodio"))]
+    let output_system = cli.system.as_str();
+    #[cfg(not(all(feature = "pulse", feature = "rodio")))]
+    let output_system = DEFAULT_SYSTEM;
+    output_system
+}
+
+// Check that everything needed to play is in place without registering
+// a player with the server. The first failure is returned as the error.
+fn dry_run(cli: &Cli) -> anyhow::Result<()> {
+    let mut servers = cli.server_addrs();
+    if servers.is_empty() {
+        match discover(
+            &cli.discover_address,
+            Some(Duration::from_secs(cli.discover_timeout)),
+        ) {
+            Ok(Some(server)) => servers.push(server.socket),
+            Ok(None) => bail!("Server discovery: no server found"),
+            Err(e) => bail!("Server discovery: {e}"),
+        }
+    }
+
+    // Connect without sending HELO so no player appears on the server. Any
+    // of the servers given will do, as the player falls back to the others.
+    let mut reached = None;
+    let mut first_error = None;
+    for server in servers {
+        match TcpStream::connect_timeout(&server.into(), Duration::from_secs(5)) {
+            Ok(_) => {
+                reached = Some(server);
+                break;
+            }
+            Err(e) => {
+                first_error.get_or_insert(
+                    anyhow::Error::new(e)
+                        .context(format!("Unable to connect to server at {server}")),
+                );
+            }
+        }
+    }
+    let Some(server) = reached else {
+        return Err(first_error.expect("at least one server is tried"));
+    };
+
+    let system = output_system(cli);
+    make_audio_output(system, &cli.output_options(), Interrupts::new())
+        .with_context(|| format!("Unable to initialise {system} audio output"))?;
+
+    println!("Server: {server}");
+    println!("Audio system: {system}");
+    println!(
+        "Output device: {}",
+        cli.device.as_deref().unwrap_or("System default device")
+    );
+    let settings = cli.proto_settings();
+    if let Some(mac) = settings.mac {
+        println!("MAC address: {mac}");
+    }
+    println!(
+        "Capabilities: {}",
+        proto::capabilities("", &settings).join(", ")
+    );
+
+    Ok(())
+}
+
+// Print what a bug report needs to know about the build and the system.
+// Unlike the dry run, every check is made whatever fails before it.
+fn diagnose(cli: &Cli) {
+    println!("Vibe {}", env!("CARGO_PKG_VERSION"));
+
+    let features = [
+        ("pulse", cfg!(feature = "pulse")),
+        ("rodio", cfg!(feature = "rodio")),
+        ("notify", cfg!(feature = "notify")),
+        ("dsp", cfg!(feature = "dsp")),
+        ("scrobble", cfg!(feature = "scrobble")),
+        ("tls", cfg!(feature = "tls")),
+    ];
+    println!(
+        "Features: {}",
+        features
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let codecs = symphonia::default::get_codecs();
+    println!(
+        "Codecs: {}",
+        [
+            CODEC_TYPE_FLAC,
+            CODEC_TYPE_MP3,
+            CODEC_TYPE_AAC,
+            CODEC_TYPE_ALAC,
+            CODEC_TYPE_VORBIS,
+            CODEC_TYPE_PCM_S16LE,
+        ]
+        .iter()
+        .filter_map(|codec| codecs.get_codec(*codec))
+        .map(|descriptor| descriptor.short_name)
+        .collect::<Vec<_>>()
+        .join(", ")
+    );
+
+    for system in AUDIO_SYSTEMS {
+        match make_audio_output(system, &cli.output_options(), Interrupts::new()) {
+            Ok(output) => {
+                println!("Audio system {system}: available");
+                for (name, value) in output.get_system_info() {
+                    println!("   {name}: {value}");
+                }
+                match output.get_output_device_names() {
+                    Ok(names) => println!("   Devices: {}", names.len()),
+                    Err(e) => println!("   Devices: unable to list ({e})"),
+                }
+            }
+            Err(e) => println!("Audio system {system}: unavailable ({e:#})"),
+        }
+    }
+
+    match cli.stream_rcvbuf_kb {
+        Some(kb) => println!("Stream receive buffer: {kb} KiB requested"),
+        None => println!("Stream receive buffer: system default"),
+    }
+
+    match paths::config_dir() {
+        Ok(dir) => println!(
+            "Config directory: {} (no config file is read)",
+            dir.display()
+        ),
+        Err(e) => println!("Config directory: {e}"),
+    }
+
+    // Anything that cannot be written is given up on while playing, which
+    // is shown here rather than found out from one warning in the log
+    match paths::state_dir().and_then(|dir| paths::check_writable(&dir).map(|_| dir)) {
+        Ok(dir) => println!("State directory: {} (writable)", dir.display()),
+        Err(e) => println!("State directory: {e:#}, a name set by the server is not kept"),
+    }
+    if let Some(ref path) = cli.checksum_log {
+        let writable = match path.parent() {
+            _ if path.exists() => OpenOptions::new()
+                .append(true)
+                .open(path)
+                .map(|_| ())
+                .map_err(anyhow::Error::from),
+            Some(dir) if !dir.as_os_str().is_empty() => paths::check_writable(dir),
+            _ => paths::check_writable(Path::new(".")),
+        };
+        match writable {
+            Ok(()) => println!("Checksum log: {} (writable)", path.display()),
+            Err(e) => println!(
+                "Checksum log: {} ({e:#}), no checksums are logged",
+                path.display()
+            ),
+        }
+    }
+
+    for server in cli.server_addrs() {
+        match TcpStream::connect_timeout(&server.into(), Duration::from_secs(2)) {
+            Ok(_) => println!("Server: {server} (reachable)"),
+            Err(e) => println!("Server: {server} (unreachable: {e})"),
+        }
+    }
+}
+
+// Log which server we are connected to, and as what, whenever SIGUSR2
+// arrives, as the startup log lines have usually scrolled away. SIGTERM
+// and SIGINT are passed on through the returned channel for a clean exit.
+fn handle_signals(conn_info: Arc<Mutex<ConnectionInfo>>) -> anyhow::Result<Receiver<()>> {
+    let mut signals = Signals::new([SIGUSR2, SIGTERM, SIGINT])?;
+    let (shutdown_tx, shutdown_rx) = bounded(1);
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            match signal {
+                SIGUSR2 => {
+                    if let Ok(conn_info) = conn_info.lock() {
+                        info!("Connection: {conn_info}");
+                    }
+                }
+                _ => {
+                    shutdown_tx.try_send(()).ok();
+                }
+            }
+        }
+    });
+    Ok(shutdown_rx)
+}
+
+// Ends a session with the server however it is left, by a break, a
+// return, an error or a panic. Bye stops the writer once it has sent all
+// that was queued before it.
+struct SessionEnd(Sender<ClientMessage>);
+
+impl Drop for SessionEnd {
+    fn drop(&mut self) {
+        // Never blocks, as this may run while unwinding
+        self.0.try_send(ClientMessage::Bye(1)).ok();
+    }
 }
 
 fn main() -> anyhow::Result<()> {
@@ -101,17 +901,83 @@ fn main() -> anyhow::Result<()> {
         .with_level(cli.loglevel)
         .init()?;
 
-    #[cfg(all(feature = "pulse", feature = "rodio"))]
-    let output_system = cli.system.as_str();
-    #[cfg(all(feature = "pulse", not(feature = "rodio")))]
-    let output_system = "pulse";
-    #[cfg(all(not(feature = "pulse"), feature = "rodio"))]
-    let output_system = "rodio";
-    let mut output = AudioOutput::try_new(
-        output_system,
-        #[cfg(feature = "rodio")]
-        &cli.device,
-    )?;
+    // Every way out passes through here, so that the writer to the server
+    // and the helpers get the chance to finish
+    let result = panic::catch_unwind(AssertUnwindSafe(|| run(cli)));
+    threads::shutdown(SHUTDOWN_WAIT);
+    match result {
+        Ok(result) => result,
+        Err(payload) => panic::resume_unwind(payload),
+    }
+}
+
+fn run(cli: Cli) -> anyhow::Result<()> {
+    if let Some(ref dir) = cli.state_dir {
+        paths::set_state_dir(dir.to_owned());
+    }
+
+    #[cfg(feature = "dsp")]
+    if let Some(ref path) = cli.dsp_plugin {
+        dsp::set_hook(dsp::Hook::load_plugin(path)?);
+    } else if let Some(ref command) = cli.dsp_exec {
+        dsp::set_hook(dsp::Hook::exec(command.to_owned()));
+    }
+
+    #[cfg(feature = "scrobble")]
+    if let Some(ref token) = cli.listenbrainz_token {
+        scrobble::set_token(token.to_owned());
+    }
+
+    if cli.device_port.is_some() && output_system(&cli) != "pulse" {
+        warn!("--device-port is only supported with pulse, ignoring it");
+    }
+    if let Some(format) = cli.force_output_format {
+        if output_system(&cli) != "pulse" && format.bits != 16 {
+            warn!("The bits per sample of --force-output-format are only supported with pulse, rodio uses the device's own");
+        }
+        info!("Output format forced to {format}");
+    }
+
+    if cli.diagnose {
+        diagnose(&cli);
+        return Ok(());
+    }
+
+    if cli.dry_run {
+        return dry_run(&cli);
+    }
+
+    if cli.monitor {
+        let name = Arc::new(PlayerName::new());
+        if let Some(ref cli_name) = cli.name {
+            name.set_from_cli(cli_name.to_owned());
+        }
+        let conn_info = connection_info(&cli);
+        let shutdown = handle_signals(conn_info.clone())?;
+        return monitor::run(
+            name,
+            cli.proto_settings(),
+            Arc::new(Mutex::new(ConnectionHistory::new())),
+            conn_info,
+            Arc::new(Stats::new()),
+            shutdown,
+        );
+    }
+
+    let output_options = cli.output_options();
+    // Shared by every output made, as the reader of the server's messages
+    // may outlive the output it started with
+    let interrupts = Interrupts::new();
+    let mut output = make_audio_output(output_system(&cli), &output_options, interrupts.clone())?;
+
+    if cli.measure_latency {
+        return latency::measure(
+            &mut output,
+            output_system(&cli),
+            &cli.device,
+            &cli.decode_options(),
+        );
+    }
 
     // List the output devices and terminate
     if cli.list {
@@ -125,6 +991,9 @@ fn main() -> anyhow::Result<()> {
                 if let Some(desc) = description {
                     println!("   {}", desc);
                 }
+                for (port, active) in output.get_output_device_ports(name) {
+                    println!("   port: {}{}", port, if active { " (active)" } else { "" });
+                }
             });
         print!("Found {} device", names.len());
         if names.len() != 1 {
@@ -134,72 +1003,396 @@ fn main() -> anyhow::Result<()> {
         return Ok(());
     }
 
-    loop {
-        let name = {
-            let name = match hostname::get().map(|s| s.into_string()) {
-                Ok(Ok(hostname)) => cli.name.clone() + &format!("@{hostname}"),
-                _ => cli.name.clone(),
-            };
-            Arc::new(RwLock::new(name))
-        };
+    let stats = Arc::new(Stats::new());
+    let history = Arc::new(Mutex::new(ConnectionHistory::new()));
+    let conn_info = connection_info(&cli);
+    let shutdown = handle_signals(conn_info.clone())?;
+    let resumed = suspend::watch();
+    let mut chime = cli.chime.clone();
+    let mut first_connection = true;
+    let mut orphaned = false;
+    // The server's gain outlives the connection, so that anything played
+    // before it is sent again starts at the volume last set
+    let mut last_volume = Volume::new();
+    // Shared by every connection, so that a name set by the server is kept
+    // across reconnects
+    let name = Arc::new(PlayerName::new());
+    if let Some(ref cli_name) = cli.name {
+        name.set_from_cli(cli_name.to_owned());
+    }
+    let mut idle = IdleWatch::new();
+    let mut output_retry_at = Instant::now();
+    let clock = Clock::new();
+    let stream_options = Arc::new(cli.stream_options());
 
+    loop {
         // Start the slim protocol threads
         let status = Arc::new(Mutex::new(StatusData::default()));
-        let start_time = Instant::now();
-        let mut server_default_ip = *cli.server.unwrap_or(SocketAddrV4::new(0.into(), 0)).ip();
+        let mut server_default_ip = *cli
+            .server_addrs()
+            .first()
+            .unwrap_or(&SocketAddrV4::new(0.into(), 0))
+            .ip();
         let skip = Arc::new(AtomicCell::new(Duration::ZERO));
-        let (slim_tx_in, slim_tx_out) = bounded(1);
-        let (slim_rx_in, slim_rx_out) = bounded(1);
+        let mut drift = DriftTracker::new(cli.drift_correction);
+        // The stream the server expects to be playing, if any
+        let mut last_stream: Option<decode::StreamRequest> = None;
+        let (slim_tx_in, slim_tx_out) = bounded(32);
+        let (slim_rx_in, slim_rx_out) = bounded(8);
         proto::run(
-            cli.server,
             name.clone(),
+            cli.proto_settings(),
             slim_rx_in.clone(),
             slim_tx_out.clone(),
+            history.clone(),
+            conn_info.clone(),
+            interrupts.clone(),
         );
+        let _session_end = SessionEnd(slim_tx_in.clone());
 
-        let volume = Arc::new(Mutex::new(vec![1.0f32, 1.0]));
-        let (stream_in, stream_out) = bounded(10);
+        let volume = Arc::new(Mutex::new(last_volume));
+        let mut chime_due = first_connection || cli.chime_on_reconnect;
+        // Unbounded, as this loop sends on it too, when enqueuing a stream,
+        // and the output sends from its callbacks while the loop may hold
+        // the output's lock. A full channel would block either for good.
+        let (stream_in, stream_out) = unbounded();
         let mut select = Select::new();
         let slim_idx = select.recv(&slim_rx_out);
         let stream_idx = select.recv(&stream_out);
+        let shutdown_idx = select.recv(&shutdown);
+        let resumed_idx = select.recv(&resumed);
 
-        loop {
-            match select.select() {
-                op if op.index() == slim_idx => match op.recv(&slim_rx_out)? {
-                    Some(msg) => process_slim_msg(
-                        &mut output,
-                        msg,
-                        &mut server_default_ip,
-                        name.clone(),
-                        slim_tx_in.clone(),
-                        volume.clone(),
-                        status.clone(),
-                        stream_in.clone(),
-                        skip.clone(),
-                        &start_time,
-                    )?,
-                    None => {
-                        info!("Lost contact with server, resetting");
-                        slim_tx_in.send(ClientMessage::Bye(1)).ok();
+        'session: loop {
+            if output.is_lost() && Instant::now() >= output_retry_at {
+                output_retry_at = Instant::now() + OUTPUT_RETRY;
+                warn!("Lost the connection to the audio system, reconnecting");
+                match make_audio_output(output_system(&cli), &output_options, interrupts.clone()) {
+                    Ok(new_output) => {
+                        SlimHandler {
+                            output: &mut output,
+                            server_default_ip: &mut server_default_ip,
+                            last_stream: &mut last_stream,
+                            name: name.clone(),
+                            slim_tx_in: slim_tx_in.clone(),
+                            volume: volume.clone(),
+                            status: status.clone(),
+                            stream_in: stream_in.clone(),
+                            skip: skip.clone(),
+                            stats: stats.clone(),
+                            drift: &mut drift,
+                            clock,
+                            stream_options: stream_options.clone(),
+                        }
+                        .replace_output(new_output);
+                        info!("Reconnected to the audio system");
+                    }
+                    Err(e) => warn!("Unable to reconnect to the audio system: {e:#}"),
+                }
+            }
+
+            if cli.exit_when_done && idle.done(&output) {
+                info!("Playlist done, exiting");
+                output.stop();
+                return Ok(());
+            }
+            if let Some(mins) = cli.idle_timeout_mins {
+                // With rodio this closes the device. Pulse holds no stream
+                // when idle, so only its connection to the server remains.
+                if idle.release_due(&output, Duration::from_secs(mins * 60)) {
+                    info!("Idle for {mins} minutes, releasing the audio output");
+                    output.stop();
+                }
+            }
+
+            let Ok(op) = select.select_timeout(IDLE_CHECK) else {
+                continue;
+            };
+            match op {
+                op if op.index() == slim_idx => {
+                    let msg = op.recv(&slim_rx_out)?;
+
+                    // Audio left playing from before the connection was lost
+                    // is stopped once the server is back, so that it decides
+                    // where to carry on from
+                    if orphaned && msg.is_some() {
+                        info!("Server is back, stopping audio from the last connection");
                         output.stop();
-                        break;
+                        orphaned = false;
+                    }
+
+                    // The first message from the server shows the connection is up
+                    if chime_due && msg.is_some() {
+                        chime_due = false;
+                        first_connection = false;
+                        if let Some(ref path) = chime {
+                            if output.is_playing() {
+                                info!("Skipping chime, already playing");
+                            } else if let Err(e) =
+                                play_file(&mut output, path, volume.clone(), &stream_options.decode)
+                            {
+                                warn!("Unable to play chime: {e:#}");
+                                chime = None;
+                            }
+                        }
                     }
-                },
+
+                    for msg in coalesce_gain(msg, &slim_rx_out) {
+                        match msg {
+                            Some(msg) => SlimHandler {
+                                output: &mut output,
+                                server_default_ip: &mut server_default_ip,
+                                last_stream: &mut last_stream,
+                                name: name.clone(),
+                                slim_tx_in: slim_tx_in.clone(),
+                                volume: volume.clone(),
+                                status: status.clone(),
+                                stream_in: stream_in.clone(),
+                                skip: skip.clone(),
+                                stats: stats.clone(),
+                                drift: &mut drift,
+                                clock,
+                                stream_options: stream_options.clone(),
+                            }
+                            .handle(msg),
+                            None => {
+                                info!("Lost contact with server, resetting");
+                                info!("Statistics: {stats}");
+                                last_volume = volume.lock().map(|v| *v).unwrap_or_default();
+                                match cli.on_disconnect.as_str() {
+                                    "drain" => {
+                                        info!("Playing out buffered audio");
+                                        orphaned = true;
+                                    }
+                                    "fade" => {
+                                        info!("Playing out buffered audio with a fade");
+                                        fade_out(
+                                            volume.clone(),
+                                            DISCONNECT_GRACE - DISCONNECT_FADE,
+                                            DISCONNECT_FADE,
+                                        );
+                                        orphaned = true;
+                                    }
+                                    _ => output.stop(),
+                                }
+                                break 'session;
+                            }
+                        }
+                    }
+                }
+                op if op.index() == shutdown_idx => {
+                    op.recv(&shutdown).ok();
+                    info!("Shutting down");
+                    info!("Statistics: {stats}");
+                    // Tell the server the player has gone, rather than leave
+                    // it to notice. Waits for a busy writer only as long as
+                    // the exit does, as there may be no writer.
+                    slim_tx_in
+                        .send_timeout(ClientMessage::Bye(0), SHUTDOWN_WAIT)
+                        .ok();
+                    output.stop_with_fade(SHUTDOWN_FADE);
+                    return Ok(());
+                }
+                // Playback timing and the connection are both suspect after
+                // a suspend, so start afresh and let the server carry on
+                op if op.index() == resumed_idx => {
+                    let asleep = op.recv(&resumed)?;
+                    info!("System resumed after {asleep:?} asleep, reconnecting");
+                    output.stop();
+                    if let Ok(mut status) = status.lock() {
+                        let msg = clock.status_message(&mut status, StatusCode::Flushed);
+                        slim_tx_in.send(msg).ok();
+                    }
+                    last_volume = volume.lock().map(|v| *v).unwrap_or_default();
+                    orphaned = false;
+                    break 'session;
+                }
                 op if op.index() == stream_idx => {
                     let msg = op.recv(&stream_out)?;
-                    process_stream_msg(
-                        msg,
-                        status.clone(),
-                        slim_tx_in.clone(),
-                        &mut output,
-                        stream_in.clone(),
-                        &cli.device,
-                        #[cfg(feature = "notify")]
-                        &cli.quiet,
-                    );
+                    for msg in coalesce_events(msg, &stream_out, &stats) {
+                        let drained = matches!(msg, PlayerMsg::Drained);
+                        process_stream_msg(
+                            msg,
+                            status.clone(),
+                            slim_tx_in.clone(),
+                            &mut output,
+                            stream_in.clone(),
+                            clock,
+                            #[cfg(feature = "notify")]
+                            &cli.quiet,
+                        );
+                        // The last stream played out with nothing after it
+                        if drained && !output.is_playing() {
+                            last_stream = None;
+                        }
+                    }
                 }
                 _ => {}
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn helo_values() {
+        assert_eq!(cli_helo_value_parser("Vibe").unwrap(), "Vibe");
+        assert_eq!(cli_helo_value_parser("v1.9.9-1449").unwrap(), "v1.9.9-1449");
+        for bad in ["", "a,b", "a=b", "with space", &"x".repeat(33)] {
+            assert!(cli_helo_value_parser(bad).is_err(), "{bad:?} accepted");
+        }
+    }
+
+    fn addr(a: u8, b: u8, c: u8, d: u8, port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(a, b, c, d), port)
+    }
+
+    #[test]
+    fn server_addresses() {
+        let table = [
+            ("192.168.1.10", addr(192, 168, 1, 10, SLIM_PORT)),
+            ("192.168.1.10:", addr(192, 168, 1, 10, SLIM_PORT)),
+            ("192.168.1.10:3484", addr(192, 168, 1, 10, 3484)),
+            ("localhost", addr(127, 0, 0, 1, SLIM_PORT)),
+            ("localhost:3484", addr(127, 0, 0, 1, 3484)),
+        ];
+        for (value, expected) in table {
+            assert_eq!(cli_address_parser(value).unwrap(), expected, "{value}");
+            let server = cli_server_parser(value).unwrap();
+            assert_eq!(server.addr, expected, "{value}");
+            assert_eq!(server.http_port, None, "{value}");
+        }
+        for bad in [
+            "192.168.1.10:port",
+            "192.168.1.10:65536",
+            "192.168.1.10:-1",
+            "::1",
+            "[::1]:3483",
+            "fe80::1",
+        ] {
+            assert!(cli_address_parser(bad).is_err(), "{bad:?} accepted");
+            assert!(cli_server_parser(bad).is_err(), "{bad:?} accepted");
+        }
+    }
+
+    #[test]
+    fn server_urls() {
+        let table = [
+            ("http://192.168.1.10", 9000),
+            ("http://192.168.1.10/", 9000),
+            ("http://192.168.1.10:/", 9000),
+            ("HTTP://192.168.1.10:9002/material/", 9002),
+            ("https://192.168.1.10:443", 443),
+        ];
+        for (value, http_port) in table {
+            let server = cli_server_parser(value).unwrap();
+            assert_eq!(server.addr, addr(192, 168, 1, 10, SLIM_PORT), "{value}");
+            assert_eq!(server.http_port, Some(http_port), "{value}");
+        }
+        for bad in [
+            "http://[::1]:9000/",
+            "http://192.168.1.10:port/",
+            "http://192.168.1.10:90000/",
+            "ftp://192.168.1.10",
+        ] {
+            assert!(cli_server_parser(bad).is_err(), "{bad:?} accepted");
+        }
+    }
+
+    #[test]
+    fn resolved_addresses() {
+        assert_eq!(resolve("10.0.0.1", 3483).unwrap(), addr(10, 0, 0, 1, 3483));
+        assert_eq!(
+            resolve("localhost", 9000).unwrap(),
+            addr(127, 0, 0, 1, 9000)
+        );
+        assert!(resolve("no-such-host.invalid", 3483).is_err());
+    }
+
+    #[test]
+    fn device_rules() {
+        let rule = |rates, device: &str| DeviceRule::new(rates, device.to_owned());
+        let table = [
+            ("44100:hw:0", rule(44100..=44100, "hw:0")),
+            ("=48000:usb", rule(48000..=48000, "usb")),
+            ("<=48000:dac", rule(0..=48000, "dac")),
+            ("<48000:dac", rule(0..=47999, "dac")),
+            (">=88200:dac", rule(88200..=u32::MAX, "dac")),
+            (">48000:dac", rule(48001..=u32::MAX, "dac")),
+            ("88200-192000:dac", rule(88200..=192000, "dac")),
+            (" 44100 - 48000 :dac", rule(44100..=48000, "dac")),
+        ];
+        for (value, expected) in table {
+            assert_eq!(cli_device_rule_parser(value).unwrap(), expected, "{value}");
+        }
+        for bad in [
+            "",
+            "44100",
+            "44100:",
+            "rate:dac",
+            "48000-44100:dac",
+            "<0:dac",
+            ">4294967295:dac",
+            "-48000:dac",
+        ] {
+            assert!(cli_device_rule_parser(bad).is_err(), "{bad:?} accepted");
+        }
+    }
+
+    #[test]
+    fn output_formats() {
+        let format = |rate, bits, channels| OutputFormat {
+            rate,
+            bits,
+            channels,
+        };
+        let table = [
+            ("48000:24:2", format(48000, 24, 2)),
+            ("44100:16:1", format(44100, 16, 1)),
+            ("8000:32:2", format(8000, 32, 2)),
+            ("384000:16:2", format(384000, 16, 2)),
+        ];
+        for (value, expected) in table {
+            assert_eq!(
+                cli_output_format_parser(value).unwrap(),
+                expected,
+                "{value}"
+            );
+        }
+        for bad in [
+            "",
+            "48000",
+            "48000:24",
+            "48000:24:2:1",
+            "7999:16:2",
+            "384001:16:2",
+            "48000:20:2",
+            "48000:24:6",
+            "48k:24:2",
+        ] {
+            assert!(cli_output_format_parser(bad).is_err(), "{bad:?} accepted");
+        }
+    }
+
+    #[test]
+    fn mac_addresses() {
+        assert_eq!(
+            cli_mac_parser("02:1a:2B:3c:4D:5e").unwrap(),
+            MacAddress::new([0x02, 0x1a, 0x2b, 0x3c, 0x4d, 0x5e])
+        );
+        for bad in [
+            "",
+            "02:00:00:00:00",
+            "02:00:00:00:00:01:02",
+            "02-00-00-00-00-01",
+            "2:00:00:00:00:01",
+            "02:00:00:00:00:0g",
+            "00:00:00:00:00:00",
+            "01:00:5e:00:00:01",
+        ] {
+            assert!(cli_mac_parser(bad).is_err(), "{bad:?} accepted");
+        }
+    }
+}
@@ -1,169 +1,428 @@
 use std::{
+    collections::HashMap,
+    mem::{self, Discriminant},
     net::Ipv4Addr,
-    sync::{Arc, Mutex, RwLock},
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 
-use crossbeam::{atomic::AtomicCell, channel::Sender};
-use log::{info, warn};
+use crossbeam::{
+    atomic::AtomicCell,
+    channel::{Receiver, Sender, TrySendError},
+};
+use log::{debug, info, trace, warn};
 use slimproto::{
+    proto::AutoStart,
     status::{StatusCode, StatusData},
     ClientMessage, ServerMessage,
 };
 
 #[cfg(feature = "notify")]
 use crate::notify::notify;
-use crate::{audio_out::AudioOutput, decode, StreamParams};
+#[cfg(feature = "scrobble")]
+use crate::scrobble::{self, Track};
+use crate::{
+    audio_out::AudioOutput,
+    decode,
+    player_name::PlayerName,
+    stats::Stats,
+    sync::{Correction, DriftTracker},
+    threads,
+    transition::Transition,
+    volume::Volume,
+    StreamParams,
+};
 
+/// Events for the main loop. Only the main loop sends to the server, so
+/// that status messages reach it in the order they happen. Threads and
+/// callbacks send one of these instead.
+///
+/// Every event but a stalled stream moves playback on, e.g. to the next
+/// track, and is never dropped. A stalled stream is only reported, so a
+/// run of them waiting in the queue is sent as one, see `coalesce_events`.
 #[allow(unused)]
 pub enum PlayerMsg {
     EndOfDecode,
     Drained,
     Pause,
     Unpause,
+    // The time given with a server's unpause has come
+    Resume,
     Connected,
     BufferThreshold,
     NotSupported,
     StreamEstablished,
+    StreamStalled,
     TrackStarted,
-    Decoder((decode::Decoder, StreamParams)),
+    EndOfHold(u64),
+    // Boxed, as a decoder is far larger than any other event
+    Decoder(Box<(decode::Decoder, StreamParams)>),
 }
 
-pub fn process_slim_msg(
-    output: &mut AudioOutput,
-    msg: ServerMessage,
-    server_default_ip: &mut Ipv4Addr,
-    name: Arc<RwLock<String>>,
-    slim_tx_in: Sender<ClientMessage>,
-    volume: Arc<Mutex<Vec<f32>>>,
-    status: Arc<Mutex<StatusData>>,
-    stream_in: Sender<PlayerMsg>,
-    skip: Arc<AtomicCell<Duration>>,
-    start_time: &Instant,
-) -> anyhow::Result<()> {
-    // println!("{:?}", msg);
-    match msg {
-        ServerMessage::Serv { ip_address, .. } => {
-            info!("Switching to server at {ip_address}");
-            *server_default_ip = ip_address;
+/// The player's clock, as sent to the server in every status message. It
+/// counts milliseconds from when it was made and wraps at 32 bits, as the
+/// field does. Make one at startup and keep it across reconnects, as the
+/// server models each player's clock from the jiffies it sends.
+#[derive(Clone, Copy)]
+pub struct Clock(Instant);
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self(Instant::now())
+    }
+
+    pub fn jiffies(&self) -> Duration {
+        let millis = self.0.elapsed().as_millis();
+        Duration::from_millis((millis % (1 << 32)) as u64)
+    }
+
+    /// Make a status message to send to the server, stamped with the
+    /// current jiffies. Every status message should be made this way.
+    pub fn status_message(&self, status: &mut StatusData, code: StatusCode) -> ClientMessage {
+        status.set_jiffies(self.jiffies());
+        status.make_status_message(code)
+    }
+}
+
+/// Dragging the volume slider sends a burst of Gain messages, of which only
+/// the latest matters. Replace any run of queued Gain messages with the last
+/// one, keeping the first message that follows them.
+pub fn coalesce_gain(
+    msg: Option<ServerMessage>,
+    slim_rx_out: &Receiver<Option<ServerMessage>>,
+) -> Vec<Option<ServerMessage>> {
+    let mut msgs = vec![msg];
+    while let Some(Some(ServerMessage::Gain(..))) = msgs.last() {
+        match slim_rx_out.try_recv() {
+            Ok(next @ Some(ServerMessage::Gain(..))) => {
+                msgs.pop();
+                msgs.push(next);
+            }
+            Ok(next) => msgs.push(next),
+            Err(_) => break,
         }
+    }
+    msgs
+}
+
+impl PlayerMsg {
+    /// Whether the event only reports something, rather than being one
+    /// that playback waits on.
+    pub fn is_informational(&self) -> bool {
+        matches!(self, PlayerMsg::StreamStalled)
+    }
+}
 
-        ServerMessage::Queryname => {
-            log::info!("Name query from server");
-            if let Ok(name) = name.read() {
-                info!("Sending name: {name}");
-                slim_tx_in.send(ClientMessage::Name(name.to_owned())).ok();
+/// Replace any run of queued informational events of one kind with the
+/// last of them, keeping the first event that follows, as for
+/// `coalesce_gain`. Each event left out is counted in `stats`.
+pub fn coalesce_events(
+    msg: PlayerMsg,
+    stream_out: &Receiver<PlayerMsg>,
+    stats: &Stats,
+) -> Vec<PlayerMsg> {
+    let mut msgs = vec![msg];
+    while let Some(last) = msgs.last().filter(|last| last.is_informational()) {
+        let kind = mem::discriminant(last);
+        match stream_out.try_recv() {
+            Ok(next) if mem::discriminant(&next) == kind => {
+                stats.add_coalesced_event();
+                msgs.pop();
+                msgs.push(next);
             }
+            Ok(next) => msgs.push(next),
+            Err(_) => break,
         }
+    }
+    msgs
+}
+
+// Some servers keep sending messages that are not acted on, e.g. for
+// visualisers, so each kind is warned of once and then counted
+const MAX_UNHANDLED_KINDS: usize = 64;
+const UNHANDLED_SUMMARY_EVERY: Duration = Duration::from_secs(60 * 60);
 
-        ServerMessage::Setname(new_name) => {
-            if let Ok(mut name) = name.write() {
-                info!("Set name to {new_name}");
-                *name = new_name;
+struct Unhandled {
+    // The name of each kind and how many have come since the last summary
+    seen: HashMap<Discriminant<ServerMessage>, (String, usize)>,
+    since: Instant,
+}
+
+static UNHANDLED: Mutex<Option<Unhandled>> = Mutex::new(None);
+
+fn log_unhandled(msg: &ServerMessage) {
+    let Ok(mut unhandled) = UNHANDLED.lock() else {
+        return;
+    };
+    let unhandled = unhandled.get_or_insert_with(|| Unhandled {
+        seen: HashMap::new(),
+        since: Instant::now(),
+    });
+
+    match unhandled.seen.get_mut(&mem::discriminant(msg)) {
+        Some((_, count)) => {
+            *count += 1;
+            trace!("Unimplemented command: {:?}", msg);
+        }
+        None => {
+            warn!("Unimplemented command: {:?}", msg);
+            if unhandled.seen.len() < MAX_UNHANDLED_KINDS {
+                let name = format!("{:?}", msg)
+                    .split(|c: char| !c.is_alphanumeric())
+                    .next()
+                    .unwrap_or_default()
+                    .to_owned();
+                unhandled.seen.insert(mem::discriminant(msg), (name, 0));
             }
         }
+    }
 
-        ServerMessage::Gain(l, r) => {
-            info!("Setting volume to ({l}, {r})");
-            if let Ok(mut vol) = volume.lock() {
-                vol[0] = l.sqrt() as f32;
-                vol[1] = r.sqrt() as f32;
+    if unhandled.since.elapsed() >= UNHANDLED_SUMMARY_EVERY {
+        for (name, count) in unhandled.seen.values_mut() {
+            if *count > 0 {
+                info!("Another {count} unimplemented {name} commands in the last hour");
+                *count = 0;
             }
         }
+        unhandled.since = Instant::now();
+    }
+}
 
-        ServerMessage::Status(ts) => {
-            // info!("Received status tick from server with timestamp {:#?}", ts);
-            let dur = output.get_dur();
-            if let Ok(mut status) = status.lock() {
-                // info!("Sending status update - jiffies: {:?}", status.get_jiffies());
-                status.set_elapsed_milli_seconds(dur.as_millis() as u32);
-                status.set_elapsed_seconds(dur.as_secs() as u32);
-                status.set_timestamp(ts);
+/// The state a server message acts on, with one method for each kind of
+/// message. A handler is made for each message and used up by it.
+pub struct SlimHandler<'a> {
+    pub output: &'a mut AudioOutput,
+    pub server_default_ip: &'a mut Ipv4Addr,
+    /// The stream the server last asked for, kept until a stop or flush so
+    /// that it can be started again on a new output.
+    pub last_stream: &'a mut Option<decode::StreamRequest>,
+    pub name: Arc<PlayerName>,
+    pub slim_tx_in: Sender<ClientMessage>,
+    pub volume: Arc<Mutex<Volume>>,
+    pub status: Arc<Mutex<StatusData>>,
+    pub stream_in: Sender<PlayerMsg>,
+    pub skip: Arc<AtomicCell<Duration>>,
+    pub stats: Arc<Stats>,
+    pub drift: &'a mut DriftTracker,
+    pub clock: Clock,
+    pub stream_options: Arc<decode::StreamOptions>,
+}
 
-                let msg = status.make_status_message(StatusCode::Timer);
-                slim_tx_in.send(msg).ok();
-            }
+impl SlimHandler<'_> {
+    pub fn handle(self, msg: ServerMessage) {
+        // println!("{:?}", msg);
+        match msg {
+            ServerMessage::Serv {
+                ip_address,
+                sync_group_id,
+            } => self.serv(ip_address, sync_group_id),
+            ServerMessage::Queryname => self.queryname(),
+            ServerMessage::Setname(new_name) => self.setname(new_name),
+            ServerMessage::Gain(l, r) => self.gain(l, r),
+            ServerMessage::Status(ts) => self.status(ts),
+            ServerMessage::Stop => self.stop(),
+            ServerMessage::Flush => self.flush(),
+            ServerMessage::Pause(interval) => self.pause(interval),
+            ServerMessage::Unpause(interval) => self.unpause(interval),
+            ServerMessage::Enable(spdif, dac) => self.enable(spdif, dac),
+            ServerMessage::Skip(interval) => self.skip(interval),
+            msg @ ServerMessage::Stream { .. } => self.stream(msg),
+            cmd => log_unhandled(&cmd),
         }
+    }
 
-        ServerMessage::Stop => {
-            info!("Stop playback received");
-            output.stop();
-            if let Ok(mut status) = status.lock() {
-                status.set_elapsed_milli_seconds(0);
-                status.set_elapsed_seconds(0);
-                status.set_output_buffer_size(0);
-                status.set_output_buffer_fullness(0);
-                info!("Player flushed");
-                let msg = status.make_status_message(StatusCode::Flushed);
-                slim_tx_in.send(msg).ok();
-            }
+    pub fn serv(self, ip_address: Ipv4Addr, sync_group_id: Option<String>) {
+        match sync_group_id {
+            Some(sgid) => info!("Switching to server at {ip_address}, in sync group {sgid}"),
+            None => info!("Switching to server at {ip_address}"),
         }
+        *self.server_default_ip = ip_address;
+    }
 
-        ServerMessage::Flush => {
-            info!("Flushing");
-            output.flush();
-            if let Ok(mut status) = status.lock() {
-                status.set_elapsed_milli_seconds(0);
-                status.set_elapsed_seconds(0);
-                status.set_output_buffer_size(0);
-                status.set_output_buffer_fullness(0);
-                info!("Player flushed");
-                let msg = status.make_status_message(StatusCode::Flushed);
-                slim_tx_in.send(msg).ok();
-            }
+    pub fn queryname(self) {
+        log::info!("Name query from server");
+        let name = self.name.current();
+        info!("Sending name: {name}");
+        self.slim_tx_in.send(ClientMessage::Name(name)).ok();
+    }
+
+    pub fn setname(self, new_name: String) {
+        info!("Set name to {new_name}");
+        self.name.set_from_server(new_name);
+    }
+
+    pub fn gain(self, l: f64, r: f64) {
+        info!("Setting volume to ({l}, {r})");
+        if let Ok(mut vol) = self.volume.lock() {
+            vol.set(l.sqrt() as f32, r.sqrt() as f32);
         }
+    }
+
+    pub fn status(self, ts: Duration) {
+        // info!("Received status tick from server with timestamp {:#?}", ts);
+        let dur = self.output.get_dur();
+        if let Ok(mut status) = self.status.lock() {
+            // info!("Sending status update - jiffies: {:?}", status.get_jiffies());
+            status.set_elapsed_milli_seconds(dur.as_millis() as u32);
+            status.set_elapsed_seconds(dur.as_secs() as u32);
+            status.set_timestamp(ts);
 
-        ServerMessage::Pause(interval) => {
-            info!("Pause requested with interval {:?}", interval);
-            if interval.is_zero() {
-                if output.pause() {
-                    if let Ok(mut status) = status.lock() {
-                        info!("Sending paused to server");
-                        let msg = status.make_status_message(StatusCode::Pause);
-                        slim_tx_in.send(msg).ok();
+            // Timer updates are periodic so it is better to drop one
+            // than to hold up the messages the server is waiting for
+            let msg = self.clock.status_message(&mut status, StatusCode::Timer);
+            if let Err(TrySendError::Full(_)) = self.slim_tx_in.try_send(msg) {
+                match self.stats.add_dropped_status() {
+                    1 => info!(
+                        "Server connection busy, dropped a timer status, later ones are \
+                        counted in the statistics"
+                    ),
+                    dropped => {
+                        debug!("Server connection busy, dropped timer status ({dropped} in total)")
                     }
                 }
-            } else {
-                if output.pause() {
-                    std::thread::spawn(move || {
-                        std::thread::sleep(interval);
-                        stream_in.send(PlayerMsg::Unpause).ok();
+            }
+        }
+
+        match self.drift.check(dur, self.output.is_paused(), &self.stats) {
+            Some(Correction::Skip(dur)) => self.skip.store(dur),
+            Some(Correction::Hold(dur)) => {
+                if let Some(hold) = self.output.hold() {
+                    let stream_in = self.stream_in;
+                    threads::spawn(move || {
+                        if threads::sleep(dur) {
+                            stream_in.send(PlayerMsg::EndOfHold(hold)).ok();
+                        }
                     });
                 }
             }
+            None => {}
         }
 
-        ServerMessage::Unpause(interval) => {
-            info!("Resume requested with interval {:?}", interval);
-            if interval.is_zero() {
-                if output.unpause() {
-                    if let Ok(mut status) = status.lock() {
-                        info!("Sending resumed to server");
-                        let msg = status.make_status_message(StatusCode::Resume);
-                        slim_tx_in.send(msg).ok();
-                    }
+        #[cfg(feature = "scrobble")]
+        scrobble::progress(dur);
+    }
+
+    pub fn stop(self) {
+        info!("Stop playback received");
+        *self.last_stream = None;
+        self.drift.reset();
+        #[cfg(feature = "scrobble")]
+        scrobble::stop();
+        self.output.stop();
+        self.send_flushed();
+    }
+
+    pub fn flush(self) {
+        info!("Flushing");
+        *self.last_stream = None;
+        self.drift.reset();
+        #[cfg(feature = "scrobble")]
+        scrobble::stop();
+        self.output.flush();
+        self.send_flushed();
+    }
+
+    fn send_flushed(self) {
+        if let Ok(mut status) = self.status.lock() {
+            status.set_elapsed_milli_seconds(0);
+            status.set_elapsed_seconds(0);
+            status.set_output_buffer_size(0);
+            status.set_output_buffer_fullness(0);
+            info!("Player flushed");
+            let msg = self.clock.status_message(&mut status, StatusCode::Flushed);
+            self.slim_tx_in.send(msg).ok();
+        }
+    }
+
+    pub fn pause(self, interval: Duration) {
+        info!("Pause requested with interval {:?}", interval);
+        self.drift.reset();
+        if interval.is_zero() {
+            if self.output.pause() {
+                if let Ok(mut status) = self.status.lock() {
+                    info!("Sending paused to server");
+                    let msg = self.clock.status_message(&mut status, StatusCode::Pause);
+                    self.slim_tx_in.send(msg).ok();
                 }
-            } else {
-                let dur = interval.saturating_sub(Instant::now() - *start_time);
-                info!("Resuming in {:?}", dur);
-                std::thread::spawn(move || {
-                    std::thread::sleep(dur);
-                    stream_in.send(PlayerMsg::Unpause).ok();
-                    if let Ok(mut status) = status.lock() {
-                        info!("Sending resumed to server");
-                        let msg = status.make_status_message(StatusCode::Resume);
-                        slim_tx_in.send(msg).ok();
+            }
+        } else if self.output.can_micro_pause(interval) {
+            if self.output.is_playing() {
+                info!("Pausing for {interval:?} by playing silence");
+                self.output.micro_pause(interval);
+            }
+        } else {
+            if self.output.pause() {
+                let stream_in = self.stream_in;
+                threads::spawn(move || {
+                    if threads::sleep(interval) {
+                        stream_in.send(PlayerMsg::Unpause).ok();
                     }
                 });
             }
         }
+    }
 
-        ServerMessage::Skip(interval) => {
-            info!("Skip ahead: {:?}", interval);
-            skip.store(interval);
+    pub fn unpause(self, interval: Duration) {
+        info!("Resume requested with interval {:?}", interval);
+        self.drift.reset();
+        if interval.is_zero() {
+            if self.output.unpause() {
+                if let Ok(mut status) = self.status.lock() {
+                    info!("Sending resumed to server");
+                    let msg = self.clock.status_message(&mut status, StatusCode::Resume);
+                    self.slim_tx_in.send(msg).ok();
+                }
+            } else {
+                // e.g. just after a seek, before the new stream is enqueued
+                self.output.start_when_ready();
+            }
+        } else {
+            // The interval is the player's jiffies at which to start, so
+            // that every player in a sync group starts together. If that
+            // has passed, start now and skip the audio already missed.
+            let elapsed = self.clock.jiffies();
+            let dur = match interval.checked_sub(elapsed) {
+                Some(dur) => {
+                    info!("Resuming in {:?}", dur);
+                    dur
+                }
+                None => {
+                    let late = elapsed - interval;
+                    info!("Resuming {:?} late, skipping ahead to catch up", late);
+                    self.skip.store(late);
+                    Duration::ZERO
+                }
+            };
+            let stream_in = self.stream_in;
+            threads::spawn(move || {
+                if threads::sleep(dur) {
+                    stream_in.send(PlayerMsg::Resume).ok();
+                }
+            });
         }
+    }
 
-        ServerMessage::Stream {
+    // The audio output is created at startup, not here, so a stream
+    // command arriving before this one still plays
+    pub fn enable(self, spdif: bool, dac: bool) {
+        info!("Audio output enable: spdif {spdif}, dac {dac}");
+    }
+
+    pub fn skip(self, interval: Duration) {
+        info!("Skip ahead: {:?}", interval);
+        self.drift.reset();
+        self.skip.store(interval);
+    }
+
+    /// Start fetching and decoding a stream, given a `Stream` message.
+    pub fn stream(self, msg: ServerMessage) {
+        let ServerMessage::Stream {
             http_headers,
             server_ip,
             server_port,
@@ -173,75 +432,202 @@ pub fn process_slim_msg(
             pcmchannels,
             autostart,
             output_threshold,
+            trans_type,
+            trans_period,
             ..
-        } => {
-            info!("Start stream command from server");
-            info!("\tFormat: {:?}", format);
-            info!("\tThreshold: {} bytes", threshold);
-            info!("\tOutput threshold: {:?}", output_threshold);
-            if let Some(http_headers) = http_headers {
-                let num_crlf = http_headers.matches("\r\n").count();
-
-                if num_crlf > 0 {
-                    if let Ok(mut status) = status.lock() {
-                        status.add_crlf(num_crlf as u8);
-                    }
+        } = msg
+        else {
+            return;
+        };
 
-                    let stream_in_r = stream_in.clone();
-                    let default_ip = server_default_ip.clone();
-                    std::thread::spawn(move || {
-                        match decode::make_decoder(
-                            server_ip,
-                            default_ip,
-                            server_port,
-                            http_headers,
-                            stream_in_r.clone(),
-                            status,
-                            threshold,
-                            format,
-                            pcmsamplerate,
-                            pcmchannels,
-                            autostart,
-                            volume.clone(),
-                            #[cfg(feature = "pulse")]
-                            skip.clone(),
-                            output_threshold,
-                        ) {
-                            Ok(decoder_params) => {
-                                stream_in_r.send(PlayerMsg::Decoder(decoder_params)).ok();
-                            }
-                            Err(e) => {
-                                warn!("{}", e);
-                                stream_in_r.send(PlayerMsg::NotSupported).ok();
-                            }
-                        }
-                    });
+        info!("Start stream command from server");
+        // STAT has no fields for the stream format, the server shows
+        // the format it asked for here on its player information page
+        info!("\tFormat: {:?}", format);
+        info!("\tThreshold: {} bytes", threshold);
+        info!("\tOutput threshold: {:?}", output_threshold);
+        let transition =
+            Transition::from_server(trans_type, trans_period, self.stream_options.transition);
+        info!(
+            "\tTransition: {:?} over {:?}",
+            transition.kind, transition.period
+        );
+        let Some(http_headers) = http_headers else {
+            return;
+        };
+        let num_crlf = http_headers.matches("\r\n").count();
+        if num_crlf == 0 {
+            return;
+        }
+
+        if let Ok(mut status) = self.status.lock() {
+            status.add_crlf(num_crlf as u8);
+        }
+
+        let request = decode::StreamRequest {
+            server_ip,
+            server_port,
+            http_headers,
+            threshold,
+            format,
+            pcmsamplerate,
+            pcmchannels,
+            autostart,
+            output_threshold,
+            transition,
+        };
+        *self.last_stream = Some(request.clone());
+        let context = decode::StreamContext {
+            default_ip: *self.server_default_ip,
+            stream_in: self.stream_in,
+            status: self.status,
+            volume: self.volume,
+            skip: self.skip,
+            stats: self.stats,
+            output: self.output.state(),
+        };
+        fetch_stream(request, context, self.stream_options);
+    }
+
+    /// Carry on with `new_output`, made as the old output was lost. What
+    /// was buffered went with the old output, so the stream the server
+    /// expects to be playing starts again from the beginning, and the
+    /// server hears of it as a new track starting.
+    pub fn replace_output(self, new_output: AudioOutput) {
+        let old_output = mem::replace(self.output, new_output);
+        match self.last_stream.clone() {
+            Some(mut request) => {
+                info!("Starting the last stream again on the new output");
+                if old_output.is_paused() {
+                    request.autostart = AutoStart::None;
+                }
+                let context = decode::StreamContext {
+                    default_ip: *self.server_default_ip,
+                    stream_in: self.stream_in,
+                    status: self.status,
+                    volume: self.volume,
+                    skip: self.skip,
+                    stats: self.stats,
+                    output: self.output.state(),
+                };
+                fetch_stream(request, context, self.stream_options);
+            }
+            // Anything else that was playing cannot be picked up again, so
+            // have the server move on
+            None if old_output.is_playing() => {
+                if let Ok(mut status) = self.status.lock() {
+                    let msg = self
+                        .clock
+                        .status_message(&mut status, StatusCode::NotSupported);
+                    self.slim_tx_in.send(msg).ok();
                 }
             }
+            None => {}
         }
+    }
+}
 
-        cmd => {
-            warn!("Unimplemented command: {:?}", cmd);
+/// Fetch and decode a stream on another thread, sending the decoder to the
+/// main loop, or telling the server that the stream is not supported.
+pub fn fetch_stream(
+    request: decode::StreamRequest,
+    context: decode::StreamContext,
+    stream_options: Arc<decode::StreamOptions>,
+) {
+    let format_name = format!("{:?}", request.format);
+    let stream_in = context.stream_in.clone();
+    threads::spawn(move || {
+        match decode::make_decoder(request, context, &stream_options) {
+            Ok(decoder_params) => {
+                stream_in
+                    .send(PlayerMsg::Decoder(Box::new(decoder_params)))
+                    .ok();
+            }
+            // The server is told the stream is not supported
+            // in every case, the log says why
+            Err(e) => {
+                match e.downcast_ref::<decode::OpenError>() {
+                    Some(decode::OpenError::CodecUnsupported(_)) => {
+                        warn!("Cannot play stream: {e}")
+                    }
+                    Some(
+                        decode::OpenError::ProbeFailed(_)
+                        | decode::OpenError::NoDefaultTrack
+                        | decode::OpenError::BadParameters(_),
+                    ) => warn!("Stream is not valid {format_name}: {e}"),
+                    _ => warn!("{e}"),
+                }
+                stream_in.send(PlayerMsg::NotSupported).ok();
+            }
         }
-    }
+    });
+}
 
+/// Act on a message from the server. No stream is kept to start again, as
+/// for a player whose output is never replaced.
+pub fn process_slim_msg(
+    output: &mut AudioOutput,
+    msg: ServerMessage,
+    server_default_ip: &mut Ipv4Addr,
+    name: Arc<PlayerName>,
+    slim_tx_in: Sender<ClientMessage>,
+    volume: Arc<Mutex<Volume>>,
+    status: Arc<Mutex<StatusData>>,
+    stream_in: Sender<PlayerMsg>,
+    skip: Arc<AtomicCell<Duration>>,
+    stats: Arc<Stats>,
+    drift: &mut DriftTracker,
+    clock: Clock,
+    stream_options: Arc<decode::StreamOptions>,
+) -> anyhow::Result<()> {
+    SlimHandler {
+        output,
+        server_default_ip,
+        last_stream: &mut None,
+        name,
+        slim_tx_in,
+        volume,
+        status,
+        stream_in,
+        skip,
+        stats,
+        drift,
+        clock,
+        stream_options,
+    }
+    .handle(msg);
     Ok(())
 }
 
+fn unpaused_by_player(
+    output: &mut AudioOutput,
+    status: &Mutex<StatusData>,
+    slim_tx_in: &Sender<ClientMessage>,
+    clock: Clock,
+) {
+    if output.unpause() {
+        info!("Sending track unpaused by player");
+        if let Ok(mut status) = status.lock() {
+            let msg = clock.status_message(&mut status, StatusCode::TrackStarted);
+            slim_tx_in.send(msg).ok();
+        }
+    }
+}
+
 pub fn process_stream_msg(
     msg: PlayerMsg,
     status: Arc<Mutex<StatusData>>,
     slim_tx_in: Sender<ClientMessage>,
     output: &mut AudioOutput,
     stream_in: Sender<PlayerMsg>,
-    device: &Option<String>,
+    clock: Clock,
     #[cfg(feature = "notify")] quiet: &bool,
 ) {
     match msg {
         PlayerMsg::EndOfDecode => {
             if let Ok(mut status) = status.lock() {
                 info!("Decoder ready for new stream");
-                let msg = status.make_status_message(StatusCode::DecoderReady);
+                let msg = clock.status_message(&mut status, StatusCode::DecoderReady);
                 slim_tx_in.send(msg).ok();
             }
         }
@@ -249,7 +635,15 @@ pub fn process_stream_msg(
         PlayerMsg::Drained => {
             info!("End of track");
             output.shift();
-            output.unpause();
+            #[cfg(feature = "scrobble")]
+            scrobble::track_ended();
+            // A pause that arrived as the track ended carries over to the next
+            if output.is_paused() {
+                info!("Next track queued paused");
+                output.pause();
+            } else {
+                output.unpause();
+            }
         }
 
         PlayerMsg::Pause => {
@@ -257,20 +651,24 @@ pub fn process_stream_msg(
             output.pause();
         }
 
-        PlayerMsg::Unpause => {
-            if output.unpause() {
-                info!("Sending track unpaused by player");
-                if let Ok(mut status) = status.lock() {
-                    let msg = status.make_status_message(StatusCode::TrackStarted);
-                    slim_tx_in.send(msg).ok();
-                }
+        // Drift correction is over, carry on without telling the server
+        PlayerMsg::EndOfHold(hold) => output.end_hold(hold),
+
+        PlayerMsg::Unpause => unpaused_by_player(output, &status, &slim_tx_in, clock),
+
+        PlayerMsg::Resume => {
+            unpaused_by_player(output, &status, &slim_tx_in, clock);
+            if let Ok(mut status) = status.lock() {
+                info!("Sending resumed to server");
+                let msg = clock.status_message(&mut status, StatusCode::Resume);
+                slim_tx_in.send(msg).ok();
             }
         }
 
         PlayerMsg::Connected => {
             if let Ok(mut status) = status.lock() {
                 info!("Sending stream connected");
-                let msg = status.make_status_message(StatusCode::Connect);
+                let msg = clock.status_message(&mut status, StatusCode::Connect);
                 slim_tx_in.send(msg).ok();
             }
         }
@@ -278,7 +676,7 @@ pub fn process_stream_msg(
         PlayerMsg::BufferThreshold => {
             if let Ok(mut status) = status.lock() {
                 info!("Sending buffer threshold reached");
-                let msg = status.make_status_message(StatusCode::BufferThreshold);
+                let msg = clock.status_message(&mut status, StatusCode::BufferThreshold);
                 slim_tx_in.send(msg).ok();
             }
         }
@@ -286,7 +684,7 @@ pub fn process_stream_msg(
         PlayerMsg::NotSupported => {
             warn!("Unsupported format");
             if let Ok(mut status) = status.lock() {
-                let msg = status.make_status_message(StatusCode::NotSupported);
+                let msg = clock.status_message(&mut status, StatusCode::NotSupported);
                 slim_tx_in.send(msg).ok();
             }
         }
@@ -294,34 +692,646 @@ pub fn process_stream_msg(
         PlayerMsg::StreamEstablished => {
             if let Ok(mut status) = status.lock() {
                 info!("Sending stream established");
-                let msg = status.make_status_message(StatusCode::StreamEstablished);
+                let msg = clock.status_message(&mut status, StatusCode::StreamEstablished);
+                slim_tx_in.send(msg).ok();
+            }
+        }
+
+        // The server treats this as it does a hardware player running out
+        // of audio, showing the player as buffering until it has enough
+        PlayerMsg::StreamStalled => {
+            if let Ok(mut status) = status.lock() {
+                info!("Sending output underrun");
+                let msg = clock.status_message(&mut status, StatusCode::OutputUnderrun);
                 slim_tx_in.send(msg).ok();
             }
         }
 
         PlayerMsg::TrackStarted => {
             info!("Sending track started");
+            #[cfg(feature = "scrobble")]
+            scrobble::track_started();
             if let Ok(mut status) = status.lock() {
                 status.set_elapsed_milli_seconds(0);
                 status.set_elapsed_seconds(0);
-                let msg = status.make_status_message(StatusCode::TrackStarted);
+                let msg = clock.status_message(&mut status, StatusCode::TrackStarted);
                 slim_tx_in.send(msg).ok();
             }
         }
 
-        #[cfg(not(feature = "notify"))]
-        PlayerMsg::Decoder((decoder, stream_params)) => {
-            output.enqueue_new_stream(decoder, stream_in.clone(), stream_params, device)
+        // Started again from the beginning on the output made next
+        PlayerMsg::Decoder(_) if output.is_lost() => {
+            info!("Holding back the stream until the audio system is back");
         }
 
-        #[cfg(feature = "notify")]
-        PlayerMsg::Decoder((mut decoder, stream_params)) => {
-            if let Some(metadata) = decoder.metadata() {
+        PlayerMsg::Decoder(decoder_params) => {
+            let (decoder, stream_params) = *decoder_params;
+            #[cfg(any(feature = "notify", feature = "scrobble"))]
+            let mut decoder = decoder;
+            #[cfg(any(feature = "notify", feature = "scrobble"))]
+            let info = decoder.take_info();
+
+            #[cfg(feature = "scrobble")]
+            scrobble::queue(
+                info.as_ref()
+                    .and_then(|info| Track::from_info(info, decoder.duration())),
+            );
+
+            #[cfg(feature = "notify")]
+            if let Some(info) = info {
                 if !quiet {
-                    notify(metadata);
+                    notify(info);
+                }
+            }
+
+            // Often why the player is quieter than expected
+            if let Ok(volume) = stream_params.volume.lock() {
+                if volume.is_attenuating() {
+                    info!("Applying software volume: {volume}");
                 }
             }
-            output.enqueue_new_stream(decoder, stream_in.clone(), stream_params, device)
+
+            output.enqueue_new_stream(decoder, stream_in.clone(), stream_params)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crossbeam::channel::bounded;
+
+    use super::*;
+
+    const WAIT: Duration = Duration::from_secs(1);
+
+    // What a handler acts on, kept between messages as the main loop does
+    struct Player {
+        output: AudioOutput,
+        server_ip: Ipv4Addr,
+        last_stream: Option<decode::StreamRequest>,
+        name: Arc<PlayerName>,
+        volume: Arc<Mutex<Volume>>,
+        status: Arc<Mutex<StatusData>>,
+        drift: DriftTracker,
+        skip: Arc<AtomicCell<Duration>>,
+        stats: Arc<Stats>,
+        clock: Clock,
+        slim_tx_in: Sender<ClientMessage>,
+        slim_tx_out: Receiver<ClientMessage>,
+        stream_in: Sender<PlayerMsg>,
+        stream_out: Receiver<PlayerMsg>,
+    }
+
+    impl Player {
+        fn new(playing: bool) -> Self {
+            let (slim_tx_in, slim_tx_out) = bounded(8);
+            let (stream_in, stream_out) = bounded(8);
+            Self {
+                output: AudioOutput::null(playing),
+                server_ip: Ipv4Addr::LOCALHOST,
+                last_stream: None,
+                name: Arc::new(PlayerName::fixed("Vibe")),
+                volume: Arc::new(Mutex::new(Volume::new())),
+                status: Arc::new(Mutex::new(StatusData::default())),
+                drift: DriftTracker::new(true),
+                skip: Arc::new(AtomicCell::new(Duration::ZERO)),
+                stats: Arc::new(Stats::new()),
+                clock: Clock::new(),
+                slim_tx_in,
+                slim_tx_out,
+                stream_in,
+                stream_out,
+            }
+        }
+
+        fn handler(&mut self) -> SlimHandler<'_> {
+            SlimHandler {
+                output: &mut self.output,
+                server_default_ip: &mut self.server_ip,
+                last_stream: &mut self.last_stream,
+                name: self.name.clone(),
+                slim_tx_in: self.slim_tx_in.clone(),
+                volume: self.volume.clone(),
+                status: self.status.clone(),
+                stream_in: self.stream_in.clone(),
+                skip: self.skip.clone(),
+                stats: self.stats.clone(),
+                drift: &mut self.drift,
+                clock: self.clock,
+                stream_options: Arc::new(decode::StreamOptions::default()),
+            }
+        }
+
+        // Act on the events from fetching a stream, as the main loop does,
+        // until its decoder is handed to the output
+        fn play_fetched(&mut self) {
+            loop {
+                let msg = self.stream_out.recv_timeout(WAIT).expect("a decoder");
+                let decoder = matches!(msg, PlayerMsg::Decoder(_));
+                assert!(!matches!(msg, PlayerMsg::NotSupported));
+                process_stream_msg(
+                    msg,
+                    self.status.clone(),
+                    self.slim_tx_in.clone(),
+                    &mut self.output,
+                    self.stream_in.clone(),
+                    self.clock,
+                    #[cfg(feature = "notify")]
+                    &true,
+                );
+                if decoder {
+                    return;
+                }
+            }
+        }
+
+        // The event codes of the status messages sent since last asked
+        fn sent(&self) -> Vec<String> {
+            self.slim_tx_out
+                .try_iter()
+                .filter_map(|msg| match msg {
+                    ClientMessage::Stat { event_code, .. } => Some(event_code),
+                    _ => None,
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn status_sends_timer() {
+        let mut player = Player::new(true);
+        player.handler().status(Duration::from_secs(5));
+        assert_eq!(player.sent(), ["STMt"]);
+        assert_eq!(player.stats.dropped_status(), 0);
+    }
+
+    #[test]
+    fn status_is_dropped_when_the_connection_is_busy() {
+        let mut player = Player::new(true);
+        while player.slim_tx_in.try_send(ClientMessage::Bye(0)).is_ok() {}
+        player.handler().status(Duration::from_secs(5));
+        assert_eq!(player.stats.dropped_status(), 1);
+        assert!(player.sent().is_empty());
+    }
+
+    #[test]
+    fn pause_tells_the_server() {
+        let mut player = Player::new(true);
+        player.handler().pause(Duration::ZERO);
+        assert!(player.output.is_paused());
+        assert_eq!(player.sent(), ["STMp"]);
+    }
+
+    #[test]
+    fn pause_with_nothing_playing_is_not_reported() {
+        let mut player = Player::new(false);
+        player.handler().pause(Duration::ZERO);
+        assert!(player.sent().is_empty());
+    }
+
+    #[test]
+    fn timed_pause_resumes_without_telling_the_server() {
+        let mut player = Player::new(true);
+        player.handler().pause(Duration::from_millis(10));
+        assert!(player.output.is_paused());
+        assert!(player.sent().is_empty());
+        assert!(matches!(
+            player.stream_out.recv_timeout(WAIT),
+            Ok(PlayerMsg::Unpause)
+        ));
+    }
+
+    #[test]
+    fn pause_during_a_hold_outlasts_it() {
+        let mut player = Player::new(true);
+        let hold = player.output.hold().expect("playing, so held");
+        assert!(!player.output.is_paused());
+        player.handler().pause(Duration::ZERO);
+        assert_eq!(player.sent(), ["STMp"]);
+        player.output.end_hold(hold);
+        assert!(player.output.is_paused());
+    }
+
+    #[test]
+    fn unpause_tells_the_server() {
+        let mut player = Player::new(true);
+        player.output.pause();
+        player.handler().unpause(Duration::ZERO);
+        assert!(!player.output.is_paused());
+        assert_eq!(player.sent(), ["STMr"]);
+    }
+
+    #[test]
+    fn unpause_with_nothing_playing_is_not_reported() {
+        let mut player = Player::new(false);
+        player.handler().unpause(Duration::ZERO);
+        assert!(player.sent().is_empty());
+    }
+
+    #[test]
+    fn late_unpause_skips_what_was_missed() {
+        let mut player = Player::new(true);
+        player.output.pause();
+        std::thread::sleep(Duration::from_millis(20));
+        // Asked to start at a jiffies value already passed
+        player.handler().unpause(Duration::from_millis(1));
+        assert!(player.skip.load() >= Duration::from_millis(10));
+        assert!(matches!(
+            player.stream_out.recv_timeout(WAIT),
+            Ok(PlayerMsg::Resume)
+        ));
+    }
+
+    #[test]
+    fn gain_sets_the_volume() {
+        let mut player = Player::new(true);
+        player.handler().gain(0.25, 1.0);
+        let volume = *player.volume.lock().unwrap();
+        assert_eq!(volume.gain(0, 2), 0.5);
+        assert_eq!(volume.gain(1, 2), 1.0);
+        assert!(player.sent().is_empty());
+    }
+
+    #[test]
+    fn gain_bursts_keep_the_last_gain() {
+        let (tx, rx) = bounded(8);
+        tx.send(Some(ServerMessage::Gain(0.5, 0.5))).unwrap();
+        tx.send(Some(ServerMessage::Gain(0.25, 0.25))).unwrap();
+        tx.send(Some(ServerMessage::Stop)).unwrap();
+        tx.send(Some(ServerMessage::Gain(1.0, 1.0))).unwrap();
+        let msgs = coalesce_gain(Some(ServerMessage::Gain(0.0, 0.0)), &rx);
+        assert!(matches!(
+            msgs[..],
+            [
+                Some(ServerMessage::Gain(l, r)),
+                Some(ServerMessage::Stop)
+            ] if l == 0.25 && r == 0.25
+        ));
+        // A gain after another message waits its turn
+        assert!(matches!(rx.try_recv(), Ok(Some(ServerMessage::Gain(..)))));
+
+        let msgs = coalesce_gain(Some(ServerMessage::Stop), &rx);
+        assert!(matches!(msgs[..], [Some(ServerMessage::Stop)]));
+    }
+
+    #[test]
+    fn stop_tells_the_server() {
+        let mut player = Player::new(true);
+        player.handler().stop();
+        assert!(!player.output.is_playing());
+        assert_eq!(player.sent(), ["STMf"]);
+    }
+
+    #[test]
+    fn flush_tells_the_server() {
+        let mut player = Player::new(true);
+        player.handler().flush();
+        assert!(!player.output.is_playing());
+        assert_eq!(player.sent(), ["STMf"]);
+    }
+
+    #[test]
+    fn skip_is_passed_to_the_output() {
+        let mut player = Player::new(true);
+        player.handler().skip(Duration::from_secs(3));
+        assert_eq!(player.skip.load(), Duration::from_secs(3));
+        assert!(player.sent().is_empty());
+    }
+
+    #[test]
+    fn enable_changes_nothing() {
+        let mut player = Player::new(true);
+        player.handler().enable(false, true);
+        assert!(player.output.is_playing());
+        assert!(player.sent().is_empty());
+        assert!(player.stream_out.try_recv().is_err());
+    }
+
+    #[test]
+    fn queryname_sends_the_name_last_set() {
+        let mut player = Player::new(true);
+        player.handler().queryname();
+        player.handler().setname("Kitchen".to_owned());
+        player.handler().queryname();
+        let names = player
+            .slim_tx_out
+            .try_iter()
+            .filter_map(|msg| match msg {
+                ClientMessage::Name(name) => Some(name),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["Vibe", "Kitchen"]);
+        assert_eq!(player.name.current(), "Kitchen");
+    }
+
+    #[test]
+    fn unhandled_commands_are_counted_by_kind() {
+        let mut player = Player::new(true);
+        player.handler().handle(ServerMessage::DisableDac);
+        player.handler().handle(ServerMessage::DisableDac);
+        let unhandled = UNHANDLED.lock().unwrap();
+        let seen = &unhandled.as_ref().unwrap().seen;
+        let (name, count) = &seen[&mem::discriminant(&ServerMessage::DisableDac)];
+        assert_eq!(name, "DisableDac");
+        assert_eq!(*count, 1);
+        assert!(player.output.is_playing());
+        assert!(player.sent().is_empty());
+    }
+
+    fn stream(port: u16, http_headers: Option<&str>) -> ServerMessage {
+        use slimproto::proto::{
+            Format, PcmChannels, PcmEndian, PcmSampleRate, PcmSampleSize, SpdifEnable, StreamFlags,
+            TransType,
+        };
+        ServerMessage::Stream {
+            autostart: AutoStart::Auto,
+            format: Format::Pcm,
+            pcmsamplesize: PcmSampleSize::SelfDescribing,
+            pcmsamplerate: PcmSampleRate::SelfDescribing,
+            pcmchannels: PcmChannels::SelfDescribing,
+            pcmendian: PcmEndian::SelfDescribing,
+            threshold: 1,
+            spdif_enable: SpdifEnable::Auto,
+            trans_period: Duration::ZERO,
+            trans_type: TransType::None,
+            flags: StreamFlags::empty(),
+            output_threshold: Duration::ZERO,
+            replay_gain: 0.0,
+            server_port: port,
+            server_ip: Ipv4Addr::LOCALHOST,
+            http_headers: http_headers.map(str::to_owned),
+        }
+    }
+
+    // A second of 16 bit stereo silence
+    fn wav() -> Vec<u8> {
+        let data = vec![0u8; 44100 * 4];
+        let mut wav = Vec::with_capacity(44 + data.len());
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        wav.extend_from_slice(&2u16.to_le_bytes()); // channels
+        wav.extend_from_slice(&44100u32.to_le_bytes());
+        wav.extend_from_slice(&(44100u32 * 4).to_le_bytes()); // bytes per second
+        wav.extend_from_slice(&4u16.to_le_bytes()); // bytes per frame
+        wav.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        wav.extend(data);
+        wav
+    }
+
+    #[test]
+    fn stream_without_headers_does_nothing() {
+        let mut player = Player::new(false);
+        player.handler().stream(stream(9000, None));
+        player.handler().stream(stream(9000, Some("")));
+        assert!(player.stream_out.recv_timeout(WAIT / 10).is_err());
+        assert!(player.sent().is_empty());
+    }
+
+    #[test]
+    fn stream_that_cannot_be_fetched_is_not_supported() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut player = Player::new(false);
+        player
+            .handler()
+            .stream(stream(port, Some("GET /stream.wav HTTP/1.0\r\n")));
+        assert!(matches!(
+            player.stream_out.recv_timeout(WAIT),
+            Ok(PlayerMsg::NotSupported)
+        ));
+    }
+
+    // Serve the WAV file to each of `connections` requests, giving back the
+    // header lines of each request
+    fn serve_wav(connections: usize) -> (u16, std::thread::JoinHandle<Vec<Vec<String>>>) {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for _ in 0..connections {
+                let (mut socket, _) = listener.accept().unwrap();
+                let request = BufReader::new(socket.try_clone().unwrap())
+                    .lines()
+                    .map_while(Result::ok)
+                    .take_while(|line| !line.is_empty())
+                    .collect::<Vec<_>>();
+                socket.write_all(&wav()).unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+        (port, server)
+    }
+
+    #[test]
+    fn stream_is_fetched_and_decoded() {
+        let (port, server) = serve_wav(1);
+        let mut player = Player::new(false);
+        player
+            .handler()
+            .stream(stream(port, Some("GET /stream.wav HTTP/1.0\r\n")));
+        assert!(matches!(
+            player.stream_out.recv_timeout(WAIT),
+            Ok(PlayerMsg::Connected)
+        ));
+        let decoder = std::iter::from_fn(|| player.stream_out.recv_timeout(WAIT).ok())
+            .find_map(|msg| match msg {
+                PlayerMsg::Decoder(decoder_params) => Some(decoder_params),
+                PlayerMsg::NotSupported => panic!("the stream is valid"),
+                _ => None,
+            })
+            .unwrap();
+        let (_, stream_params) = *decoder;
+        assert!(matches!(stream_params.autostart, AutoStart::Auto));
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0][0], "GET /stream.wav HTTP/1.0");
+        assert!(requests[0].contains(&format!("Host: 127.0.0.1:{port}")));
+    }
+
+    #[test]
+    fn stop_and_flush_forget_the_stream() {
+        let mut player = Player::new(false);
+        player
+            .handler()
+            .stream(stream(9, Some("GET /stream.wav HTTP/1.0\r\n")));
+        assert!(player.last_stream.is_some());
+        player.handler().stop();
+        assert!(player.last_stream.is_none());
+
+        player
+            .handler()
+            .stream(stream(9, Some("GET /stream.wav HTTP/1.0\r\n")));
+        player.handler().flush();
+        assert!(player.last_stream.is_none());
+    }
+
+    #[test]
+    fn stream_starts_again_on_a_new_output() {
+        let (port, server) = serve_wav(2);
+        let mut player = Player::new(false);
+        // The strm arrives while there is no audio system to play it on
+        player.output.lose();
+        player
+            .handler()
+            .stream(stream(port, Some("GET /stream.wav HTTP/1.0\r\n")));
+        player.play_fetched();
+        assert!(!player.output.is_playing());
+
+        // Then one is back, with no further command from the server
+        player.handler().replace_output(AudioOutput::null(false));
+        player.play_fetched();
+        assert!(player.output.is_playing());
+        assert!(!player.output.is_paused());
+        assert!(!player.sent().contains(&"STMn".to_owned()));
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0], requests[1]);
+    }
+
+    #[test]
+    fn paused_stream_starts_again_paused() {
+        let (port, server) = serve_wav(2);
+        let mut player = Player::new(false);
+        player
+            .handler()
+            .stream(stream(port, Some("GET /stream.wav HTTP/1.0\r\n")));
+        player.play_fetched();
+        player.handler().pause(Duration::ZERO);
+        assert!(player.output.is_paused());
+
+        player.output.lose();
+        player.handler().replace_output(AudioOutput::null(false));
+        player.play_fetched();
+        assert!(player.output.is_playing());
+        assert!(player.output.is_paused());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn lost_output_with_no_stream_has_the_server_move_on() {
+        let mut player = Player::new(true);
+        player.handler().replace_output(AudioOutput::null(false));
+        assert_eq!(player.sent(), ["STMn"]);
+
+        let mut player = Player::new(false);
+        player.handler().replace_output(AudioOutput::null(false));
+        assert!(player.sent().is_empty());
+        assert!(player.stream_out.try_recv().is_err());
+    }
+
+    #[test]
+    fn stalls_are_coalesced_between_other_events() {
+        let stats = Stats::new();
+        let (tx, rx) = bounded(8);
+        tx.send(PlayerMsg::StreamStalled).unwrap();
+        tx.send(PlayerMsg::StreamStalled).unwrap();
+        tx.send(PlayerMsg::TrackStarted).unwrap();
+        tx.send(PlayerMsg::StreamStalled).unwrap();
+        let msgs = coalesce_events(PlayerMsg::StreamStalled, &rx, &stats);
+        assert!(matches!(
+            msgs[..],
+            [PlayerMsg::StreamStalled, PlayerMsg::TrackStarted]
+        ));
+        assert_eq!(stats.coalesced_events(), 2);
+        // A stall after another event waits its turn
+        assert!(matches!(rx.try_recv(), Ok(PlayerMsg::StreamStalled)));
+
+        let msgs = coalesce_events(PlayerMsg::Drained, &rx, &stats);
+        assert!(matches!(msgs[..], [PlayerMsg::Drained]));
+    }
+
+    #[test]
+    fn track_change_survives_a_flood_of_stalls() {
+        use slimproto::proto::{Format, PcmChannels, PcmSampleRate};
+        use symphonia::core::io::MediaSourceStream;
+
+        use crate::audio_out::WriteMarker;
+
+        const STALLS: usize = 10_000;
+
+        let mss = MediaSourceStream::new(Box::new(std::io::Cursor::new(wav())), Default::default());
+        let decoder = decode::Decoder::try_new(
+            mss,
+            &Format::Pcm,
+            &PcmSampleRate::SelfDescribing,
+            &PcmChannels::SelfDescribing,
+            &decode::DecodeOptions::default(),
+        )
+        .unwrap();
+        let stream_params =
+            StreamParams::local(Arc::new(Mutex::new(Volume::new())), WriteMarker::new());
+
+        // The end of one track and the start of the next, while a stalling
+        // stream reports from another thread
+        let (stream_in, stream_out) = crossbeam::channel::unbounded();
+        let flood = {
+            let stream_in = stream_in.clone();
+            std::thread::spawn(move || {
+                for _ in 0..STALLS {
+                    stream_in.send(PlayerMsg::StreamStalled).unwrap();
+                }
+            })
+        };
+        let transition = std::thread::spawn(move || {
+            let events = [
+                PlayerMsg::EndOfDecode,
+                PlayerMsg::Decoder(Box::new((decoder, stream_params))),
+                PlayerMsg::Drained,
+                PlayerMsg::TrackStarted,
+            ];
+            for event in events {
+                stream_in.send(event).unwrap();
+                std::thread::yield_now();
+            }
+        });
+        flood.join().unwrap();
+        transition.join().unwrap();
+
+        let mut player = Player::new(false);
+        let (slim_tx_in, slim_tx_out) = crossbeam::channel::unbounded();
+        while let Ok(msg) = stream_out.try_recv() {
+            for msg in coalesce_events(msg, &stream_out, &player.stats) {
+                process_stream_msg(
+                    msg,
+                    player.status.clone(),
+                    slim_tx_in.clone(),
+                    &mut player.output,
+                    player.stream_in.clone(),
+                    player.clock,
+                    #[cfg(feature = "notify")]
+                    &true,
+                );
+            }
+        }
+
+        let sent = slim_tx_out
+            .try_iter()
+            .filter_map(|msg| match msg {
+                ClientMessage::Stat { event_code, .. } => Some(event_code),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let stalls = sent.iter().filter(|code| *code == "STMo").count();
+        let events = sent
+            .iter()
+            .filter(|code| *code != "STMo")
+            .collect::<Vec<_>>();
+        assert_eq!(events, ["STMd", "STMs"]);
+        assert!(player.output.is_playing());
+        // Only the stalls between the track events are left
+        assert!((1..=5).contains(&stalls));
+        assert_eq!(stalls + player.stats.coalesced_events(), STALLS);
+    }
+}
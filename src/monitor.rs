@@ -0,0 +1,167 @@
+//! Monitor mode, which connects to the server as a player but never opens
+//! an audio output, to keep watch on the server from a machine with no
+//! sound hardware.
+//!
+//! Status ticks are answered as if nothing were playing and every stream
+//! is refused at once. The server is reached afresh every minute to time
+//! how long a connection takes, and the result is logged and kept in the
+//! statistics, as is any long silence from the server.
+
+use std::{
+    net::{SocketAddrV4, TcpStream},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::{bounded, Receiver, Select, TrySendError};
+use log::{debug, info, warn};
+use slimproto::{
+    status::{StatusCode, StatusData},
+    ClientMessage, ServerMessage,
+};
+
+use crate::{
+    audio_out::Interrupts,
+    message::Clock,
+    player_name::PlayerName,
+    proto::{self, ConnectionHistory, ConnectionInfo},
+    stats::Stats,
+    threads,
+};
+
+const PROBE_EVERY: Duration = Duration::from_secs(60);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+// The server sends a status tick every few seconds while connected
+const QUIET_AFTER: Duration = Duration::from_secs(30);
+
+// Time a fresh connection to the server, without sending HELO so that no
+// second player appears
+fn probe(server: SocketAddrV4, stats: Arc<Stats>) {
+    threads::spawn(move || {
+        let start = Instant::now();
+        match TcpStream::connect_timeout(&server.into(), PROBE_TIMEOUT) {
+            Ok(_) => {
+                let took = start.elapsed();
+                info!("Server {server} reachable, connecting took {took:?}");
+                stats.set_server_connect(Some(took));
+            }
+            Err(e) => {
+                warn!("Server {server} unreachable: {e}");
+                stats.set_server_connect(None);
+            }
+        }
+    });
+}
+
+/// Connect to the server and answer it without playing anything, until a
+/// message arrives on `shutdown`. Reconnects whenever the server is lost.
+pub fn run(
+    name: Arc<PlayerName>,
+    settings: proto::Settings,
+    history: Arc<Mutex<ConnectionHistory>>,
+    conn_info: Arc<Mutex<ConnectionInfo>>,
+    stats: Arc<Stats>,
+    shutdown: Receiver<()>,
+) -> anyhow::Result<()> {
+    info!("Monitor mode, no audio output will be opened");
+    let clock = Clock::new();
+
+    loop {
+        let mut status = StatusData::default();
+        let (slim_tx_in, slim_tx_out) = bounded(32);
+        let (slim_rx_in, slim_rx_out) = bounded(8);
+        proto::run(
+            name.clone(),
+            settings.clone(),
+            slim_rx_in,
+            slim_tx_out,
+            history.clone(),
+            conn_info.clone(),
+            Interrupts::new(),
+        );
+
+        let mut last_tick = Instant::now();
+        let mut last_probe: Option<Instant> = None;
+        let mut quiet = false;
+        let mut select = Select::new();
+        let slim_idx = select.recv(&slim_rx_out);
+        let shutdown_idx = select.recv(&shutdown);
+
+        'session: loop {
+            let op = match select.select_timeout(QUIET_AFTER) {
+                Ok(op) => op,
+                Err(_) => {
+                    if !quiet && last_tick.elapsed() >= QUIET_AFTER {
+                        warn!("No status from the server for {:?}", last_tick.elapsed());
+                        quiet = true;
+                    }
+                    continue;
+                }
+            };
+
+            if op.index() == shutdown_idx {
+                op.recv(&shutdown).ok();
+                info!("Shutting down");
+                slim_tx_in.try_send(ClientMessage::Bye(0)).ok();
+                slim_tx_in.send(ClientMessage::Bye(1)).ok();
+                return Ok(());
+            }
+            if op.index() != slim_idx {
+                continue;
+            }
+
+            match op.recv(&slim_rx_out)? {
+                Some(ServerMessage::Status(ts)) => {
+                    if quiet {
+                        info!("Server is sending status again");
+                        quiet = false;
+                    }
+                    last_tick = Instant::now();
+                    status.set_timestamp(ts);
+                    let msg = clock.status_message(&mut status, StatusCode::Timer);
+                    if let Err(TrySendError::Full(_)) = slim_tx_in.try_send(msg) {
+                        let dropped = stats.add_dropped_status();
+                        debug!("Server connection busy, dropped timer status ({dropped} in total)");
+                    }
+
+                    if !matches!(last_probe, Some(at) if at.elapsed() < PROBE_EVERY) {
+                        let connected_to = conn_info.lock().ok().and_then(|info| info.server());
+                        if let Some(server) = connected_to {
+                            probe(server, stats.clone());
+                            last_probe = Some(Instant::now());
+                        }
+                    }
+                }
+
+                Some(ServerMessage::Stream { .. }) => {
+                    info!("Refusing stream, there is no audio output in monitor mode");
+                    let msg = clock.status_message(&mut status, StatusCode::NotSupported);
+                    slim_tx_in.send(msg).ok();
+                }
+
+                Some(ServerMessage::Stop | ServerMessage::Flush) => {
+                    let msg = clock.status_message(&mut status, StatusCode::Flushed);
+                    slim_tx_in.send(msg).ok();
+                }
+
+                Some(ServerMessage::Queryname) => {
+                    slim_tx_in.send(ClientMessage::Name(name.current())).ok();
+                }
+
+                Some(ServerMessage::Setname(new_name)) => {
+                    info!("Set name to {new_name}");
+                    name.set_from_server(new_name);
+                }
+
+                Some(msg) => debug!("Ignoring in monitor mode: {:?}", msg),
+
+                None => {
+                    info!("Lost contact with server, resetting");
+                    info!("Statistics: {stats}");
+                    slim_tx_in.send(ClientMessage::Bye(1)).ok();
+                    break 'session;
+                }
+            }
+        }
+    }
+}
@@ -1,73 +1,70 @@
-use std::{collections::HashMap, thread};
+//! Desktop notifications of the track now playing.
+//!
+//! Notifications are shown by a single worker. When tracks change faster
+//! than once every [`SETTLE`], e.g. while skipping through a playlist, only
+//! the latest is shown once the changes stop.
 
-use notify_rust::Notification;
-use symphonia::core::meta::{MetadataRevision, StandardTagKey, Value};
+use std::{
+    sync::OnceLock,
+    thread,
+    time::{Duration, Instant},
+};
 
-pub fn notify(metadata: MetadataRevision) {
-    thread::spawn(move || {
-        let notify_tags = metadata.tags().iter().filter(|tag| tag.is_known()).fold(
-            HashMap::new(),
-            |mut tags, tag| {
-                match tag.std_key {
-                    Some(StandardTagKey::Artist) => {
-                        tags.entry("artist").or_insert_with(|| tag.value.to_owned());
-                    }
+use crossbeam::channel::{unbounded, Sender};
+use notify_rust::Notification;
 
-                    Some(StandardTagKey::AlbumArtist) => {
-                        tags.insert("artist", tag.value.to_owned());
-                    }
+use crate::track_info::{escape_markup, TrackInfo};
 
-                    Some(StandardTagKey::Album) => {
-                        tags.insert("album", tag.value.to_owned());
-                    }
+const SETTLE: Duration = Duration::from_secs(1);
 
-                    Some(StandardTagKey::TrackTitle) => {
-                        tags.insert("track", tag.value.to_owned());
-                    }
+static WORKER: OnceLock<Sender<TrackInfo>> = OnceLock::new();
 
-                    Some(StandardTagKey::Date) => {
-                        let year: String = tag
-                            .value
-                            .to_string()
-                            .as_str()
-                            .split("-")
-                            .filter(|s| s.len() == 4)
-                            .take(1)
-                            .collect();
-                        tags.insert("year", Value::String(year));
-                    }
+pub fn notify(info: TrackInfo) {
+    WORKER.get_or_init(start_worker).send(info).ok();
+}
 
-                    _ => {}
+fn start_worker() -> Sender<TrackInfo> {
+    let (info_tx, info_rx) = unbounded();
+    thread::spawn(move || {
+        let mut last_shown: Option<Instant> = None;
+        while let Ok(mut info) = info_rx.recv() {
+            if last_shown.is_some_and(|shown| shown.elapsed() < SETTLE) {
+                while let Ok(newer) = info_rx.recv_timeout(SETTLE) {
+                    info = newer;
                 }
-                tags
-            },
-        );
-
-        let mut notification = String::new();
-        if let Some(track) = notify_tags.get("track") {
-            notification.push_str(format!("<b>{}</b>", track).as_str());
+            }
+            show(&info);
+            last_shown = Some(Instant::now());
         }
+    });
+    info_tx
+}
 
-        if let Some(artist) = notify_tags.get("artist") {
-            notification.push_str(format!(" by <b>{}</b>", artist).as_str());
-        }
+fn show(info: &TrackInfo) {
+    let mut notification = String::new();
+    if let Some(ref track) = info.title {
+        notification.push_str(format!("<b>{}</b>", escape_markup(track)).as_str());
+    }
 
-        if let Some(album) = notify_tags.get("album") {
-            notification.push_str(format!(" from <b>{}</b>", album).as_str());
-        }
+    if let Some(artist) = info.album_artist() {
+        notification.push_str(format!(" by <b>{}</b>", escape_markup(artist)).as_str());
+    }
 
-        if let Some(date) = notify_tags.get("year") {
-            notification.push_str(format!(" ({})", date).as_str());
-        }
+    if let Some(ref album) = info.album {
+        notification.push_str(format!(" from <b>{}</b>", escape_markup(album)).as_str());
+    }
 
-        if notification.len() > 0 {
-            Notification::new()
-                .summary("Now playing")
-                .body(&notification)
-                .icon("emblem-music-symbolic")
-                .timeout(6000)
-                .show()
-                .ok();
-        }
-    });
+    if let Some(ref date) = info.year {
+        notification.push_str(format!(" ({})", date).as_str());
+    }
+
+    if notification.len() > 0 {
+        Notification::new()
+            .summary("Now playing")
+            .body(&notification)
+            .icon("emblem-music-symbolic")
+            .timeout(6000)
+            .show()
+            .ok();
+    }
 }
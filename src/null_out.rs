@@ -0,0 +1,78 @@
+//! An output that plays nothing, so that what is asked of an output can be
+//! tested without an audio system.
+
+use std::time::Duration;
+
+use crossbeam::channel::Sender;
+use slimproto::proto::AutoStart;
+
+use crate::{decode::Decoder, message::PlayerMsg, StreamParams};
+
+pub struct AudioOutput {
+    playing: bool,
+    paused: bool,
+    lost: bool,
+}
+
+impl AudioOutput {
+    /// An output with a stream on it, playing, or none.
+    pub fn new(playing: bool) -> Self {
+        Self {
+            playing,
+            paused: false,
+            lost: false,
+        }
+    }
+
+    pub fn enqueue_new_stream(
+        &mut self,
+        _decoder: Decoder,
+        _stream_in: Sender<PlayerMsg>,
+        stream_params: StreamParams,
+    ) {
+        if !self.playing {
+            self.playing = true;
+            self.paused = stream_params.autostart == AutoStart::None;
+        }
+    }
+
+    pub fn unpause(&mut self) -> bool {
+        if self.playing {
+            self.paused = false;
+        }
+        self.playing
+    }
+
+    pub fn pause(&mut self) -> bool {
+        if self.playing {
+            self.paused = true;
+        }
+        self.playing
+    }
+
+    pub fn stop(&mut self) {
+        self.playing = false;
+        self.paused = false;
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Act as if the audio system had gone away.
+    pub fn lose(&mut self) {
+        self.lost = true;
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.lost
+    }
+
+    pub fn get_dur(&self) -> Duration {
+        Duration::ZERO
+    }
+}
@@ -0,0 +1,52 @@
+//! A fixed output format, for an output that takes only one, e.g. a
+//! digital crossover that only accepts 48 kHz 24 bit stereo.
+//!
+//! Every stream is converted to the rate, sample size and channels chosen
+//! as it is decoded, after everything else is applied, so the output sees
+//! one format from track to track and can keep its device open in it.
+//! Rates are converted by the `resample` module, channels are mixed down or
+//! the one channel of a mono stream is played on both sides.
+
+use std::fmt;
+
+use symphonia::core::audio::Channels;
+
+use crate::decode::AudioFormat;
+
+/// A sample rate, sample size and number of channels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutputFormat {
+    pub rate: u32,
+    pub bits: u8,
+    pub channels: u8,
+}
+
+impl OutputFormat {
+    /// The sample sizes an output can be locked to.
+    pub const BITS: [u8; 3] = [16, 24, 32];
+    /// The channel counts an output can be locked to.
+    pub const CHANNELS: [u8; 2] = [1, 2];
+
+    // Which speaker each channel is for
+    pub(crate) fn layout(&self) -> Channels {
+        match self.channels {
+            1 => Channels::FRONT_LEFT,
+            _ => Channels::FRONT_LEFT | Channels::FRONT_RIGHT,
+        }
+    }
+
+    // How the samples are handed to the output
+    pub(crate) fn audio_format(&self) -> AudioFormat {
+        match self.bits {
+            16 => AudioFormat::I16,
+            24 => AudioFormat::I24,
+            _ => AudioFormat::I32,
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} Hz {} bit {} ch", self.rate, self.bits, self.channels)
+    }
+}
@@ -0,0 +1,112 @@
+//! Where files kept between runs are stored.
+//!
+//! State goes in `$XDG_STATE_HOME/vibe` (`~/.local/state/vibe`), caches in
+//! `$XDG_CACHE_HOME/vibe` (`~/.cache/vibe`) and configuration is read from
+//! `$XDG_CONFIG_HOME/vibe` (`~/.config/vibe`). Setting a state directory
+//! moves both state and caches beneath it, for systems where the home
+//! directory is read-only.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+};
+
+use anyhow::{bail, Context};
+use log::warn;
+
+static STATE_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Use `dir` for all persisted files instead of the XDG directories.
+/// Only the first call has any effect.
+pub fn set_state_dir(dir: PathBuf) {
+    STATE_DIR.set(dir).ok();
+}
+
+/// The directory for persisted state, created if needed.
+pub fn state_dir() -> anyhow::Result<PathBuf> {
+    match STATE_DIR.get() {
+        Some(dir) => make_dir(dir.to_owned()),
+        None => make_dir(xdg_dir("XDG_STATE_HOME", ".local/state")?),
+    }
+}
+
+/// The directory for caches, created if needed.
+pub fn cache_dir() -> anyhow::Result<PathBuf> {
+    match STATE_DIR.get() {
+        Some(dir) => make_dir(dir.join("cache")),
+        None => make_dir(xdg_dir("XDG_CACHE_HOME", ".cache")?),
+    }
+}
+
+/// The directory configuration is read from. This is not created.
+pub fn config_dir() -> anyhow::Result<PathBuf> {
+    xdg_dir("XDG_CONFIG_HOME", ".config")
+}
+
+// The XDG specification says relative paths in these variables are invalid
+// and should be ignored
+fn xdg_dir(var: &str, home_fallback: &str) -> anyhow::Result<PathBuf> {
+    let base = match env::var_os(var).map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir,
+        _ => match env::var_os("HOME") {
+            Some(home) if Path::new(&home).is_absolute() => Path::new(&home).join(home_fallback),
+            _ => bail!("Neither {var} nor HOME is set, use --state-dir"),
+        },
+    };
+    Ok(base.join("vibe"))
+}
+
+/// Check that files can be written in `dir`, by writing one and removing
+/// it again.
+pub fn check_writable(dir: &Path) -> anyhow::Result<()> {
+    let probe = dir.join(".vibe-write-check");
+    fs::write(&probe, b"").with_context(|| format!("Unable to write in {}", dir.display()))?;
+    fs::remove_file(&probe).ok();
+    Ok(())
+}
+
+/// One kind of file written while playing, which is given up on for the
+/// rest of the run after its first failed write. A full or read-only
+/// filesystem then costs one warning for each kind, and never playback.
+pub struct Persisted {
+    what: &'static str,
+    disabled: AtomicBool,
+}
+
+impl Persisted {
+    pub const fn new(what: &'static str) -> Self {
+        Self {
+            what,
+            disabled: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.disabled.load(Ordering::Relaxed)
+    }
+
+    /// Make a write, unless an earlier one failed.
+    pub fn write(&self, write: impl FnOnce() -> anyhow::Result<()>) {
+        if self.is_disabled() {
+            return;
+        }
+        if let Err(e) = write() {
+            if !self.disabled.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "Unable to write {}, giving up until restart: {e:#}",
+                    self.what
+                );
+            }
+        }
+    }
+}
+
+fn make_dir(dir: PathBuf) -> anyhow::Result<PathBuf> {
+    fs::create_dir_all(&dir)
+        .with_context(|| format!("Unable to create directory {}", dir.display()))?;
+    Ok(dir)
+}
@@ -0,0 +1,115 @@
+//! The MAC address the player reports to the server.
+//!
+//! The server keys each player's settings, such as its alarms and audio
+//! settings, off the MAC address sent in HELO. The machine's own address
+//! is shared by every player on the machine and is new on each run in most
+//! containers, so unless one is given a MAC address is made from the host
+//! name and the player name given on the command line. It is kept in the
+//! state directory, against that player name, so that the player keeps its
+//! identity when the host name changes.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use log::info;
+use mac_address::MacAddress;
+
+use crate::paths::{self, Persisted};
+
+const MAC_FILE: &str = "mac";
+
+static MAC_WRITES: Persisted = Persisted::new("the player MAC address");
+
+/// The MAC address kept for the player given `name` on the command line,
+/// or for a player given no name, making and keeping one if there is none.
+pub fn kept_or_made(name: Option<&str>) -> MacAddress {
+    let name = name.unwrap_or_default();
+    if let Some(mac) = load(name) {
+        return mac;
+    }
+
+    let hostname = hostname::get()
+        .ok()
+        .and_then(|s| s.into_string().ok())
+        .unwrap_or_default();
+    let mac = made_from(&hostname, name);
+    info!("Using the MAC address {mac}, made from the host and player names");
+    MAC_WRITES.write(|| {
+        let path = mac_file()?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| path.display().to_string())?;
+        writeln!(file, "{mac} {name}").with_context(|| path.display().to_string())
+    });
+    mac
+}
+
+/// Whether `mac` can identify a player, as a unicast address that is not
+/// all zeros.
+pub fn is_usable(mac: MacAddress) -> bool {
+    let bytes = mac.bytes();
+    bytes[0] & 0x01 == 0 && bytes != [0; 6]
+}
+
+// An FNV-1a hash of the two names, as a locally administered unicast
+// address so that it cannot clash with a real interface's
+fn made_from(hostname: &str, name: &str) -> MacAddress {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in hostname.bytes().chain([0]).chain(name.bytes()) {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    let mut bytes = [0u8; 6];
+    bytes.copy_from_slice(&hash.to_be_bytes()[2..]);
+    bytes[0] = (bytes[0] | 0x02) & !0x01;
+    MacAddress::new(bytes)
+}
+
+fn mac_file() -> anyhow::Result<PathBuf> {
+    Ok(paths::state_dir()?.join(MAC_FILE))
+}
+
+// Each line is a MAC address then the player name it was made for
+fn load(name: &str) -> Option<MacAddress> {
+    let kept = fs::read_to_string(mac_file().ok()?).ok()?;
+    kept.lines().find_map(|line| match line.split_once(' ') {
+        Some((mac, kept)) if kept == name => mac.parse().ok(),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn made_addresses_are_local_unicast() {
+        let mac = made_from("host", "Kitchen");
+        assert!(is_usable(mac));
+        assert_eq!(mac.bytes()[0] & 0x02, 0x02);
+        assert_eq!(mac, made_from("host", "Kitchen"));
+    }
+
+    #[test]
+    fn made_addresses_differ_by_name_and_host() {
+        let kitchen = made_from("host", "Kitchen");
+        assert_ne!(kitchen, made_from("host", "Lounge"));
+        assert_ne!(kitchen, made_from("other", "Kitchen"));
+        assert_ne!(kitchen, made_from("host", ""));
+        // The separator keeps the two names apart
+        assert_ne!(made_from("ab", "c"), made_from("a", "bc"));
+    }
+
+    #[test]
+    fn unusable_addresses() {
+        assert!(!is_usable(MacAddress::new([0; 6])));
+        assert!(!is_usable(MacAddress::new([0x01, 0, 0x5e, 0, 0, 1])));
+        assert!(is_usable(MacAddress::new([0x02, 0, 0, 0, 0, 1])));
+    }
+}
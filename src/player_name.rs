@@ -0,0 +1,101 @@
+//! The name the player presents to the server.
+//!
+//! At startup the name is the one given on the command line, else the last
+//! name the server set, else the default of `Vibe@hostname`. After that the
+//! latest name set wins. A name set by the server is kept in the state
+//! directory so that it survives restarts, and as one `PlayerName` is
+//! shared by every connection it also survives reconnects.
+
+use std::{fs, path::PathBuf, sync::RwLock};
+
+use anyhow::Context;
+use log::info;
+
+use crate::paths::{self, Persisted};
+
+const DEFAULT_NAME: &str = "Vibe";
+const NAME_FILE: &str = "name";
+
+static NAME_WRITES: Persisted = Persisted::new("the player name");
+
+pub struct PlayerName {
+    current: RwLock<String>,
+    kept: bool,
+}
+
+impl PlayerName {
+    /// The last name the server set, or the default.
+    pub fn new() -> Self {
+        let name = match load() {
+            Some(name) => {
+                info!("Using the name last set by the server: {name}");
+                name
+            }
+            None => default_name(),
+        };
+        Self {
+            current: RwLock::new(name),
+            kept: true,
+        }
+    }
+
+    /// A name that is never loaded or kept, leaving the state directory
+    /// alone.
+    #[cfg(test)]
+    pub(crate) fn fixed(name: &str) -> Self {
+        Self {
+            current: RwLock::new(name.to_owned()),
+            kept: false,
+        }
+    }
+
+    pub fn current(&self) -> String {
+        self.current
+            .read()
+            .map(|name| name.to_owned())
+            .unwrap_or_else(|_| default_name())
+    }
+
+    /// Use a name given by the user, which is not kept.
+    pub fn set_from_cli(&self, name: String) {
+        if let Ok(mut current) = self.current.write() {
+            *current = name;
+        }
+    }
+
+    /// Use a name set on the server, keeping it for next time.
+    pub fn set_from_server(&self, name: String) {
+        if self.kept {
+            NAME_WRITES.write(|| {
+                let path = name_file()?;
+                fs::write(&path, &name).with_context(|| path.display().to_string())
+            });
+        }
+        if let Ok(mut current) = self.current.write() {
+            *current = name;
+        }
+    }
+}
+
+impl Default for PlayerName {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn default_name() -> String {
+    match hostname::get().map(|s| s.into_string()) {
+        Ok(Ok(hostname)) => format!("{DEFAULT_NAME}@{hostname}"),
+        _ => DEFAULT_NAME.to_owned(),
+    }
+}
+
+fn name_file() -> anyhow::Result<PathBuf> {
+    Ok(paths::state_dir()?.join(NAME_FILE))
+}
+
+fn load() -> Option<String> {
+    let name = fs::read_to_string(name_file().ok()?).ok()?;
+    let name = name.trim();
+    (!name.is_empty()).then(|| name.to_owned())
+}
@@ -1,27 +1,530 @@
 use std::{
-    net::{Ipv4Addr, SocketAddrV4},
-    sync::{Arc, RwLock},
+    collections::VecDeque,
+    fmt,
+    io::{self, BufReader, BufWriter, ErrorKind},
+    mem,
+    net::{Ipv4Addr, SocketAddrV4, TcpStream},
+    os::fd::{AsRawFd, RawFd},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use crossbeam::channel::{Receiver, Sender};
-use log::{error, info};
+use log::{debug, info, warn};
+use mac_address::{get_mac_address, MacAddress};
 use slimproto::{
-    self, discovery::discover, proto::Server, Capabilities, Capability, ClientMessage,
-    FramedReader, FramedWriter, ServerMessage,
+    self, codec::SlimCodec, proto::Server, Capability, ClientMessage, FramedRead, FramedReader,
+    FramedWrite, FramedWriter, ServerMessage,
 };
 
+use crate::{
+    audio_out::{Interrupt, Interrupts},
+    decode,
+    discovery::{self, discover},
+    player_name::PlayerName,
+    threads,
+};
+
+// More server closes than this within the window suggests that another
+// player with the same identity keeps taking our place
+const DUPLICATE_CLOSES: usize = 3;
+const DUPLICATE_WINDOW: Duration = Duration::from_secs(60);
+
+// The wait between attempts to find or connect to the server doubles
+// from the first to the last
+const RETRY_FIRST: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(32);
+// How often the watchdog looks for silence from the server
+const WATCH_EVERY: Duration = Duration::from_secs(1);
+// As slimproto sets for writes on the connection it makes
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+// Once a connection is idle, keepalive probes go this far apart, and the
+// system drops it after this many go unanswered
+#[cfg(target_os = "linux")]
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+#[cfg(target_os = "linux")]
+const KEEPALIVE_PROBES: libc::c_int = 3;
+
+// Doubling waits between attempts, until one succeeds
+struct Backoff {
+    next: Duration,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Self { next: RETRY_FIRST }
+    }
+
+    // Wait before the next attempt, returning false if shutdown started
+    fn wait(&mut self) -> bool {
+        let wait = self.next;
+        self.next = (self.next * 2).min(RETRY_MAX);
+        info!("Trying again in {wait:?}");
+        threads::sleep(wait)
+    }
+
+    fn reset(&mut self) {
+        self.next = RETRY_FIRST;
+    }
+}
+
+// Look for a server until one answers, or until shutdown starts. Nothing
+// answering is expected at boot, before the network or the server is up,
+// so it is reported once rather than on every attempt.
+fn find_server(backoff: &mut Backoff, settings: &Settings) -> Option<Server> {
+    info!("Looking for a server");
+    let mut waiting = false;
+    loop {
+        match discover(
+            &settings.discover_addresses,
+            Some(settings.discover_timeout),
+        ) {
+            Ok(Some(server)) => {
+                if waiting {
+                    info!("Found server at {}", server.socket);
+                }
+                return Some(server);
+            }
+            Ok(None) if !waiting => info!("No server found yet, waiting for a server"),
+            Ok(None) => debug!("Still no server found"),
+            // The network may not be up yet
+            Err(e) => warn!("Unable to look for a server: {e}"),
+        }
+        waiting = true;
+        if !backoff.wait() {
+            return None;
+        }
+    }
+}
+
+/// How recent connections to the server have ended, kept across
+/// reconnects to spot patterns.
+#[derive(Default)]
+pub struct ConnectionHistory {
+    server_closes: VecDeque<Instant>,
+}
+
+impl ConnectionHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that the server closed the connection, returning how many
+    /// times it has done so within the last minute.
+    pub fn add_server_close(&mut self) -> usize {
+        let now = Instant::now();
+        while let Some(then) = self.server_closes.front() {
+            if now.duration_since(*then) > DUPLICATE_WINDOW {
+                self.server_closes.pop_front();
+            } else {
+                break;
+            }
+        }
+        self.server_closes.push_back(now);
+        self.server_closes.len()
+    }
+}
+
+/// Who the player is connected to and what it presented, kept up to date
+/// across reconnects and server switches for diagnosing problems.
+#[derive(Default)]
+pub struct ConnectionInfo {
+    server: Option<SocketAddrV4>,
+    // The server's web interface, when known
+    http_port: Option<u16>,
+    connected_at: Option<Instant>,
+    name: Option<Arc<PlayerName>>,
+    capabilities: Vec<String>,
+    // A move asked for by the server that is not yet complete
+    switch: Option<Switch>,
+    // The server left in the last move to complete
+    switched_from: Option<SocketAddrV4>,
+}
+
+/// A move to another server asked for by the server, from Serv.
+#[derive(Clone, Debug)]
+pub struct Switch {
+    pub from: SocketAddrV4,
+    pub to: Ipv4Addr,
+    pub sync_group: Option<String>,
+    /// How many times connecting to the new server has failed.
+    pub failures: u32,
+}
+
+impl fmt::Display for Switch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "from server {} to {}, sync group {}",
+            self.from,
+            self.to,
+            self.sync_group.as_deref().unwrap_or("none")
+        )
+    }
+}
+
+impl ConnectionInfo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns the move this completes, if any
+    fn connected(
+        &mut self,
+        server: SocketAddrV4,
+        name: Arc<PlayerName>,
+        capabilities: Vec<String>,
+    ) -> Option<Switch> {
+        self.server = Some(server);
+        self.connected_at = Some(Instant::now());
+        self.name = Some(name);
+        self.capabilities = capabilities;
+        let switch = self
+            .switch
+            .take()
+            .filter(|switch| *server.ip() == switch.to)?;
+        self.switched_from = Some(switch.from);
+        Some(switch)
+    }
+
+    fn disconnected(&mut self) {
+        self.server = None;
+        self.connected_at = None;
+    }
+
+    // The server has asked the player to move. The old server is dropped
+    // as the move is recorded, so no one sees it as current meanwhile.
+    fn switching(&mut self, to: Ipv4Addr, sync_group: Option<String>) -> Option<&Switch> {
+        let from = self.server?;
+        self.disconnected();
+        self.switch = Some(Switch {
+            from,
+            to,
+            sync_group,
+            failures: 0,
+        });
+        self.switch.as_ref()
+    }
+
+    // Returns the move that has failed, if any
+    fn switch_failed(&mut self) -> Option<&Switch> {
+        let switch = self.switch.as_mut()?;
+        switch.failures += 1;
+        Some(switch)
+    }
+
+    /// The move to another server under way, if any.
+    pub fn switch(&self) -> Option<&Switch> {
+        self.switch.as_ref()
+    }
+
+    pub fn server(&self) -> Option<SocketAddrV4> {
+        self.server
+    }
+
+    /// Use this port for the server's web interface, e.g. when it was
+    /// given in a URL.
+    pub fn set_http_port(&mut self, port: u16) {
+        self.http_port = Some(port);
+    }
+
+    /// The port of the server's web interface, the default unless another
+    /// was given.
+    pub fn http_port(&self) -> u16 {
+        self.http_port.unwrap_or(DEFAULT_HTTP_PORT)
+    }
+}
+
+impl fmt::Display for ConnectionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.server, self.connected_at) {
+            (Some(server), Some(at)) => write!(
+                f,
+                "server {server}, connected for {}s",
+                at.elapsed().as_secs()
+            )?,
+            _ => write!(f, "not connected")?,
+        }
+        if let Some(ref switch) = self.switch {
+            write!(f, ", moving {switch}")?;
+        } else if let Some(from) = self.switched_from {
+            write!(f, ", moved from server {from}")?;
+        }
+        write!(f, ", web port {}", self.http_port())?;
+        if let Some(ref name) = self.name {
+            write!(f, ", name {}", name.current())?;
+        }
+        write!(f, ", capabilities: {}", self.capabilities.join(", "))
+    }
+}
+
+/// The port of the server's web interface unless another is given.
+pub const DEFAULT_HTTP_PORT: u16 = 9000;
+
+/// The model name presented to the server when none is chosen.
+pub const DEFAULT_MODELNAME: &str = "Vibe";
+
+/// The highest sample rate advertised unless another is chosen.
+pub const DEFAULT_MAX_SAMPLE_RATE: u32 = 192_000;
+
+/// The server is taken as lost after this long without a message from it,
+/// unless another time is chosen.
+pub const DEFAULT_KEEPALIVE: Duration = Duration::from_secs(35);
+
+/// How the player finds the server, presents itself and keeps in touch.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// The servers to connect to, each tried in turn, or none to find one
+    /// by discovery.
+    pub servers: Vec<SocketAddrV4>,
+    /// The model name, from which the model is made.
+    pub modelname: String,
+    /// The firmware version, Vibe's version by default.
+    pub firmware: String,
+    /// The MAC address the server knows the player by, or `None` for the
+    /// machine's own, which every player on the machine shares.
+    pub mac: Option<MacAddress>,
+    /// The highest sample rate the player takes, so that the server
+    /// resamples anything higher, e.g. for a DAC that stops short of the
+    /// default.
+    pub max_sample_rate: u32,
+    /// Take the server as lost after this long without a message from it.
+    /// The server asks for a status every few seconds, playing or not, so
+    /// a long silence means a connection dropped without a close, e.g.
+    /// over Wi-Fi or a VPN, which reading alone may not notice for
+    /// minutes. It is also how long the connection is idle before TCP
+    /// keepalive probes it. Zero turns the watch off and leaves TCP
+    /// keepalive to the system's timing, which may take hours.
+    pub keepalive: Duration,
+    /// Send discovery requests to these addresses, in turn, rather than
+    /// broadcasting.
+    pub discover_addresses: Vec<SocketAddrV4>,
+    /// How long each attempt at discovery waits for a server.
+    pub discover_timeout: Duration,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            servers: Vec::new(),
+            modelname: DEFAULT_MODELNAME.to_owned(),
+            firmware: env!("CARGO_PKG_VERSION").to_owned(),
+            mac: None,
+            max_sample_rate: DEFAULT_MAX_SAMPLE_RATE,
+            keepalive: DEFAULT_KEEPALIVE,
+            discover_addresses: Vec::new(),
+            discover_timeout: discovery::DEFAULT_TIMEOUT,
+        }
+    }
+}
+
+// Watches for silence from the server on one connection, and stops
+// watching when dropped. Holds when the server was last heard from, or
+// none once the connection is finished with, so that only one of the
+// reader and the watchdog reports it lost.
+struct Watchdog(Arc<Mutex<Option<Instant>>>);
+
+impl Watchdog {
+    fn start(
+        server: SocketAddrV4,
+        keepalive: Duration,
+        slim_rx_in: Sender<Option<ServerMessage>>,
+        info: Arc<Mutex<ConnectionInfo>>,
+    ) -> Self {
+        let heard = Arc::new(Mutex::new(Some(Instant::now())));
+        if keepalive.is_zero() {
+            return Self(heard);
+        }
+
+        let watched = heard.clone();
+        threads::spawn(move || {
+            while threads::sleep(WATCH_EVERY.min(keepalive)) {
+                let Ok(mut heard) = watched.lock() else {
+                    return;
+                };
+                let Some(at) = *heard else {
+                    return;
+                };
+                if at.elapsed() >= keepalive {
+                    warn!(
+                        "Nothing from server {server} for {}s, taking the connection as lost",
+                        at.elapsed().as_secs()
+                    );
+                    *heard = None;
+                    if let Ok(mut info) = info.lock() {
+                        info.disconnected();
+                    }
+                    slim_rx_in.send(None).ok();
+                    return;
+                }
+            }
+        });
+        Self(heard)
+    }
+
+    // Note that the server was heard from, returning false if the watchdog
+    // has already taken the connection as lost and the player moved on
+    fn heard(&self) -> bool {
+        match self.0.lock() {
+            Ok(mut heard) => match *heard {
+                Some(ref mut at) => {
+                    *at = Instant::now();
+                    true
+                }
+                None => false,
+            },
+            Err(_) => true,
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        if let Ok(mut heard) = self.0.lock() {
+            *heard = None;
+        }
+    }
+}
+
+/// The capabilities advertised to the server in the HELO message, as they
+/// are sent. The model follows the convention of being the model name in
+/// lower case. The firmware is sent
+/// as older servers show it in the player's settings and expect one.
+/// `CanHTTPS` tells the server it may hand HTTPS URLs to the player, so is
+/// only sent when TLS is built in.
+pub fn capabilities(syncgroupid: &str, settings: &Settings) -> Vec<String> {
+    let mut caps = vec![
+        Capability::Model(settings.modelname.to_lowercase()),
+        Capability::Modelname(settings.modelname.clone()),
+        Capability::Maxsamplerate(settings.max_sample_rate),
+        Capability::Accurateplaypoints,
+    ];
+    if !syncgroupid.is_empty() {
+        caps.push(Capability::Syncgroupid(syncgroupid.to_owned()));
+    }
+    caps.extend(decode::supported_formats());
+
+    // slimproto has no capability for these, so they are written as
+    // squeezelite sends them
+    let mut caps = caps.iter().map(Capability::to_string).collect::<Vec<_>>();
+    caps.insert(2, format!("Firmware={}", settings.firmware));
+    if cfg!(feature = "tls") {
+        caps.push("CanHTTPS=1".to_owned());
+    }
+    caps
+}
+
+type SlimReader = FramedRead<BufReader<TcpStream>, SlimCodec>;
+type SlimWriter = FramedWrite<BufWriter<TcpStream>, SlimCodec>;
+
+fn set_socket_option(
+    fd: RawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    // SAFETY: value is a valid c_int for the length given
+    if unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Have the system probe the connection once it is idle for `idle`, so
+// that one dropped without a close is noticed even when nothing is sent.
+// Without an idle time, or off Linux, the system's own timing is kept.
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+fn set_keepalive(stream: &TcpStream, idle: Option<Duration>) -> io::Result<()> {
+    let fd = stream.as_raw_fd();
+    set_socket_option(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    #[cfg(target_os = "linux")]
+    if let Some(idle) = idle {
+        let secs = |d: Duration| d.as_secs().clamp(1, libc::c_int::MAX as u64) as libc::c_int;
+        set_socket_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, secs(idle))?;
+        set_socket_option(
+            fd,
+            libc::IPPROTO_TCP,
+            libc::TCP_KEEPINTVL,
+            secs(KEEPALIVE_INTERVAL),
+        )?;
+        set_socket_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, KEEPALIVE_PROBES)?;
+    }
+    Ok(())
+}
+
+// Connect and send HELO, as slimproto's `PreparedServer::connect` does,
+// but with capabilities it has no entry for
+fn connect(
+    server: SocketAddrV4,
+    settings: &Settings,
+    capabilities: String,
+) -> io::Result<(SlimReader, SlimWriter)> {
+    let cx = TcpStream::connect(server)?;
+    cx.set_nodelay(true)?;
+    // A read gives up a little after the watchdog would, so that the
+    // watchdog is the one to report a silent server
+    let keepalive = Some(settings.keepalive).filter(|keepalive| !keepalive.is_zero());
+    cx.set_read_timeout(keepalive.map(|keepalive| keepalive + WATCH_EVERY))?;
+    cx.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    if let Err(e) = set_keepalive(&cx, keepalive) {
+        warn!("Unable to set TCP keepalive on the server connection: {e}");
+    }
+
+    let helo = ClientMessage::Helo {
+        device_id: 12,
+        revision: 0,
+        mac: settings.mac.unwrap_or_else(|| match get_mac_address() {
+            Ok(Some(mac)) => mac,
+            _ => MacAddress::new([1, 2, 3, 4, 5, 6]),
+        }),
+        uuid: [0u8; 16],
+        wlan_channel_list: 0,
+        bytes_received: 0,
+        language: ['e', 'n'],
+        capabilities,
+    };
+
+    let rx = FramedRead::new(BufReader::new(cx.try_clone()?), SlimCodec);
+    let mut tx = FramedWrite::new(BufWriter::new(cx), SlimCodec);
+    tx.framed_write(helo)?;
+    Ok((rx, tx))
+}
+
+/// Connect to the first of the servers in `settings` that answers, trying
+/// each in turn, or to a server found by discovery if there are none, and
+/// pass messages between it and the channels until the connection is
+/// lost. Stops and pauses are counted in `interrupts` as they are read,
+/// ahead of the channel.
 pub fn run(
-    server_addr: Option<SocketAddrV4>,
-    name: Arc<RwLock<String>>,
+    name: Arc<PlayerName>,
+    settings: Settings,
     slim_rx_in: Sender<Option<ServerMessage>>,
     slim_tx_out: Receiver<ClientMessage>,
+    history: Arc<Mutex<ConnectionHistory>>,
+    info: Arc<Mutex<ConnectionInfo>>,
+    interrupts: Interrupts,
 ) {
     std::thread::spawn(move || {
-        let mut server = match server_addr {
-            Some(sock) => Server::from(sock),
-            None => match discover(None) {
-                Ok(Some(server)) => server,
-                _ => unreachable!(),
+        let servers = &settings.servers;
+        let mut backoff = Backoff::new();
+        let mut discovered = servers.is_empty();
+        // Where the server being tried is in the list given, none once
+        // switched to another server
+        let mut given = (!servers.is_empty()).then_some(0);
+        let mut server = match servers.first() {
+            Some(sock) => Server::from(*sock),
+            None => match find_server(&mut backoff, &settings) {
+                Some(server) => server,
+                None => return,
             },
         };
 
@@ -36,36 +539,92 @@ pub fn run(
         // Outer loop to reconnect to a different server and
         // update server details when a Serv message is received
         'outer: loop {
-            let mut caps = Capabilities::default();
-            if let Ok(name) = name.read() {
-                caps.add_name(&name);
-            }
-            caps.add(Capability::Maxsamplerate(192000));
-            if syncgroupid.len() > 0 {
+            if !syncgroupid.is_empty() {
                 info!("Joining sync group: {syncgroupid}");
-                caps.add(Capability::Syncgroupid(syncgroupid.to_owned()));
             }
-            caps.add(Capability::Pcm);
-            caps.add(Capability::Mp3);
-            caps.add(Capability::Aac);
-            caps.add(Capability::Alc);
-            caps.add(Capability::Ogg);
-            caps.add(Capability::Flc);
+            let caps = capabilities(&syncgroupid, &settings).join(",");
+            debug!("Capabilities: {caps}");
 
             // Connect to the server
             info!("Connecting to server: {}", server.socket);
-            let (mut rx, mut tx) = match server.clone().prepare(caps).connect() {
+            let (mut rx, mut tx) = match connect(server.socket, &settings, caps) {
                 Ok((rx, tx)) => (rx, tx),
-                Err(_) => {
-                    error!("Error connecting to server");
-                    return;
+                Err(e) => {
+                    info!("Unable to connect to server {}: {e}", server.socket);
+                    // Only the first failure of a move is worth a warning,
+                    // the rest are as for any other reconnect
+                    if let Ok(mut info) = info.lock() {
+                        if let Some(switch) = info.switch_failed().filter(|s| s.failures == 1) {
+                            warn!("Server move {switch} failed: {e}, retrying");
+                        }
+                    }
+                    // Each server given is tried in turn, with a wait once
+                    // all of them have failed
+                    if let Some(at) = given {
+                        let next = (at + 1) % servers.len();
+                        given = Some(next);
+                        if next == 0 && !backoff.wait() {
+                            return;
+                        }
+                        if servers[next] != server.socket {
+                            server = Server::from(servers[next]);
+                            info!("Falling back to server {}", server.socket);
+                            slim_rx_in
+                                .send(Some(ServerMessage::Serv {
+                                    ip_address: *server.socket.ip(),
+                                    sync_group_id: None,
+                                }))
+                                .ok();
+                        }
+                        continue;
+                    }
+
+                    if !backoff.wait() {
+                        return;
+                    }
+                    // A server that was found may since have moved, one
+                    // that was switched to is tried again
+                    if discovered {
+                        let Some(found) = find_server(&mut backoff, &settings) else {
+                            return;
+                        };
+                        if found.socket != server.socket {
+                            server = found;
+                            slim_rx_in
+                                .send(Some(ServerMessage::Serv {
+                                    ip_address: *server.socket.ip(),
+                                    sync_group_id: None,
+                                }))
+                                .ok();
+                        }
+                    }
+                    continue;
                 }
             };
+            backoff.reset();
+
+            if let Ok(mut info) = info.lock() {
+                let switch = info.connected(
+                    server.socket,
+                    name.clone(),
+                    capabilities(&syncgroupid, &settings),
+                );
+                if let Some(switch) = switch {
+                    match switch.failures {
+                        0 => info!("Server move {switch} complete"),
+                        n => info!("Server move {switch} complete after {n} failed attempts"),
+                    }
+                }
+                info!("Connected: {info}");
+            }
 
             // Start write thread
-            // Continues until connection is dropped
+            // Continues until connection is dropped or Bye is sent, and is
+            // tracked so that what is queued is written before exit
             let slim_tx_out_r = slim_tx_out.clone();
-            std::thread::spawn(move || {
+            threads::spawn(move || {
+                // Senders block while this thread is busy, apart from timer
+                // status updates which are dropped when the channel is full
                 while let Ok(msg) = slim_tx_out_r.recv() {
                     // println!("{:?}", msg);
                     if let ClientMessage::Bye(n) = msg {
@@ -74,15 +633,28 @@ pub fn run(
                         }
                     }
 
-                    if tx.framed_write(msg).is_err() {
+                    if let Err(e) = tx.framed_write(msg) {
+                        warn!("Unable to write to server: {e}");
                         break;
                     }
                 }
             });
 
+            let watchdog = Watchdog::start(
+                server.socket,
+                settings.keepalive,
+                slim_rx_in.clone(),
+                info.clone(),
+            );
+
             // Inner read loop
             loop {
-                match rx.framed_read() {
+                let read = rx.framed_read();
+                // Anything more is for a session that has already ended
+                if !watchdog.heard() {
+                    return;
+                }
+                match read {
                     Ok(msg) => {
                         // println!("{:?}", msg);
                         match msg {
@@ -91,34 +663,290 @@ pub fn run(
                                 ip_address: ip,
                                 sync_group_id: sgid,
                             } => {
-                                if let Some(ref sgid) = sgid {
-                                    syncgroupid = sgid.to_owned();
+                                // The group is for the next HELO only, so a
+                                // switch without one leaves any group
+                                syncgroupid = sgid.clone().unwrap_or_default();
+                                if let Ok(mut info) = info.lock() {
+                                    if let Some(switch) = info.switching(ip, sgid.clone()) {
+                                        info!("Server move {switch} asked for by the server");
+                                    }
                                 }
 
-                                server = (ip, sgid).into();
+                                server = (ip, sgid.clone()).into();
+                                discovered = false;
+                                given = None;
                                 // Now inform the main thread
                                 slim_rx_in
                                     .send(Some(ServerMessage::Serv {
                                         ip_address: ip,
-                                        sync_group_id: None,
+                                        sync_group_id: sgid,
                                     }))
                                     .ok();
                                 break;
                             }
 
                             _ => {
+                                match msg {
+                                    ServerMessage::Stop | ServerMessage::Flush => {
+                                        interrupts.interrupt_prefill(Interrupt::Stop)
+                                    }
+                                    ServerMessage::Pause(_) => {
+                                        interrupts.interrupt_prefill(Interrupt::Pause)
+                                    }
+                                    _ => {}
+                                }
                                 slim_rx_in.send(Some(msg)).ok();
                             }
                         }
                     }
 
-                    Err(_) => {
+                    // A clean close comes from the server, anything else
+                    // is a problem with the network
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                        info!("Connection closed by server");
+                        let closes = history
+                            .lock()
+                            .map(|mut history| history.add_server_close())
+                            .unwrap_or_default();
+                        if closes > DUPLICATE_CLOSES {
+                            warn!(
+                                "The server has closed the connection {closes} times in the last \
+                                minute, this usually means that another player with the same \
+                                MAC address is connected, e.g. a second Vibe on this machine \
+                                with the same -n name, or a player on a cloned SD card or \
+                                with the same --mac. Each player needs a distinct MAC address."
+                            );
+                        }
+                        slim_rx_in.send(None).ok();
+                        break 'outer;
+                    }
+
+                    Err(e) => {
+                        info!("Connection to server lost: {e}");
                         slim_rx_in.send(None).ok();
                         break 'outer;
                     }
                 }
             }
         }
+        if let Ok(mut info) = info.lock() {
+            info.disconnected();
+        }
         info!("Lost contact with server at {}", server.socket);
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capabilities_carry_model_and_firmware() {
+        let caps = capabilities("", &Settings::default());
+        assert_eq!(caps[0], "Model=vibe");
+        assert_eq!(caps[1], "Modelname=Vibe");
+        assert_eq!(caps[2], format!("Firmware={}", env!("CARGO_PKG_VERSION")));
+        assert!(caps.iter().any(|cap| cap.starts_with("MaxSampleRate=")));
+        assert!(!caps.iter().any(|cap| cap.starts_with("SyncgroupID=")));
+        assert_eq!(
+            caps.iter().any(|cap| cap == "CanHTTPS=1"),
+            cfg!(feature = "tls")
+        );
+    }
+
+    #[test]
+    fn helo_sends_each_capability_once() {
+        let settings = Settings {
+            modelname: "Kitchen".to_owned(),
+            ..Settings::default()
+        };
+        let helo = capabilities("", &settings).join(",");
+
+        let mut expected = format!(
+            "Model=kitchen,Modelname=Kitchen,Firmware={},MaxSampleRate=192000,AccuratePlayPoints=1",
+            env!("CARGO_PKG_VERSION")
+        );
+        for format in decode::supported_formats() {
+            expected.push_str(&format!(",{format}"));
+        }
+        if cfg!(feature = "tls") {
+            expected.push_str(",CanHTTPS=1");
+        }
+        assert_eq!(helo, expected);
+        for key in ["Model=", "Modelname="] {
+            assert_eq!(
+                helo.split(',').filter(|cap| cap.starts_with(key)).count(),
+                1
+            );
+        }
+    }
+
+    #[test]
+    fn capabilities_join_a_sync_group() {
+        let caps = capabilities("1234", &Settings::default());
+        assert!(caps.iter().any(|cap| cap == "SyncgroupID=1234"));
+    }
+
+    fn socket_option(stream: &TcpStream, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        // SAFETY: value and len are valid for the call to fill in
+        let rc = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                level,
+                name,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(rc, 0, "{}", io::Error::last_os_error());
+        value
+    }
+
+    #[test]
+    fn keepalive_probes_after_the_idle_time() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        set_keepalive(&stream, Some(Duration::from_secs(35))).unwrap();
+        assert_ne!(
+            socket_option(&stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE),
+            0
+        );
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            socket_option(&stream, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE),
+            35
+        );
+    }
+
+    // Read a frame as the server would, giving its opcode, or for a status
+    // message its event code, or for a name the name
+    fn read_frame(socket: &mut TcpStream) -> io::Result<String> {
+        use std::io::Read;
+
+        let mut header = [0u8; 8];
+        socket.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let mut body = vec![0u8; len as usize];
+        socket.read_exact(&mut body)?;
+        Ok(match &header[..4] {
+            b"STAT" => String::from_utf8_lossy(&body[..4]).into_owned(),
+            b"SETD" => String::from_utf8_lossy(&body[1..]).into_owned(),
+            opcode => String::from_utf8_lossy(opcode).into_owned(),
+        })
+    }
+
+    #[test]
+    fn stalled_server_gets_every_event_in_order() {
+        use crossbeam::channel::{bounded, TrySendError};
+        use slimproto::status::{StatusCode, StatusData};
+
+        use crate::{message::Clock, stats::Stats};
+
+        const WAIT: Duration = Duration::from_secs(5);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        // A small buffer at the server, so that a stall soon backs up
+        set_socket_option(
+            listener.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVBUF,
+            4096,
+        )
+        .unwrap();
+        let std::net::SocketAddr::V4(server) = listener.local_addr().unwrap() else {
+            unreachable!("bound to an IPv4 address");
+        };
+        let (resume_tx, resume_rx) = bounded(1);
+        let server_thread = std::thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            resume_rx.recv().ok();
+            let mut frames = Vec::new();
+            loop {
+                let frame = read_frame(&mut socket).unwrap();
+                if frame == "BYE!" {
+                    return frames;
+                }
+                frames.push(frame);
+            }
+        });
+
+        // As in the main loop
+        let (slim_tx_in, slim_tx_out) = bounded(32);
+        let (slim_rx_in, slim_rx_out) = bounded(8);
+        let settings = Settings {
+            servers: vec![server],
+            keepalive: Duration::ZERO,
+            ..Settings::default()
+        };
+        run(
+            Arc::new(PlayerName::fixed("Vibe")),
+            settings,
+            slim_rx_in,
+            slim_tx_out,
+            Arc::new(Mutex::new(ConnectionHistory::new())),
+            Arc::new(Mutex::new(ConnectionInfo::new())),
+            Interrupts::new(),
+        );
+        assert!(matches!(
+            slim_rx_out.recv_timeout(WAIT),
+            Ok(Some(ServerMessage::Serv { .. }))
+        ));
+
+        let clock = Clock::new();
+        let stats = Arc::new(Stats::new());
+        let status = Arc::new(Mutex::new(StatusData::default()));
+        let stat = move |code| clock.status_message(&mut status.lock().unwrap(), code);
+        // Sent as the message handler sends them
+        let timer = {
+            let (slim_tx_in, stats, stat) = (slim_tx_in.clone(), stats.clone(), stat.clone());
+            move || {
+                if let Err(TrySendError::Full(_)) = slim_tx_in.try_send(stat(StatusCode::Timer)) {
+                    stats.add_dropped_status();
+                }
+            }
+        };
+
+        // Far more timers than the stalled server takes
+        let mut timers = 0;
+        while stats.dropped_status() < 10_000 {
+            timer();
+            timers += 1;
+        }
+
+        let events = std::thread::spawn(move || {
+            let events = [
+                stat(StatusCode::DecoderReady),
+                stat(StatusCode::Underrun),
+                stat(StatusCode::NotSupported),
+                ClientMessage::Name("Kitchen".to_owned()),
+                ClientMessage::Bye(0),
+            ];
+            for event in events {
+                slim_tx_in.send(event).unwrap();
+                for _ in 0..10 {
+                    timer();
+                    timers += 1;
+                }
+            }
+            timers
+        });
+        std::thread::sleep(Duration::from_millis(200));
+        resume_tx.send(()).unwrap();
+        let timers = events.join().unwrap();
+
+        let frames = server_thread.join().unwrap();
+        assert_eq!(frames[0], "HELO");
+        let events = frames
+            .iter()
+            .filter(|frame| *frame != "STMt")
+            .collect::<Vec<_>>();
+        assert_eq!(events, ["HELO", "STMd", "STMu", "STMn", "Kitchen"]);
+        // Every timer that was not dropped was sent, and only those
+        // queued after the Bye are missing
+        let sent = frames.len() - events.len();
+        assert!(sent + stats.dropped_status() <= timers);
+        assert!(sent + stats.dropped_status() + 10 >= timers);
+    }
+}
@@ -0,0 +1,203 @@
+//! The pulse threaded mainloop, with the rules for using it kept in one
+//! place.
+//!
+//! Pulse runs its callbacks on the mainloop's own thread, holding the
+//! mainloop lock while each runs. Anything else that touches the context,
+//! a stream or an operation must hold the same lock. The pulse objects are
+//! shared with the callbacks as `Rc<RefCell<_>>`, so the lock is also what
+//! stops their reference counts and borrow flags being raced. Hence:
+//!
+//! - outside callbacks, take the lock with [`MainLoop::lock`] before using
+//!   a pulse object, and let the guard release it, which it does however
+//!   the scope is left,
+//! - never keep a borrow of a pulse object once the lock is released,
+//!   including across [`Locked::wait`],
+//! - in callbacks, reach pulse objects with [`in_callback`], which borrows
+//!   them as anywhere else rather than through a raw pointer.
+//!
+//! Debug builds panic when a pulse object is used without the lock, see
+//! [`MainLoop::assert_locked`].
+
+use std::{
+    cell::{Cell, RefCell, UnsafeCell},
+    ops::Deref,
+    rc::Rc,
+};
+
+use pulse::{context::Context, error::PAErr, mainloop::threaded::Mainloop};
+
+// How many times the thread driving the output holds the lock, which
+// pulse lets a thread take more than once, and the rules it checks in
+// debug builds. Only read on that thread. It is kept apart from libpulse
+// so that the rules can be tested without a pulse server.
+#[derive(Default)]
+struct LockDepth(Cell<usize>);
+
+impl LockDepth {
+    // The lock is about to be taken, which would deadlock in a callback
+    fn taking(&self, in_thread: bool) {
+        debug_assert!(!in_thread, "pulse mainloop locked from a callback");
+        self.0.set(self.0.get() + 1);
+    }
+
+    fn released(&self) {
+        self.0.set(self.0.get() - 1);
+    }
+
+    fn assert_locked(&self, in_thread: bool) {
+        debug_assert!(
+            in_thread || self.0.get() > 0,
+            "pulse used without the mainloop lock"
+        );
+    }
+
+    fn assert_unlocked(&self) {
+        debug_assert_eq!(self.0.get(), 0, "pulse mainloop stopped while locked");
+    }
+}
+
+/// The mainloop, shared by the output and the callbacks it sets.
+#[derive(Clone)]
+pub(crate) struct MainLoop {
+    inner: Rc<UnsafeCell<Mainloop>>,
+    depth: Rc<LockDepth>,
+}
+
+impl MainLoop {
+    pub(crate) fn new() -> Option<Self> {
+        Some(Self {
+            inner: Rc::new(UnsafeCell::new(Mainloop::new()?)),
+            depth: Rc::new(LockDepth::default()),
+        })
+    }
+
+    // The mainloop's own functions are made to be called from any thread,
+    // e.g. `signal` from a callback while another thread is in `wait`. The
+    // binding asks for `&mut` for each, which no `RefCell` can give both
+    // threads at once, so this is the one place that hands it out without
+    // a borrow check. Nothing here keeps it beyond the one call.
+    fn with<R>(&self, f: impl FnOnce(&mut Mainloop) -> R) -> R {
+        // SAFETY: each use is a single call into libpulse, which does its
+        // own locking, and no reference outlives it
+        f(unsafe { &mut *self.inner.get() })
+    }
+
+    pub(crate) fn new_context(&self, name: &str) -> Option<Context> {
+        self.with(|mainloop| Context::new(mainloop.deref(), name))
+    }
+
+    pub(crate) fn start(&self) -> Result<(), PAErr> {
+        self.with(|mainloop| mainloop.start())
+    }
+
+    /// Stop the mainloop's thread, which needs the lock to finish, so it
+    /// must not be held.
+    pub(crate) fn stop(&self) {
+        self.depth.assert_unlocked();
+        self.with(|mainloop| mainloop.stop());
+    }
+
+    /// Whether this is the mainloop's own thread, i.e. a callback.
+    pub(crate) fn in_thread(&self) -> bool {
+        self.with(|mainloop| mainloop.in_thread())
+    }
+
+    /// Take the lock until the guard is dropped. A callback already holds
+    /// it, and taking it there would deadlock.
+    pub(crate) fn lock(&self) -> Locked<'_> {
+        self.depth.taking(self.in_thread());
+        self.with(|mainloop| mainloop.lock());
+        Locked(self)
+    }
+
+    /// Panic in debug builds unless the lock is held, by this thread or
+    /// by the mainloop running a callback.
+    pub(crate) fn assert_locked(&self) {
+        self.depth.assert_locked(self.in_thread());
+    }
+
+    /// Wake whoever is in [`Locked::wait`], from a callback.
+    pub(crate) fn signal(&self) {
+        self.assert_locked();
+        self.with(|mainloop| mainloop.signal(false));
+    }
+}
+
+/// The mainloop lock, released when dropped.
+pub(crate) struct Locked<'a>(&'a MainLoop);
+
+impl Locked<'_> {
+    /// Release the lock until a callback calls [`MainLoop::signal`], then
+    /// take it again.
+    pub(crate) fn wait(&self) {
+        self.0.with(|mainloop| mainloop.wait());
+    }
+}
+
+impl Drop for Locked<'_> {
+    fn drop(&mut self) {
+        self.0.depth.released();
+        self.0.with(|mainloop| mainloop.unlock());
+    }
+}
+
+/// Use a pulse object from one of its callbacks. Pulse also runs some
+/// callbacks straight from a call made on the output's side, e.g. a state
+/// change on disconnect, while the object is borrowed for that call, so
+/// this gives `None` rather than panicking when it is already borrowed.
+pub(crate) fn in_callback<T, R>(object: &RefCell<T>, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+    object
+        .try_borrow_mut()
+        .ok()
+        .map(|mut object| f(&mut object))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_held_or_in_a_callback() {
+        let depth = LockDepth::default();
+        depth.assert_locked(true);
+        depth.taking(false);
+        depth.taking(false);
+        depth.released();
+        depth.assert_locked(false);
+        depth.released();
+        depth.assert_unlocked();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "pulse used without the mainloop lock")]
+    fn used_without_the_lock() {
+        LockDepth::default().assert_locked(false);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "pulse used without the mainloop lock")]
+    fn used_after_the_lock_is_released() {
+        let depth = LockDepth::default();
+        depth.taking(false);
+        depth.released();
+        depth.assert_locked(false);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "pulse mainloop locked from a callback")]
+    fn locked_from_a_callback() {
+        LockDepth::default().taking(true);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "pulse mainloop stopped while locked")]
+    fn stopped_while_locked() {
+        let depth = LockDepth::default();
+        depth.taking(false);
+        depth.assert_unlocked();
+    }
+}
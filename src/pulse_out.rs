@@ -1,73 +1,144 @@
-use std::{cell::RefCell, ops::Deref, rc::Rc, time::Duration};
+use std::{
+    cell::RefCell,
+    panic::{self, AssertUnwindSafe},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use anyhow::anyhow;
+use anyhow::{anyhow, bail};
 use crossbeam::channel::{bounded, Sender};
-use log::warn;
+use log::{debug, info, warn};
 use pulse::{
     callbacks::ListResult,
+    channelmap::{Map, Position},
     context::{Context, FlagSet as CxFlagSet, State},
     def::BufferAttr,
     error::PAErr,
-    mainloop::threaded::Mainloop,
-    operation::Operation,
     sample::Spec,
     stream::{FlagSet as SmFlagSet, SeekMode},
-    volume::ChannelVolumes,
+    volume::{ChannelVolumes, Volume},
 };
 
+use symphonia::core::audio::Channels;
+
 use crate::{
+    audio_out::{Interrupt, Interrupts, OutputOptions, OutputState, WriteMarker},
     decode::{AudioFormat, Decoder, DecoderError},
     message::PlayerMsg,
-    StreamParams,
+    pulse_loop::{in_callback, MainLoop},
+    threads, StreamParams,
 };
 
 const MIN_AUDIO_BUFFER_SIZE: usize = 8 * 1024;
+// A sink volume below this percentage is mentioned in the log
+const SINK_VOLUME_HINT: f64 = 95.0;
+// Pauses up to this long are played as silence, as corking and uncorking
+// takes a round trip to the server each
+pub(crate) const MICRO_PAUSE_MAX: Duration = Duration::from_millis(250);
+// How long a resume may wait for the buffer to be refilled
+const REFILL_MAX_WAIT: Duration = Duration::from_millis(500);
+const REFILL_POLL: Duration = Duration::from_millis(10);
+// How long a stream that has played out is left before it is disconnected
+const RETIRE_AFTER: Duration = Duration::from_secs(1);
+
+// The pulse channel map for channels in symphonia's order
+fn channel_map(layout: Channels) -> Map {
+    let mut map = Map::default();
+    map.set_len(layout.count() as u8);
+    for (position, channel) in map.get_mut().iter_mut().zip(layout.iter()) {
+        *position = match channel {
+            Channels::FRONT_LEFT => Position::FrontLeft,
+            Channels::FRONT_RIGHT => Position::FrontRight,
+            Channels::FRONT_CENTRE => Position::FrontCenter,
+            Channels::LFE1 | Channels::LFE2 => Position::Lfe,
+            Channels::REAR_LEFT => Position::RearLeft,
+            Channels::REAR_RIGHT => Position::RearRight,
+            Channels::FRONT_LEFT_CENTRE => Position::FrontLeftOfCenter,
+            Channels::FRONT_RIGHT_CENTRE => Position::FrontRightOfCenter,
+            Channels::REAR_CENTRE => Position::RearCenter,
+            Channels::SIDE_LEFT => Position::SideLeft,
+            Channels::SIDE_RIGHT => Position::SideRight,
+            Channels::TOP_CENTRE => Position::TopCenter,
+            Channels::TOP_FRONT_LEFT => Position::TopFrontLeft,
+            Channels::TOP_FRONT_CENTRE => Position::TopFrontCenter,
+            Channels::TOP_FRONT_RIGHT => Position::TopFrontRight,
+            Channels::TOP_REAR_LEFT => Position::TopRearLeft,
+            Channels::TOP_REAR_CENTRE => Position::TopRearCenter,
+            Channels::TOP_REAR_RIGHT => Position::TopRearRight,
+            // No pulse position matches, so play it as the nearest
+            Channels::REAR_LEFT_CENTRE => Position::RearLeft,
+            Channels::REAR_RIGHT_CENTRE => Position::RearRight,
+            Channels::FRONT_LEFT_WIDE | Channels::FRONT_LEFT_HIGH => Position::FrontLeft,
+            Channels::FRONT_RIGHT_WIDE | Channels::FRONT_RIGHT_HIGH => Position::FrontRight,
+            _ => Position::FrontCenter,
+        };
+    }
+    map
+}
 
-#[derive(Clone)]
+// Each use of the pulse stream needs the mainloop lock, see `pulse_loop`.
+// The write callback holds the stream weakly, so only this owns it.
 pub struct Stream {
+    mainloop: MainLoop,
     inner: Rc<RefCell<pulse::stream::Stream>>,
+    spec: Spec,
+    written: WriteMarker,
 }
 
 impl Stream {
-    fn new(context: Rc<RefCell<Context>>, decoder: &Decoder) -> Option<Self> {
+    fn new(mainloop: &MainLoop, context: &RefCell<Context>, decoder: &Decoder) -> Option<Self> {
+        mainloop.assert_locked();
         let spec = Spec {
             format: match decoder.format() {
                 AudioFormat::I16 | AudioFormat::U16 => pulse::sample::Format::S16NE,
                 AudioFormat::I32 | AudioFormat::U32 => pulse::sample::Format::S32NE,
+                AudioFormat::I24 => pulse::sample::Format::S24NE,
                 AudioFormat::F32 => pulse::sample::Format::FLOAT32NE,
             },
             rate: decoder.sample_rate(),
             channels: decoder.channels(),
         };
 
+        // Pulse's default map suits mono and stereo, more channels need
+        // saying which speaker each is for
+        let layout = decoder.channel_layout();
+        let map = (layout.count() > 2).then(|| channel_map(layout));
+
         // Create a pulseaudio stream
         let stream =
-            pulse::stream::Stream::new(&mut (*context).borrow_mut(), "Music", &spec, None)?;
+            pulse::stream::Stream::new(&mut context.borrow_mut(), "Music", &spec, map.as_ref())?;
 
         Some(Self {
+            mainloop: mainloop.clone(),
             inner: Rc::new(RefCell::new(stream)),
+            spec,
+            written: WriteMarker::new(),
         })
     }
 
-    fn into_inner(self) -> Rc<RefCell<pulse::stream::Stream>> {
-        self.inner
-    }
-
     fn set_write_callback(&mut self, callback: Box<dyn FnMut(usize) + 'static>) {
+        self.mainloop.assert_locked();
         (*self.inner)
             .borrow_mut()
             .set_write_callback(Some(callback));
     }
 
     fn set_underflow_callback(&mut self, callback: Option<Box<dyn FnMut() + 'static>>) {
+        self.mainloop.assert_locked();
         (*self.inner).borrow_mut().set_underflow_callback(callback)
     }
 
     fn disconnect(&mut self) -> Result<(), PAErr> {
+        self.mainloop.assert_locked();
         (*self.inner).borrow_mut().disconnect()
     }
 
     fn set_state_callback(&mut self, callback: Option<Box<dyn FnMut() + 'static>>) {
+        self.mainloop.assert_locked();
         (*self.inner).borrow_mut().set_state_callback(callback)
     }
 
@@ -79,21 +150,33 @@ impl Stream {
         volume: Option<&ChannelVolumes>,
         sync_stream: Option<&mut pulse::stream::Stream>,
     ) -> Result<(), PAErr> {
+        self.mainloop.assert_locked();
         (*self.inner)
             .borrow_mut()
             .connect_playback(dev, attr, flags, volume, sync_stream)
     }
 
+    fn get_device_name(&self) -> Option<String> {
+        self.mainloop.assert_locked();
+        (*self.inner)
+            .borrow()
+            .get_device_name()
+            .map(|name| name.to_string())
+    }
+
     fn get_state(&self) -> pulse::stream::State {
+        self.mainloop.assert_locked();
         (*self.inner).borrow_mut().get_state()
     }
 
+    // Dropping the operation only lets go of it, it carries on regardless
     fn play(&mut self) {
-        let op = (*self.inner).borrow_mut().uncork(None);
-        self.do_op(op);
+        self.mainloop.assert_locked();
+        (*self.inner).borrow_mut().uncork(None);
     }
 
     fn pause(&mut self) {
+        self.mainloop.assert_locked();
         (*self.inner).borrow_mut().cork(None);
     }
 
@@ -101,7 +184,26 @@ impl Stream {
         self.play();
     }
 
+    // Give the write callback the chance to top up pulse's buffer while
+    // still corked, until pulse asks for no more than a little or the wait
+    // runs out
+    fn refill(&self) {
+        let start = Instant::now();
+        while start.elapsed() < REFILL_MAX_WAIT {
+            let wanted = {
+                let _lock = self.mainloop.lock();
+                (*self.inner).borrow().writable_size()
+            };
+            if !matches!(wanted, Some(bytes) if bytes >= MIN_AUDIO_BUFFER_SIZE) {
+                break;
+            }
+            std::thread::sleep(REFILL_POLL);
+        }
+        debug!("Waited {:?} for the buffer to refill", start.elapsed());
+    }
+
     fn get_pos(&self) -> Duration {
+        self.mainloop.assert_locked();
         let micros = match (*self.inner).borrow().get_time() {
             Ok(Some(micros)) => micros,
             _ => pulse::time::MicroSeconds(0),
@@ -109,81 +211,115 @@ impl Stream {
 
         Duration::from_micros(micros.0)
     }
-
-    fn do_op(&self, op: Operation<dyn FnMut(bool)>) {
-        std::thread::spawn(move || {
-            while op.get_state() == pulse::operation::State::Running {
-                std::thread::sleep(Duration::from_millis(10));
-            }
-        });
-    }
 }
 
 pub struct AudioOutput {
-    mainloop: Rc<RefCell<Mainloop>>,
+    mainloop: MainLoop,
     context: Rc<RefCell<Context>>,
+    // Set once the connection to the pulse server has gone
+    lost: Arc<AtomicBool>,
     playing: Option<Stream>,
     next_up: Option<Stream>,
+    paused: bool,
+    options: OutputOptions,
+    state: Arc<OutputState>,
+    interrupts: Interrupts,
+    // The sink whose port has been chosen, so it is only done once
+    port_sink: Option<String>,
+    // Streams that have played out, with when, to be disconnected shortly
+    retired: RefCell<Vec<(Instant, Stream)>>,
 }
 
 impl AudioOutput {
-    pub fn try_new() -> anyhow::Result<Self> {
-        let mainloop = Rc::new(RefCell::new(
-            Mainloop::new().ok_or(pulse::error::Code::ConnectionRefused)?,
-        ));
+    pub fn try_new(
+        options: &OutputOptions,
+        state: Arc<OutputState>,
+        interrupts: Interrupts,
+    ) -> anyhow::Result<Self> {
+        let mainloop = MainLoop::new().ok_or(pulse::error::Code::ConnectionRefused)?;
 
         let context = Rc::new(RefCell::new(
-            Context::new((*mainloop).borrow_mut().deref(), "Vibe")
+            mainloop
+                .new_context("Vibe")
                 .ok_or(pulse::error::Code::ConnectionRefused)?,
         ));
 
-        // Context state change callback
+        // Wake the wait below on each change of state, which it checks
         {
             let mainloop_ref = mainloop.clone();
-            let context_ref = context.clone();
             (*context)
                 .borrow_mut()
-                .set_state_callback(Some(Box::new(move || {
-                    let state = unsafe { (*context_ref.as_ptr()).get_state() };
-                    match state {
-                        State::Ready | State::Terminated | State::Failed => unsafe {
-                            (*mainloop_ref.as_ptr()).signal(false);
-                        },
-                        _ => {}
-                    }
-                })))
+                .set_state_callback(Some(Box::new(move || mainloop_ref.signal())));
         }
 
+        let lock = mainloop.lock();
         (*context)
             .borrow_mut()
             .connect(None, CxFlagSet::NOFLAGS, None)?;
-        (*mainloop).borrow_mut().lock();
-        (*mainloop).borrow_mut().start()?;
+        mainloop.start()?;
 
         // Wait for context to be ready
         loop {
-            match context.borrow().get_state() {
+            let state = context.borrow().get_state();
+            match state {
                 State::Ready => {
                     break;
                 }
                 State::Failed | State::Terminated => {
-                    (*mainloop).borrow_mut().unlock();
-                    (*mainloop).borrow_mut().stop();
+                    drop(lock);
+                    mainloop.stop();
                     return Err(anyhow!("Unable to connect with pulseaudio"));
                 }
-                _ => (*mainloop).borrow_mut().wait(),
+                _ => lock.wait(),
             }
         }
 
-        (*context).borrow_mut().set_state_callback(None);
-        (*mainloop).borrow_mut().unlock();
+        // From now on only watch for the server going away, e.g. when
+        // pulseaudio is restarted. The context is held weakly, as it owns
+        // the callback.
+        let lost = Arc::new(AtomicBool::new(false));
+        {
+            let context_ref = Rc::downgrade(&context);
+            let lost_ref = lost.clone();
+            (*context)
+                .borrow_mut()
+                .set_state_callback(Some(Box::new(move || {
+                    let Some(context) = context_ref.upgrade() else {
+                        return;
+                    };
+                    let state = in_callback(&context, |context| context.get_state());
+                    if let Some(State::Failed | State::Terminated) = state {
+                        lost_ref.store(true, Ordering::Relaxed);
+                    }
+                })));
+        }
+        drop(lock);
 
-        Ok(AudioOutput {
+        let output = AudioOutput {
             mainloop,
             context,
+            lost,
             playing: None,
             next_up: None,
-        })
+            paused: false,
+            options: options.clone(),
+            state,
+            interrupts,
+            port_sink: None,
+            retired: RefCell::new(Vec::new()),
+        };
+
+        if let Some(ref device) = output.options.device {
+            if !output
+                .get_output_device_names()?
+                .iter()
+                .any(|(name, _)| name == device)
+            {
+                bail!("Cannot find device: {device}");
+            }
+        }
+
+        Ok(output)
     }
 
     pub fn enqueue_new_stream(
@@ -191,27 +327,46 @@ impl AudioOutput {
         mut decoder: Decoder,
         stream_in: Sender<PlayerMsg>,
         stream_params: StreamParams,
-        device: &Option<String>,
     ) {
         // Create an audio buffer to hold raw u8 samples
+        let max_ahead = decoder.dur_to_samples(self.options.max_decode_ahead) as usize;
         let buf_size = {
             let num_samps = decoder.dur_to_samples(stream_params.output_threshold) as usize;
             if num_samps < MIN_AUDIO_BUFFER_SIZE {
                 MIN_AUDIO_BUFFER_SIZE
             } else {
-                num_samps
+                num_samps.min(max_ahead.max(MIN_AUDIO_BUFFER_SIZE))
             }
         };
 
         let mut audio_buf = Vec::with_capacity(buf_size);
 
-        // Prefill audio buffer to threshold
+        // Prefill audio buffer to threshold, a little at a time so that a
+        // stop or pause from the server is not held up until it is full
+        let watch = self.interrupts.watch();
+        // A track shorter than the threshold is decoded to the end here
+        let mut ended = false;
         loop {
-            match decoder.fill_raw_buffer(&mut audio_buf, None, stream_params.volume.clone()) {
-                Ok(()) => {}
+            let limit = (audio_buf.len() + MIN_AUDIO_BUFFER_SIZE).min(buf_size);
+            match decoder.fill_raw_buffer(&mut audio_buf, Some(limit), stream_params.volume.clone())
+            {
+                Ok(()) => {
+                    if audio_buf.len() < buf_size {
+                        match watch.interrupted() {
+                            Some(Interrupt::Stop) => {
+                                info!("Stopped while prefilling, dropping stream");
+                                return;
+                            }
+                            // Start with what there is, the pause follows
+                            Some(Interrupt::Pause) => {}
+                            None => continue,
+                        }
+                    }
+                }
 
                 Err(DecoderError::EndOfDecode) => {
                     stream_in.send(PlayerMsg::EndOfDecode).ok();
+                    ended = true;
                 }
 
                 // Err(DecoderError::Unhandled) => {
@@ -225,92 +380,134 @@ impl AudioOutput {
                     return;
                 }
 
-                Err(DecoderError::Retry) => {
+                // Too early to announce, the first track has not started
+                Err(DecoderError::Retry | DecoderError::Chained) => {
                     continue;
                 }
             }
             break;
         }
 
-        (*self.mainloop).borrow_mut().lock();
-        let mut stream = match Stream::new(self.context.clone(), &decoder) {
-            Some(stream) => stream,
-            None => {
-                stream_in.send(PlayerMsg::NotSupported).ok();
-                return;
-            }
+        let lock = self.mainloop.lock();
+        let Some(mut stream) = Stream::new(&self.mainloop, &self.context, &decoder) else {
+            stream_in.send(PlayerMsg::NotSupported).ok();
+            return;
         };
-        (*self.mainloop).borrow_mut().unlock();
+        stream.written = stream_params.written.clone();
 
         {
             let mut start_flag = true;
-            let mut draining = false;
+            let mut draining = ended;
+            let mut triggered = false;
             let drained = Rc::new(RefCell::new(false));
-            let stream_ref = Rc::downgrade(&stream.clone().into_inner());
+            let stream_ref = Rc::downgrade(&stream.inner);
             let drained_ref = drained.clone();
             let stream_in_ref = stream_in.clone();
-            (*self.mainloop).borrow_mut().lock();
+            let state = self.state.clone();
             stream.set_write_callback(Box::new(move |len| {
-                if *drained_ref.borrow() {
-                    return;
-                }
+                // A panic must not unwind into pulse, so drop this stream
+                // and let the player move on
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    if *drained_ref.borrow() {
+                        return;
+                    }
 
-                if start_flag {
-                    stream_in_ref.send(PlayerMsg::TrackStarted).ok();
-                    start_flag = false;
-                }
+                    if start_flag {
+                        stream_in_ref.send(PlayerMsg::TrackStarted).ok();
+                        start_flag = false;
+                    }
 
-                loop {
-                    match decoder.fill_raw_buffer(
-                        &mut audio_buf,
-                        Some(len),
-                        stream_params.volume.clone(),
-                    ) {
-                        Ok(()) => {}
-
-                        Err(DecoderError::EndOfDecode) => {
-                            if !draining {
-                                stream_in_ref.send(PlayerMsg::EndOfDecode).ok();
+                    loop {
+                        match decoder.fill_raw_buffer(
+                            &mut audio_buf,
+                            Some(len),
+                            stream_params.volume.clone(),
+                        ) {
+                            Ok(()) => {}
+
+                            Err(DecoderError::EndOfDecode) => {
+                                if !draining {
+                                    stream_in_ref.send(PlayerMsg::EndOfDecode).ok();
+                                    draining = true;
+                                }
+                            }
+
+                            Err(DecoderError::StreamError(e)) => {
+                                warn!("Error reading data stream: {}", e);
+                                stream_in_ref.send(PlayerMsg::NotSupported).ok();
                                 draining = true;
                             }
-                        }
 
-                        Err(DecoderError::StreamError(e)) => {
-                            warn!("Error reading data stream: {}", e);
-                            stream_in_ref.send(PlayerMsg::NotSupported).ok();
-                            draining = true;
+                            Err(DecoderError::Retry) => {
+                                continue;
+                            }
+
+                            Err(DecoderError::Chained) => {
+                                stream_in_ref.send(PlayerMsg::TrackStarted).ok();
+                                continue;
+                            }
                         }
+                        break;
+                    }
 
-                        Err(DecoderError::Retry) => {
-                            continue;
+                    if let Some(stream) = stream_ref.upgrade() {
+                        // A corked stream is prebuffering, not playing
+                        if let Some(Ok(false)) = in_callback(&stream, |stream| stream.is_corked()) {
+                            state.set_starved(!draining && audio_buf.len() < len);
+
+                            // A short pause is played as silence ahead of
+                            // the audio, as corking for it takes too long
+                            let silence = state.take_silence();
+                            if !silence.is_zero() {
+                                let frame =
+                                    decoder.channels() as usize * decoder.format().size_of();
+                                let bytes =
+                                    decoder.dur_to_samples(silence) as usize / frame * frame;
+                                in_callback(&stream, |stream| {
+                                    stream.write_copy(&vec![0; bytes], 0, SeekMode::Relative)
+                                });
+                            }
                         }
                     }
-                    break;
-                }
 
-                if audio_buf.len() > 0 {
-                    let buf_len = if audio_buf.len() < len {
-                        audio_buf.len()
-                    } else {
-                        len
-                    };
+                    if audio_buf.len() > 0 {
+                        let buf_len = if audio_buf.len() < len {
+                            audio_buf.len()
+                        } else {
+                            len
+                        };
 
-                    let offset = decoder.dur_to_samples(stream_params.skip.take()) as i64;
+                        let offset = decoder.dur_to_samples(stream_params.skip.take()) as i64;
 
-                    if let Some(stream) = stream_ref.upgrade() {
-                        unsafe {
-                            (*stream.as_ptr())
-                                .write_copy(
+                        if let Some(stream) = stream_ref.upgrade() {
+                            in_callback(&stream, |stream| {
+                                stream.write_copy(
                                     &audio_buf.drain(..buf_len).collect::<Vec<u8>>(),
                                     offset,
                                     SeekMode::Relative,
                                 )
-                                .ok();
+                            });
+                            stream_params.written.advance(decoder.bytes_to_dur(buf_len));
                         }
                     }
-                }
 
-                if draining && audio_buf.len() == 0 {
+                    // Pulse only starts playing once its prebuffer is full,
+                    // which a short track never fills, so start it as soon
+                    // as no more audio is to come
+                    if draining && !triggered {
+                        if let Some(stream) = stream_ref.upgrade() {
+                            in_callback(&stream, |stream| stream.trigger(None));
+                        }
+                        triggered = true;
+                    }
+
+                    if draining && audio_buf.len() == 0 {
+                        *drained_ref.borrow_mut() = true;
+                    }
+                }));
+                if let Err(payload) = result {
+                    warn!("Stream failed: {}", threads::panic_message(&*payload));
+                    stream_in_ref.send(PlayerMsg::NotSupported).ok();
                     *drained_ref.borrow_mut() = true;
                 }
             }));
@@ -322,44 +519,81 @@ impl AudioOutput {
                     stream_in_ref.send(PlayerMsg::Drained).ok();
                 }
             })));
-            (*self.mainloop).borrow_mut().unlock();
         }
+        drop(lock);
 
         // Connect playback stream
-        if self.connect_stream(stream.clone(), device).is_err() {
+        if self.connect_stream(&mut stream, max_ahead).is_err() {
+            let _lock = self.mainloop.lock();
+            drop(stream);
             return;
         }
 
+        // Pulse quietly converts the stream if the sink runs at a different
+        // rate or format, which is worth knowing when it sounds wrong
+        let stream_spec = stream.spec;
+        if let Some((sink_spec, sink_volume)) = self.get_sink_info(&stream) {
+            let conversion = if sink_spec.rate != stream_spec.rate {
+                " (pulse resampling)"
+            } else if sink_spec != stream_spec {
+                " (pulse converting)"
+            } else {
+                ""
+            };
+            let output_format = format!(
+                "stream {} -> sink {}{conversion}",
+                spec_to_string(&stream_spec),
+                spec_to_string(&sink_spec)
+            );
+            info!("{output_format}");
+            stream_params.stats.set_output_format(output_format);
+
+            // The usual answer to why Vibe is quieter than expected
+            let percent = sink_volume.0 as f64 * 100.0 / Volume::NORMAL.0 as f64;
+            if percent < SINK_VOLUME_HINT {
+                info!("The sink volume is at {percent:.0}%, which also turns the audio down");
+            }
+        }
+
+        if let Some(port) = self.options.device_port.clone() {
+            self.set_sink_port(&stream, &port);
+        }
+
         stream_in.send(PlayerMsg::StreamEstablished).ok();
         self.enqueue(stream, stream_params.autostart, stream_in.clone());
     }
 
-    fn connect_stream(
-        &mut self,
-        mut stream: Stream,
-        device: &Option<String>,
-    ) -> anyhow::Result<()> {
-        (*self.mainloop).borrow_mut().lock();
+    fn connect_stream(&mut self, stream: &mut Stream, max_ahead: usize) -> anyhow::Result<()> {
+        let lock = self.mainloop.lock();
 
-        // Stream state change callback
+        // Wake the wait below on each change of state, which it checks
         {
             let mainloop_ref = self.mainloop.clone();
-            let stream_ref = self.context.clone();
-            stream.set_state_callback(Some(Box::new(move || {
-                let state = unsafe { (*stream_ref.as_ptr()).get_state() };
-                match state {
-                    State::Ready | State::Failed | State::Terminated => unsafe {
-                        (*mainloop_ref.as_ptr()).signal(false);
-                    },
-                    _ => {}
-                }
-            })));
+            stream.set_state_callback(Some(Box::new(move || mainloop_ref.signal())));
         }
 
         let flags =
             SmFlagSet::START_CORKED | SmFlagSet::AUTO_TIMING_UPDATE | SmFlagSet::INTERPOLATE_TIMING;
 
-        stream.connect_playback(device.as_deref(), None, flags, None, None)?;
+        // Pulse asks for audio to fill its buffer, so capping the buffer
+        // caps how far ahead the stream is decoded. The rest is left to
+        // pulse, u32::MAX meaning its default.
+        let attr = BufferAttr {
+            maxlength: max_ahead.min(u32::MAX as usize - 1) as u32,
+            tlength: u32::MAX,
+            prebuf: u32::MAX,
+            minreq: u32::MAX,
+            fragsize: u32::MAX,
+        };
+        // Each track has a stream of its own, so may go to its own device
+        let device = match self.options.mapped_device(stream.spec.rate) {
+            Some(device) => {
+                info!("Playing {} Hz on {device}, as mapped", stream.spec.rate);
+                Some(device)
+            }
+            None => self.options.device.as_deref(),
+        };
+        stream.connect_playback(device, Some(&attr), flags, None, None)?;
 
         // Wait for stream to be ready
         loop {
@@ -368,29 +602,25 @@ impl AudioOutput {
                     break;
                 }
                 pulse::stream::State::Failed | pulse::stream::State::Terminated => {
-                    (*self.mainloop).borrow_mut().unlock();
-                    (*self.mainloop).borrow_mut().stop();
+                    drop(lock);
+                    self.mainloop.stop();
                     return Err(anyhow!(pulse::error::PAErr(
                         pulse::error::Code::ConnectionTerminated as i32,
                     )));
                 }
-                _ => {
-                    (*self.mainloop).borrow_mut().wait();
-                }
+                _ => lock.wait(),
             }
         }
 
         stream.set_state_callback(None);
-        (*self.mainloop).borrow_mut().unlock();
 
         Ok(())
     }
 
     fn play(&mut self) -> bool {
         if let Some(ref mut stream) = self.playing {
-            (*self.mainloop).borrow_mut().lock();
+            let _lock = self.mainloop.lock();
             stream.play();
-            (*self.mainloop).borrow_mut().unlock();
             true
         } else {
             false
@@ -404,20 +634,28 @@ impl AudioOutput {
         _stream_in: Sender<PlayerMsg>,
     ) {
         if self.playing.is_some() {
+            // Any stream replaced is freed, which needs the lock
+            let _lock = self.mainloop.lock();
             self.next_up = Some(stream);
         } else {
             self.playing = Some(stream);
+            self.paused = false;
             if autostart == slimproto::proto::AutoStart::Auto {
                 self.play();
             }
         }
     }
 
-    pub fn unpause(&mut self) -> bool {
+    /// Resume playing, first refilling the buffer if `refill`, e.g. after
+    /// a long pause.
+    pub fn unpause(&mut self, refill: bool) -> bool {
         if let Some(ref mut stream) = self.playing {
-            (*self.mainloop).borrow_mut().lock();
+            if refill {
+                stream.refill();
+            }
+            let _lock = self.mainloop.lock();
             stream.unpause();
-            (*self.mainloop).borrow_mut().unlock();
+            self.paused = false;
             true
         } else {
             false
@@ -426,9 +664,9 @@ impl AudioOutput {
 
     pub fn pause(&mut self) -> bool {
         if let Some(ref mut stream) = self.playing {
-            (*self.mainloop).borrow_mut().lock();
+            let _lock = self.mainloop.lock();
             stream.pause();
-            (*self.mainloop).borrow_mut().unlock();
+            self.paused = true;
             true
         } else {
             false
@@ -436,13 +674,17 @@ impl AudioOutput {
     }
 
     pub fn stop(&mut self) {
-        if let Some(ref mut stream) = self.playing {
-            (*self.mainloop).borrow_mut().lock();
-            stream.disconnect().ok();
-            (*self.mainloop).borrow_mut().unlock();
+        {
+            // Held while the streams are freed too
+            let _lock = self.mainloop.lock();
+            if let Some(ref mut stream) = self.playing {
+                stream.disconnect().ok();
+            }
+            self.next_up = None;
+            self.playing = None;
         }
-        self.next_up = None;
-        self.playing = None;
+        self.paused = false;
+        self.disconnect_retired();
     }
 
     pub fn flush(&mut self) {
@@ -454,42 +696,215 @@ impl AudioOutput {
         self.playing = self.next_up.take();
 
         if let Some(old_stream) = old_stream {
-            if let Some(pa_stream) = Rc::into_inner(old_stream.into_inner()) {
-                let mut pa_stream = pa_stream.into_inner();
-                std::thread::spawn(move || {
-                    std::thread::sleep(Duration::from_secs(1));
-                    pa_stream.disconnect().ok();
-                });
-            };
+            self.retired.borrow_mut().push((Instant::now(), old_stream));
         }
+        self.disconnect_retired();
+    }
+
+    // Disconnect the streams that played out a while ago. This is done
+    // here, rather than on a thread of its own, as it needs the lock.
+    // Status updates ask for the position often enough to come by soon.
+    fn disconnect_retired(&self) {
+        let mut retired = self.retired.borrow_mut();
+        if retired.is_empty() {
+            return;
+        }
+        let _lock = self.mainloop.lock();
+        retired.retain_mut(|(at, stream)| {
+            if at.elapsed() < RETIRE_AFTER {
+                return true;
+            }
+            stream.disconnect().ok();
+            false
+        });
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing.is_some()
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::Relaxed)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
     }
 
     pub fn get_dur(&self) -> Duration {
+        self.disconnect_retired();
+        match self.playing {
+            Some(ref stream) => {
+                let _lock = self.mainloop.lock();
+                stream.get_pos()
+            }
+            None => Duration::ZERO,
+        }
+    }
+
+    pub fn get_written(&self) -> Duration {
         match self.playing {
-            Some(ref stream) => stream.get_pos(),
+            Some(ref stream) => stream.written.written(),
             None => Duration::ZERO,
         }
     }
 
+    // The format of the sink the stream plays on, and its loudest channel
+    fn get_sink_info(&self, stream: &Stream) -> Option<(Spec, Volume)> {
+        let (s, r) = bounded(2);
+
+        // Each operation is let go of while locked, it carries on
+        // regardless and answers through the channel
+        let asked = {
+            let _lock = self.mainloop.lock();
+            let device = stream.get_device_name();
+            device.map(|device| {
+                (*self.context)
+                    .borrow_mut()
+                    .introspect()
+                    .get_sink_info_by_name(&device, move |listresult| match listresult {
+                        ListResult::Item(item) => {
+                            s.try_send(Some((item.sample_spec, item.volume.max()))).ok();
+                        }
+                        ListResult::End | ListResult::Error => {
+                            s.try_send(None).ok();
+                        }
+                    });
+            })
+        };
+
+        asked?;
+        r.recv_timeout(Duration::from_secs(1)).ok().flatten()
+    }
+
+    // Failing to switch port is not worth stopping playback for
+    fn set_sink_port(&mut self, stream: &Stream, port: &str) {
+        let device = {
+            let _lock = self.mainloop.lock();
+            stream.get_device_name()
+        };
+        let Some(device) = device else {
+            return;
+        };
+        if self.port_sink.as_ref() == Some(&device) {
+            return;
+        }
+        self.port_sink = Some(device.clone());
+
+        let (s, r) = bounded(1);
+        {
+            let _lock = self.mainloop.lock();
+            (*self.context)
+                .borrow_mut()
+                .introspect()
+                .set_sink_port_by_name(
+                    &device,
+                    port,
+                    Some(Box::new(move |success| {
+                        s.try_send(success).ok();
+                    })),
+                );
+        }
+
+        match r.recv_timeout(Duration::from_secs(1)) {
+            Ok(true) => info!("Switched {device} to port {port}"),
+            _ => {
+                let ports = self.get_output_device_ports(&device);
+                match ports.iter().find(|(_, active)| *active) {
+                    Some((active, _)) => {
+                        warn!("Unable to switch {device} to port {port}, playing on {active}")
+                    }
+                    None => warn!("Unable to switch {device} to port {port}"),
+                }
+            }
+        }
+    }
+
+    pub fn get_system_info(&self) -> Vec<(String, String)> {
+        let (s, r) = bounded(1);
+
+        {
+            let _lock = self.mainloop.lock();
+            (*self.context)
+                .borrow_mut()
+                .introspect()
+                .get_server_info(move |info| {
+                    let server = format!(
+                        "{} {}",
+                        info.server_name.as_deref().unwrap_or("unknown"),
+                        info.server_version.as_deref().unwrap_or("")
+                    );
+                    let sink = info.default_sink_name.as_ref().map(|name| name.to_string());
+                    s.try_send((server, sink)).ok();
+                });
+        }
+
+        let mut ret = Vec::new();
+        if let Ok((server, sink)) = r.recv_timeout(Duration::from_secs(1)) {
+            ret.push(("Server".to_owned(), server.trim_end().to_owned()));
+            ret.push((
+                "Default device".to_owned(),
+                sink.unwrap_or_else(|| "none".to_owned()),
+            ));
+        }
+        ret
+    }
+
+    pub fn get_output_device_ports(&self, device: &str) -> Vec<(String, bool)> {
+        let (s, r) = bounded(1);
+
+        {
+            let _lock = self.mainloop.lock();
+            (*self.context)
+                .borrow_mut()
+                .introspect()
+                .get_sink_info_by_name(device, move |listresult| match listresult {
+                    ListResult::Item(item) => {
+                        let active = item
+                            .active_port
+                            .as_ref()
+                            .and_then(|port| port.name.to_owned())
+                            .map(|name| name.to_string());
+                        let ports = item
+                            .ports
+                            .iter()
+                            .filter_map(|port| port.name.to_owned().map(|name| name.to_string()))
+                            .map(|name| {
+                                let is_active = active.as_ref() == Some(&name);
+                                (name, is_active)
+                            })
+                            .collect::<Vec<_>>();
+                        s.try_send(ports).ok();
+                    }
+                    ListResult::End | ListResult::Error => {
+                        s.try_send(Vec::new()).ok();
+                    }
+                });
+        }
+
+        r.recv_timeout(Duration::from_secs(1)).unwrap_or_default()
+    }
+
     pub fn get_output_device_names(&self) -> anyhow::Result<Vec<(String, Option<String>)>> {
         let mut ret = Vec::new();
         let (s, r) = bounded(1);
 
-        (*self.mainloop).borrow_mut().lock();
-        let _op = (*self.context)
-            .borrow_mut()
-            .introspect()
-            .get_sink_info_list(move |listresult| match listresult {
-                ListResult::Item(item) => {
-                    let name = item.name.to_owned().unwrap_or_default().to_string();
-                    let description = item.description.to_owned().map(|n| n.to_string());
-                    s.send(Some((name, description))).ok();
-                }
-                ListResult::End | ListResult::Error => {
-                    s.send(None).ok();
-                }
-            });
-        (*self.mainloop).borrow_mut().unlock();
+        {
+            let _lock = self.mainloop.lock();
+            (*self.context)
+                .borrow_mut()
+                .introspect()
+                .get_sink_info_list(move |listresult| match listresult {
+                    ListResult::Item(item) => {
+                        let name = item.name.to_owned().unwrap_or_default().to_string();
+                        let description = item.description.to_owned().map(|n| n.to_string());
+                        s.send(Some((name, description))).ok();
+                    }
+                    ListResult::End | ListResult::Error => {
+                        s.send(None).ok();
+                    }
+                });
+        }
 
         while let Some(item) = r.recv()? {
             ret.push(item);
@@ -499,8 +914,20 @@ impl AudioOutput {
     }
 }
 
+fn spec_to_string(spec: &Spec) -> String {
+    format!("{} Hz {:?} {}ch", spec.rate, spec.format, spec.channels)
+}
+
 impl Drop for AudioOutput {
     fn drop(&mut self) {
-        (*self.context).borrow_mut().disconnect();
+        let _lock = self.mainloop.lock();
+        self.retired.get_mut().clear();
+        self.next_up = None;
+        self.playing = None;
+        // Disconnecting runs the state callback at once, while the context
+        // is borrowed for the call
+        let mut context = (*self.context).borrow_mut();
+        context.set_state_callback(None);
+        context.disconnect();
     }
 }
@@ -0,0 +1,144 @@
+//! Sample rate conversion, for an output locked to one rate.
+//!
+//! Each output sample is the input around its position weighted by a sinc,
+//! cut off below the lower of the two Nyquist frequencies so that
+//! downsampling does not alias, and shaped by a Blackman window. The kernel
+//! is tabulated at `PHASES` positions between input samples and
+//! interpolated between them. Positions are kept as exact fractions of
+//! the input rate, so the output never drifts from the input.
+
+use std::f64::consts::PI;
+
+use symphonia::core::audio::{AudioBuffer, Channels, Signal, SignalSpec};
+
+// Input samples used on each side of an output sample
+const TAPS: usize = 32;
+const PHASES: usize = 512;
+// Of the lower Nyquist frequency, leaving room for the window's roll-off
+const CUTOFF: f64 = 0.91;
+
+/// Converts a stream from one sample rate to another, a buffer at a time.
+pub(crate) struct Resampler {
+    from: u32,
+    to: u32,
+    channels: Channels,
+    // PHASES + 1 rows of 2 * TAPS weights, for input samples from TAPS - 1
+    // before the output's position to TAPS after
+    table: Vec<f32>,
+    // Input not yet used up, for each channel
+    history: Vec<Vec<f32>>,
+    // The next output's position in the history, a whole number of input
+    // samples and a fraction in units of 1 / `to`
+    whole: usize,
+    frac: u64,
+    finished: bool,
+}
+
+impl Resampler {
+    pub(crate) fn new(from: u32, to: u32, channels: Channels) -> Self {
+        let cutoff = CUTOFF * (to as f64 / from as f64).min(1.0);
+        let mut table = Vec::with_capacity((PHASES + 1) * 2 * TAPS);
+        for phase in 0..=PHASES {
+            let offset = phase as f64 / PHASES as f64;
+            let row = (0..2 * TAPS).map(|tap| {
+                let t = tap as f64 - (TAPS - 1) as f64 - offset;
+                let x = t / TAPS as f64;
+                let window = 0.42 + 0.5 * (PI * x).cos() + 0.08 * (2.0 * PI * x).cos();
+                let arg = PI * cutoff * t;
+                let sinc = if arg.abs() < 1e-9 {
+                    1.0
+                } else {
+                    arg.sin() / arg
+                };
+                cutoff * sinc * window
+            });
+            let row = row.collect::<Vec<f64>>();
+            // Each row passes a constant level exactly
+            let sum = row.iter().sum::<f64>();
+            table.extend(row.iter().map(|weight| (weight / sum) as f32));
+        }
+
+        Self {
+            from,
+            to,
+            channels,
+            table,
+            // The first output is at the first input, with silence before
+            history: vec![vec![0.0; TAPS - 1]; channels.count()],
+            whole: TAPS - 1,
+            frac: 0,
+            finished: false,
+        }
+    }
+
+    /// Convert a buffer, returning what can be output so far. The last
+    /// few input samples wait for those that follow them.
+    pub(crate) fn process(&mut self, input: &AudioBuffer<f32>) -> AudioBuffer<f32> {
+        for (chan, history) in self.history.iter_mut().enumerate() {
+            history.extend_from_slice(input.chan(chan));
+        }
+        let end = self.history[0].len();
+        self.resample(end)
+    }
+
+    /// Output what is left at the end of the stream, once.
+    pub(crate) fn finish(&mut self) -> Option<AudioBuffer<f32>> {
+        if self.finished {
+            return None;
+        }
+        self.finished = true;
+        let end = self.history[0].len();
+        for history in self.history.iter_mut() {
+            history.resize(end + TAPS, 0.0);
+        }
+        Some(self.resample(end))
+    }
+
+    // Output every sample before `end` with the input after it to hand,
+    // then drop the input no later output needs
+    fn resample(&mut self, end: usize) -> AudioBuffer<f32> {
+        let len = self.history[0].len();
+        let (mut whole, mut frac) = (self.whole, self.frac);
+        let mut positions = Vec::new();
+        while whole < end && whole + TAPS < len {
+            positions.push((whole, frac));
+            frac += self.from as u64;
+            whole += (frac / self.to as u64) as usize;
+            frac %= self.to as u64;
+        }
+
+        let mut output = AudioBuffer::<f32>::new(
+            positions.len().max(1) as u64,
+            SignalSpec::new(self.to, self.channels),
+        );
+        output.render_reserved(Some(positions.len()));
+
+        let mut weights = [0.0f32; 2 * TAPS];
+        for (frame, &(at, frac)) in positions.iter().enumerate() {
+            let phase = frac as f64 * PHASES as f64 / self.to as f64;
+            let row = (phase as usize).min(PHASES - 1);
+            let blend = (phase - row as f64) as f32;
+            let (this, next) = self.table[row * 2 * TAPS..(row + 2) * 2 * TAPS].split_at(2 * TAPS);
+            for (weight, (a, b)) in weights.iter_mut().zip(this.iter().zip(next)) {
+                *weight = a + (b - a) * blend;
+            }
+
+            let first = at + 1 - TAPS;
+            for (chan, history) in self.history.iter().enumerate() {
+                output.chan_mut(chan)[frame] = history[first..first + 2 * TAPS]
+                    .iter()
+                    .zip(&weights)
+                    .map(|(sample, weight)| sample * weight)
+                    .sum();
+            }
+        }
+
+        let used = whole + 1 - TAPS;
+        for history in self.history.iter_mut() {
+            history.drain(..used);
+        }
+        self.whole = whole - used;
+        self.frac = frac;
+        output
+    }
+}
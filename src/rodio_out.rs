@@ -1,17 +1,23 @@
-use std::{collections::VecDeque, time::Duration};
+use std::{
+    collections::VecDeque,
+    panic::{self, AssertUnwindSafe},
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::{self, bail, Context};
 use crossbeam::channel::Sender;
-use log::warn;
+use log::{info, warn};
 use rodio::{
     cpal::traits::HostTrait, Device, DeviceTrait, OutputStream, OutputStreamHandle, Sink, Source,
 };
 use slimproto::proto::AutoStart;
 
 use crate::{
+    audio_out::{OutputOptions, OutputState, WriteMarker},
     decode::{Decoder, DecoderError},
     message::PlayerMsg,
-    StreamParams,
+    threads, StreamParams,
 };
 
 const MIN_AUDIO_BUFFER_SIZE: usize = 4 * 1024;
@@ -21,8 +27,11 @@ pub struct DecoderSource {
     frame: VecDeque<f32>,
     stream_params: StreamParams,
     stream_in: Sender<PlayerMsg>,
+    state: Arc<OutputState>,
     start_flag: bool,
     eod_flag: bool,
+    skip_samples: usize,
+    samples_out: usize,
 }
 
 impl DecoderSource {
@@ -31,14 +40,70 @@ impl DecoderSource {
         stream_params: StreamParams,
         capacity: usize,
         stream_in: Sender<PlayerMsg>,
+        state: Arc<OutputState>,
     ) -> Self {
         DecoderSource {
             decoder,
             frame: VecDeque::with_capacity(capacity),
             stream_params,
             stream_in,
+            state,
             start_flag: true,
             eod_flag: false,
+            skip_samples: 0,
+            samples_out: 0,
+        }
+    }
+
+    // Only called as the frame runs low to keep the per-sample path cheap,
+    // so the marker trails the samples handed out by up to a frame
+    fn mark_written(&mut self) {
+        if self.samples_out > 0 {
+            let samples_per_sec =
+                self.decoder.sample_rate() as u64 * self.decoder.channels() as u64;
+            self.stream_params.written.advance(Duration::from_micros(
+                self.samples_out as u64 * 1_000_000 / samples_per_sec.max(1),
+            ));
+            self.samples_out = 0;
+        }
+    }
+
+    fn fill_frame(&mut self) {
+        let mut audio_buf = Vec::with_capacity(self.frame.capacity());
+        loop {
+            match self.decoder.fill_sample_buffer::<f32>(
+                &mut audio_buf,
+                Some(2 * MIN_AUDIO_BUFFER_SIZE),
+                self.stream_params.volume.clone(),
+            ) {
+                Ok(()) => {}
+
+                Err(DecoderError::EndOfDecode) => {
+                    if !self.eod_flag {
+                        self.stream_in.send(PlayerMsg::EndOfDecode).ok();
+                        self.eod_flag = true;
+                    }
+                }
+
+                Err(DecoderError::StreamError(e)) => {
+                    warn!("Error reading data stream: {}", e);
+                    self.stream_in.send(PlayerMsg::NotSupported).ok();
+                }
+
+                Err(DecoderError::Retry) => {
+                    continue;
+                }
+
+                Err(DecoderError::Chained) => {
+                    self.stream_in.send(PlayerMsg::TrackStarted).ok();
+                    continue;
+                }
+            }
+
+            if audio_buf.len() > 0 {
+                self.frame.extend(audio_buf);
+            }
+            break;
         }
     }
 }
@@ -73,44 +138,50 @@ impl Iterator for DecoderSource {
             self.start_flag = false;
         }
 
-        if self.frame.len() < MIN_AUDIO_BUFFER_SIZE && !self.eod_flag {
-            let mut audio_buf = Vec::with_capacity(self.frame.capacity());
-            loop {
-                match self.decoder.fill_sample_buffer::<f32>(
-                    &mut audio_buf,
-                    Some(2 * MIN_AUDIO_BUFFER_SIZE),
-                    self.stream_params.volume.clone(),
-                ) {
-                    Ok(()) => {}
-
-                    Err(DecoderError::EndOfDecode) => {
-                        if !self.eod_flag {
-                            self.stream_in.send(PlayerMsg::EndOfDecode).ok();
-                            self.eod_flag = true;
-                        }
-                    }
-
-                    Err(DecoderError::StreamError(e)) => {
-                        warn!("Error reading data stream: {}", e);
-                        self.stream_in.send(PlayerMsg::NotSupported).ok();
-                    }
+        // Skip ahead by discarding whole frames of samples, checked only
+        // when the frame runs low to keep the per-sample path cheap
+        if self.frame.len() < MIN_AUDIO_BUFFER_SIZE {
+            self.mark_written();
+            let skip = self.stream_params.skip.take();
+            if !skip.is_zero() {
+                let frames = self.decoder.sample_rate() as u128 * skip.as_micros() / 1_000_000;
+                self.skip_samples += frames as usize * self.decoder.channels() as usize;
+            }
+        }
 
-                    Err(DecoderError::Retry) => {
-                        continue;
-                    }
+        loop {
+            if self.frame.len() < MIN_AUDIO_BUFFER_SIZE && !self.eod_flag {
+                // A panic here would take down the audio thread, so end
+                // this stream instead
+                if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| self.fill_frame())) {
+                    warn!("Stream failed: {}", threads::panic_message(&*payload));
+                    self.stream_in.send(PlayerMsg::NotSupported).ok();
+                    self.eod_flag = true;
                 }
+                // Only the playing source is pulled, so this is its health
+                self.state
+                    .set_starved(!self.eod_flag && self.frame.len() < MIN_AUDIO_BUFFER_SIZE);
+            }
 
-                if audio_buf.len() > 0 {
-                    self.frame.extend(audio_buf);
-                }
+            if self.skip_samples == 0 || self.frame.is_empty() {
                 break;
             }
+
+            let num_samples = self.skip_samples.min(self.frame.len());
+            self.frame.drain(..num_samples);
+            self.skip_samples -= num_samples;
         }
 
-        self.frame.pop_front().or_else(|| {
-            self.stream_in.send(PlayerMsg::Drained).ok();
-            None
-        })
+        match self.frame.pop_front() {
+            Some(sample) => {
+                self.samples_out += 1;
+                Some(sample)
+            }
+            None => {
+                self.stream_in.send(PlayerMsg::Drained).ok();
+                None
+            }
+        }
     }
 }
 
@@ -146,19 +217,39 @@ impl Stream {
     fn stop(&self) {
         self.sink.stop();
     }
+
+    // The sink's volume applies at once, unlike the volume applied as the
+    // audio is decoded
+    fn fade_out(&self, length: Duration) {
+        const STEPS: u32 = 10;
+        let start = self.sink.volume();
+        for step in 1..=STEPS {
+            std::thread::sleep(length / STEPS);
+            self.sink
+                .set_volume(start * (1.0 - step as f32 / STEPS as f32));
+        }
+    }
 }
 
 pub struct AudioOutput {
     host: rodio::cpal::Host,
     device: rodio::cpal::Device,
     playing: Option<Stream>,
+    next_up: Option<DecoderSource>,
+    format: (u32, u16),
+    // The device mapped for the playing sink's rate, if any
+    mapped: Option<String>,
+    written: WriteMarker,
+    paused: bool,
+    options: OutputOptions,
+    state: Arc<OutputState>,
 }
 
 impl AudioOutput {
-    pub fn try_new(device_name: &Option<String>) -> anyhow::Result<Self> {
+    pub fn try_new(options: &OutputOptions, state: Arc<OutputState>) -> anyhow::Result<Self> {
         let host = rodio::cpal::default_host();
-        let device = if let Some(dev_name) = device_name {
-            match find_device(&host, &dev_name) {
+        let device = if let Some(ref dev_name) = options.device {
+            match find_device(&host, dev_name) {
                 Some(device) => device,
                 None => {
                     bail!("Cannot find device: {dev_name}");
@@ -172,6 +263,13 @@ impl AudioOutput {
             host,
             device,
             playing: None,
+            next_up: None,
+            format: (0, 0),
+            mapped: None,
+            written: WriteMarker::new(),
+            paused: false,
+            options: options.clone(),
+            state,
         })
     }
 
@@ -180,40 +278,110 @@ impl AudioOutput {
         decoder: Decoder,
         stream_in: Sender<PlayerMsg>,
         stream_params: StreamParams,
-        _device: &Option<String>,
     ) {
         let autostart = stream_params.autostart == AutoStart::Auto;
+        let mapped = self
+            .options
+            .mapped_device(decoder.sample_rate())
+            .map(str::to_owned);
+
+        // The device runs at its default configuration and rodio converts
+        // the stream to suit, which is worth knowing when it sounds wrong
+        let device = self.device_for(mapped.as_deref());
+        if let Ok(config) = device.default_output_config() {
+            let device_rate = config.sample_rate().0;
+            let output_format = format!(
+                "stream {} Hz {}ch -> device {} Hz {}ch{}",
+                decoder.sample_rate(),
+                decoder.channels(),
+                device_rate,
+                config.channels(),
+                if device_rate != decoder.sample_rate() {
+                    " (rodio resampling)"
+                } else {
+                    ""
+                }
+            );
+            info!("{output_format}");
+            stream_params.stats.set_output_format(output_format);
+        }
 
         let capacity = decoder.dur_to_samples(stream_params.output_threshold) as usize;
-        let decoder_source =
-            DecoderSource::new(decoder, stream_params, capacity, stream_in.clone());
+        let decoder_source = DecoderSource::new(
+            decoder,
+            stream_params,
+            capacity,
+            stream_in.clone(),
+            self.state.clone(),
+        );
 
         stream_in.send(PlayerMsg::StreamEstablished).ok();
 
-        if let Some(ref mut playing_stream) = self.playing {
-            playing_stream.play(decoder_source);
-        } else {
-            if let Ok(mut stream) = Stream::try_from_device(&self.device) {
-                stream.play(decoder_source);
-                if !autostart {
-                    stream.pause();
-                }
-                self.playing = Some(stream);
+        // A sink is opened for the format of its first source on one
+        // device, so a change of rate or channels, or of the device mapped
+        // for the rate, needs a new one once the current track ends
+        let format = (decoder_source.sample_rate(), decoder_source.channels());
+        match self.playing {
+            Some(ref mut playing_stream) if self.format == format && self.mapped == mapped => {
+                playing_stream.play(decoder_source);
+            }
+            Some(_) if self.mapped != mapped => {
+                info!("Output device changes, starting a new sink at the next track");
+                self.next_up = Some(decoder_source);
             }
+            Some(_) => {
+                info!("Stream format changes, starting a new sink at the next track");
+                self.next_up = Some(decoder_source);
+            }
+            None => self.start_stream(decoder_source, autostart),
         }
     }
 
-    pub fn unpause(&self) -> bool {
+    // The device mapped for a rate, else the output's own. A mapped device
+    // that has since gone is reported, and the output's own used instead.
+    fn device_for(&self, mapped: Option<&str>) -> Device {
+        let Some(name) = mapped else {
+            return self.device.clone();
+        };
+        find_device(&self.host, name).unwrap_or_else(|| {
+            warn!("Cannot find device: {name}, playing on the usual device");
+            self.device.clone()
+        })
+    }
+
+    fn start_stream(&mut self, decoder_source: DecoderSource, autostart: bool) {
+        let format = (decoder_source.sample_rate(), decoder_source.channels());
+        let written = decoder_source.stream_params.written.clone();
+        let mapped = self.options.mapped_device(format.0).map(str::to_owned);
+        if let Some(ref name) = mapped {
+            info!("Playing {} Hz on {name}, as mapped", format.0);
+        }
+        if let Ok(mut stream) = Stream::try_from_device(&self.device_for(mapped.as_deref())) {
+            stream.play(decoder_source);
+            if !autostart {
+                stream.pause();
+            }
+            self.playing = Some(stream);
+            self.format = format;
+            self.mapped = mapped;
+            self.written = written;
+            self.paused = false;
+        }
+    }
+
+    pub fn unpause(&mut self) -> bool {
         if let Some(ref stream) = self.playing {
             (*stream).unpause();
+            self.paused = false;
             return true;
         }
         false
     }
 
-    pub fn pause(&self) -> bool {
+    pub fn pause(&mut self) -> bool {
         if let Some(ref stream) = self.playing {
             (*stream).pause();
+            self.paused = true;
             return true;
         }
         false
@@ -226,12 +394,38 @@ impl AudioOutput {
         self.flush();
     }
 
+    /// Fade what is playing to silence over `length`, without stopping.
+    pub fn fade_out(&mut self, length: Duration) {
+        if let Some(ref stream) = self.playing {
+            if !self.paused {
+                stream.fade_out(length);
+            }
+        }
+    }
+
     pub fn flush(&mut self) {
         self.playing = None;
+        self.next_up = None;
+        self.paused = false;
     }
 
     pub fn shift(&mut self) {
-        // Noop - uses rodio's stream append
+        // Tracks in the same format are appended to the playing sink, only
+        // one held back for a format change needs moving in
+        if let Some(decoder_source) = self.next_up.take() {
+            let paused = self.paused;
+            self.playing = None;
+            self.start_stream(decoder_source, !paused);
+            self.paused = paused;
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing.is_some()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
     }
 
     pub fn get_dur(&self) -> Duration {
@@ -241,6 +435,23 @@ impl AudioOutput {
         }
     }
 
+    // Sources appended to a playing sink share its position, so this
+    // follows the stream that opened the sink
+    pub fn get_written(&self) -> Duration {
+        self.written.written()
+    }
+
+    pub fn get_system_info(&self) -> Vec<(String, String)> {
+        let default = self
+            .host
+            .default_output_device()
+            .and_then(|device| device.name().ok());
+        vec![(
+            "Default device".to_owned(),
+            default.unwrap_or_else(|| "none".to_owned()),
+        )]
+    }
+
     pub fn get_output_device_names(&self) -> anyhow::Result<Vec<(String, Option<String>)>> {
         let devices = self.host.output_devices()?;
         Ok(devices
@@ -251,10 +462,10 @@ impl AudioOutput {
     }
 }
 
-fn find_device(host: &rodio::cpal::Host, name: &String) -> Option<Device> {
+fn find_device(host: &rodio::cpal::Host, name: &str) -> Option<Device> {
     let mut output_devices = host.output_devices().ok()?;
     output_devices.find(|d| match d.name() {
-        Ok(n) => n == *name,
+        Ok(n) => n == name,
         Err(_) => false,
     })
 }
@@ -0,0 +1,215 @@
+//! Submit what is played to ListenBrainz.
+//!
+//! A track is sent as playing now when it starts and as a listen once half
+//! of it, or four minutes, has played. Tracks without a title and artist
+//! are skipped. Submissions are made from a background thread and retried
+//! a few times before being given up.
+
+use std::{
+    collections::VecDeque,
+    sync::{Mutex, OnceLock},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crossbeam::channel::{unbounded, Sender};
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+
+use crate::{http, track_info::TrackInfo};
+
+const SUBMIT_URL: &str = "https://api.listenbrainz.org/1/submit-listens";
+const LISTEN_AFTER: Duration = Duration::from_secs(4 * 60);
+const RETRY_DELAYS: [Duration; 3] = [
+    Duration::from_secs(5),
+    Duration::from_secs(30),
+    Duration::from_secs(120),
+];
+
+static SCROBBLER: OnceLock<Mutex<Scrobbler>> = OnceLock::new();
+
+#[derive(Clone)]
+pub struct Track {
+    title: String,
+    artist: String,
+    album: Option<String>,
+    duration: Option<Duration>,
+}
+
+impl Track {
+    /// The track described by a stream's tags, if they give a title and
+    /// an artist.
+    pub fn from_info(info: &TrackInfo, duration: Option<Duration>) -> Option<Self> {
+        Some(Self {
+            title: info.title.clone()?,
+            artist: info.track_artist()?.to_owned(),
+            album: info.album.clone(),
+            duration,
+        })
+    }
+
+    fn metadata(&self) -> Value {
+        let mut metadata = json!({
+            "artist_name": self.artist,
+            "track_name": self.title,
+        });
+        if let Some(ref album) = self.album {
+            metadata["release_name"] = json!(album);
+        }
+        metadata
+    }
+
+    // ListenBrainz counts a listen after half the track or four minutes,
+    // whichever comes first
+    fn listen_after(&self) -> Duration {
+        match self.duration {
+            Some(duration) => (duration / 2).min(LISTEN_AFTER),
+            None => LISTEN_AFTER,
+        }
+    }
+}
+
+struct Playing {
+    track: Option<Track>,
+    started_at: SystemTime,
+    submitted: bool,
+}
+
+struct Scrobbler {
+    submit_tx: Sender<Value>,
+    queued: VecDeque<Option<Track>>,
+    playing: Option<Playing>,
+}
+
+impl Scrobbler {
+    fn start_next(&mut self) {
+        self.playing = self.queued.pop_front().map(|track| {
+            if let Some(ref track) = track {
+                debug!(
+                    "Scrobbling now playing: {} by {}",
+                    track.title, track.artist
+                );
+                self.submit_tx
+                    .send(json!({
+                        "listen_type": "playing_now",
+                        "payload": [{ "track_metadata": track.metadata() }],
+                    }))
+                    .ok();
+            }
+            Playing {
+                track,
+                started_at: SystemTime::now(),
+                submitted: false,
+            }
+        });
+    }
+}
+
+/// Submit listens to ListenBrainz with this user token. Only the first
+/// call has any effect.
+pub fn set_token(token: String) {
+    let (submit_tx, submit_rx) = unbounded::<Value>();
+    let scrobbler = Scrobbler {
+        submit_tx,
+        queued: VecDeque::new(),
+        playing: None,
+    };
+    if SCROBBLER.set(Mutex::new(scrobbler)).is_err() {
+        return;
+    }
+
+    thread::spawn(move || {
+        let mut warned = false;
+        for body in submit_rx {
+            let mut delays = RETRY_DELAYS.iter();
+            loop {
+                match http::agent()
+                    .post(SUBMIT_URL)
+                    .set("Authorization", &format!("Token {token}"))
+                    .send_json(&body)
+                {
+                    Ok(_) => break,
+                    Err(e) => match delays.next() {
+                        Some(delay) => thread::sleep(*delay),
+                        None => {
+                            if !warned {
+                                warn!("Unable to submit to ListenBrainz: {e}");
+                                warned = true;
+                            }
+                            break;
+                        }
+                    },
+                }
+            }
+        }
+    });
+    info!("Scrobbling to ListenBrainz");
+}
+
+fn with_scrobbler(f: impl FnOnce(&mut Scrobbler)) {
+    if let Some(Ok(mut scrobbler)) = SCROBBLER.get().map(|s| s.lock()) {
+        f(&mut scrobbler);
+    }
+}
+
+/// A new stream has been queued for playing, `None` if it cannot be
+/// scrobbled.
+pub fn queue(track: Option<Track>) {
+    with_scrobbler(|scrobbler| scrobbler.queued.push_back(track));
+}
+
+/// Output has started with nothing playing before.
+pub fn track_started() {
+    with_scrobbler(|scrobbler| {
+        if scrobbler.playing.is_none() {
+            scrobbler.start_next();
+        }
+    });
+}
+
+/// The playing track has finished and the next queued one, if any, has
+/// taken over.
+pub fn track_ended() {
+    with_scrobbler(|scrobbler| scrobbler.start_next());
+}
+
+/// Playback has stopped, forget what was playing and queued.
+pub fn stop() {
+    with_scrobbler(|scrobbler| {
+        scrobbler.queued.clear();
+        scrobbler.playing = None;
+    });
+}
+
+/// The position in the playing track, which only moves while it plays.
+pub fn progress(position: Duration) {
+    with_scrobbler(|scrobbler| {
+        let Some(ref mut playing) = scrobbler.playing else {
+            return;
+        };
+        let Some(ref track) = playing.track else {
+            return;
+        };
+        if playing.submitted || position < track.listen_after() {
+            return;
+        }
+
+        playing.submitted = true;
+        let listened_at = playing
+            .started_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        debug!("Scrobbling listen: {} by {}", track.title, track.artist);
+        scrobbler
+            .submit_tx
+            .send(json!({
+                "listen_type": "single",
+                "payload": [{
+                    "listened_at": listened_at,
+                    "track_metadata": track.metadata(),
+                }],
+            }))
+            .ok();
+    });
+}
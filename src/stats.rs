@@ -0,0 +1,115 @@
+use std::{fmt, sync::Mutex, time::Duration};
+
+use crossbeam::atomic::AtomicCell;
+
+/// Running counters kept for diagnosing playback problems.
+#[derive(Default)]
+pub struct Stats {
+    dropped_status: AtomicCell<usize>,
+    coalesced_events: AtomicCell<usize>,
+    output_format: Mutex<Option<String>>,
+    sync_error: AtomicCell<i64>,
+    sync_corrections: AtomicCell<usize>,
+    clock_drift_ppm: AtomicCell<Option<f64>>,
+    server_connect: AtomicCell<Option<Duration>>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that a droppable status message was discarded because the
+    /// server connection could not keep up, returning the new total.
+    pub fn add_dropped_status(&self) -> usize {
+        self.dropped_status.fetch_add(1) + 1
+    }
+
+    pub fn dropped_status(&self) -> usize {
+        self.dropped_status.load()
+    }
+
+    /// Record that a queued player event was left out as a later one of
+    /// the same kind says the same, returning the new total.
+    pub fn add_coalesced_event(&self) -> usize {
+        self.coalesced_events.fetch_add(1) + 1
+    }
+
+    pub fn coalesced_events(&self) -> usize {
+        self.coalesced_events.load()
+    }
+
+    /// Record how the current stream is being played by the audio system,
+    /// e.g. whether it is being resampled.
+    pub fn set_output_format(&self, format: String) {
+        if let Ok(mut output_format) = self.output_format.lock() {
+            *output_format = Some(format);
+        }
+    }
+
+    pub fn output_format(&self) -> Option<String> {
+        self.output_format.lock().ok().and_then(|f| f.clone())
+    }
+
+    /// Record the latest gap between playback and the wall clock, in ms,
+    /// positive when playback is ahead.
+    pub fn set_sync_error(&self, error_ms: i64) {
+        self.sync_error.store(error_ms);
+    }
+
+    pub fn sync_error(&self) -> i64 {
+        self.sync_error.load()
+    }
+
+    /// Record a correction for clock drift, returning the new total.
+    pub fn add_sync_correction(&self) -> usize {
+        self.sync_corrections.fetch_add(1) + 1
+    }
+
+    pub fn sync_corrections(&self) -> usize {
+        self.sync_corrections.load()
+    }
+
+    /// Record how fast the output clock runs against the wall clock, in
+    /// parts per million, positive when it runs fast.
+    pub fn set_clock_drift_ppm(&self, ppm: f64) {
+        self.clock_drift_ppm.store(Some(ppm));
+    }
+
+    pub fn clock_drift_ppm(&self) -> Option<f64> {
+        self.clock_drift_ppm.load()
+    }
+
+    /// Record how long the latest check took to connect to the server, or
+    /// `None` if it could not connect.
+    pub fn set_server_connect(&self, took: Option<Duration>) {
+        self.server_connect.store(took);
+    }
+
+    pub fn server_connect(&self) -> Option<Duration> {
+        self.server_connect.load()
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "dropped timer status: {}", self.dropped_status())?;
+        write!(f, ", coalesced events: {}", self.coalesced_events())?;
+        write!(
+            f,
+            ", sync error: {} ms, sync corrections: {}",
+            self.sync_error(),
+            self.sync_corrections()
+        )?;
+        if let Some(ppm) = self.clock_drift_ppm() {
+            write!(f, ", clock drift: {ppm:+.1} ppm")?;
+        }
+        if let Some(took) = self.server_connect() {
+            write!(f, ", server connect: {} ms", took.as_millis())?;
+        }
+        if let Some(format) = self.output_format() {
+            write!(f, ", output: {format}")?;
+        }
+        Ok(())
+    }
+}
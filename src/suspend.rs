@@ -0,0 +1,74 @@
+//! Notice when the system has been suspended and resumed.
+//!
+//! The monotonic clock behind `Instant` stops while the system sleeps and
+//! the boot time clock does not, so the two drift apart by the time spent
+//! asleep. After a resume the output stream, the elapsed time the server
+//! has been told and the control connection can all be stale.
+
+use std::{thread, time::Duration};
+
+use crossbeam::channel::{bounded, Receiver, TrySendError};
+use log::info;
+
+const CHECK_EVERY: Duration = Duration::from_secs(2);
+// Allows for the clocks being read a little apart
+const MIN_SLEEP: Duration = Duration::from_secs(1);
+
+#[cfg(target_os = "linux")]
+fn read_clock(clock: libc::clockid_t) -> Option<Duration> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: ts is a valid timespec for the call to fill in
+    if unsafe { libc::clock_gettime(clock, &mut ts) } != 0 {
+        return None;
+    }
+    Some(Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+// The monotonic and boot time clocks, read together
+#[cfg(target_os = "linux")]
+fn clocks() -> Option<(Duration, Duration)> {
+    Some((
+        read_clock(libc::CLOCK_MONOTONIC)?,
+        read_clock(libc::CLOCK_BOOTTIME)?,
+    ))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clocks() -> Option<(Duration, Duration)> {
+    None
+}
+
+/// How long the system slept between two readings of the monotonic and
+/// boot time clocks, if long enough to matter.
+pub fn slept(before: (Duration, Duration), after: (Duration, Duration)) -> Option<Duration> {
+    let awake = after.0.saturating_sub(before.0);
+    let total = after.1.saturating_sub(before.1);
+    Some(total.saturating_sub(awake)).filter(|asleep| *asleep >= MIN_SLEEP)
+}
+
+/// Watch for the system resuming from suspend. Each resume sends how long
+/// the system was asleep. Nothing is sent where the clocks cannot be read.
+pub fn watch() -> Receiver<Duration> {
+    let (resumed_tx, resumed_rx) = bounded(1);
+    let Some(mut last) = clocks() else {
+        info!("Unable to read the boot time clock, suspend will not be noticed");
+        return resumed_rx;
+    };
+
+    thread::spawn(move || loop {
+        thread::sleep(CHECK_EVERY);
+        let Some(now) = clocks() else {
+            return;
+        };
+        if let Some(asleep) = slept(last, now) {
+            if let Err(TrySendError::Disconnected(_)) = resumed_tx.try_send(asleep) {
+                return;
+            }
+        }
+        last = now;
+    });
+    resumed_rx
+}
@@ -0,0 +1,133 @@
+//! Keep playback in step with the wall clock, which the server and the
+//! other players in a sync group go by, when the audio device's clock runs
+//! slightly fast or slow.
+//!
+//! The output position is compared with how far the wall clock has moved
+//! since playback started. The output latency is constant so cancels out.
+//!
+//! Over a long stretch of playback the same comparison, less the
+//! corrections made, gives the rate at which the output clock drifts from
+//! the wall clock. This is logged hourly, as a steady drift of a few tens
+//! of ppm is what pulls the players in a sync group apart.
+
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+
+use crate::stats::Stats;
+
+// Larger errors than this are corrected
+const MAX_ERROR: Duration = Duration::from_millis(20);
+// Playback is never held for longer than this at once, as a hold is
+// silent. A larger error is worked off over the checks that follow.
+const MAX_HOLD: Duration = Duration::from_millis(500);
+// Drift is only worked out over at least this much playback, as the
+// position the output reports is not precise
+const MIN_DRIFT_WINDOW: Duration = Duration::from_secs(60);
+const DRIFT_LOG_EVERY: Duration = Duration::from_secs(60 * 60);
+
+pub enum Correction {
+    /// Playback is behind, skip this much audio.
+    Skip(Duration),
+    /// Playback is ahead, pause for this long.
+    Hold(Duration),
+}
+
+#[derive(Default)]
+pub struct DriftTracker {
+    correct: bool,
+    anchor: Option<(Instant, Duration)>,
+    last_position: Duration,
+    hold_until: Option<Instant>,
+    // Corrections made since the anchor, in ms, positive when skipping
+    corrected_ms: i64,
+    drift_logged: Option<Instant>,
+}
+
+impl DriftTracker {
+    /// The error is always measured, but only corrected if `correct` is
+    /// set as each correction is briefly audible.
+    pub fn new(correct: bool) -> Self {
+        Self {
+            correct,
+            ..Default::default()
+        }
+    }
+
+    /// Start measuring afresh, e.g. when the server moves playback on
+    /// purpose.
+    pub fn reset(&mut self) {
+        self.anchor = None;
+        self.hold_until = None;
+        self.corrected_ms = 0;
+    }
+
+    /// Compare the output position with the wall clock, returning a
+    /// correction if the two have drifted too far apart.
+    pub fn check(&mut self, position: Duration, paused: bool, stats: &Stats) -> Option<Correction> {
+        let now = Instant::now();
+        match self.hold_until {
+            Some(until) if now < until => return None,
+            _ => self.hold_until = None,
+        }
+
+        let last_position = std::mem::replace(&mut self.last_position, position);
+        let (since, from) = match self.anchor {
+            // Stopped, paused or a new track started
+            _ if paused || position.is_zero() || position < last_position => {
+                self.anchor = None;
+                self.corrected_ms = 0;
+                return None;
+            }
+            Some(anchor) => anchor,
+            None => {
+                self.anchor = Some((now, position));
+                return None;
+            }
+        };
+
+        let expected = from + now.duration_since(since);
+        let error_ms = position.as_millis() as i64 - expected.as_millis() as i64;
+        debug!("Sync error {error_ms} ms");
+        stats.set_sync_error(error_ms);
+        self.measure_drift(now.duration_since(since), error_ms, stats);
+
+        if !self.correct || error_ms.unsigned_abs() <= MAX_ERROR.as_millis() as u64 {
+            return None;
+        }
+
+        // The anchor is kept, so the next check shows how well this worked
+        let correction = Duration::from_millis(error_ms.unsigned_abs());
+        let corrections = stats.add_sync_correction();
+        if error_ms < 0 {
+            self.corrected_ms -= error_ms;
+            debug!("Skipping {correction:?} to catch up ({corrections} corrections)");
+            Some(Correction::Skip(correction))
+        } else {
+            let correction = correction.min(MAX_HOLD);
+            self.corrected_ms -= correction.as_millis() as i64;
+            debug!("Holding for {correction:?} to fall back ({corrections} corrections)");
+            self.hold_until = Some(now + correction);
+            Some(Correction::Hold(correction))
+        }
+    }
+
+    // The drift of the output clock over the time since the anchor, with
+    // the corrections taken back out
+    fn measure_drift(&mut self, window: Duration, error_ms: i64, stats: &Stats) {
+        if window < MIN_DRIFT_WINDOW {
+            return;
+        }
+        let drift_ms = error_ms - self.corrected_ms;
+        let ppm = drift_ms as f64 / window.as_secs_f64() * 1000.0;
+        stats.set_clock_drift_ppm(ppm);
+
+        if !matches!(self.drift_logged, Some(at) if at.elapsed() < DRIFT_LOG_EVERY) {
+            self.drift_logged = Some(Instant::now());
+            info!(
+                "Output clock drift {ppm:+.1} ppm over {}s of playback",
+                window.as_secs()
+            );
+        }
+    }
+}
@@ -0,0 +1,78 @@
+//! Short-lived helper threads, tracked so that shutdown can wait for them
+//! rather than have them still running, and perhaps logging, as the
+//! process exits.
+
+use std::{
+    any::Any,
+    mem,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+static HELPERS: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// Spawn a helper thread that [`shutdown`] will wait for.
+pub fn spawn<F>(f: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let handle = thread::spawn(f);
+    if let Ok(mut helpers) = HELPERS.lock() {
+        helpers.retain(|helper| !helper.is_finished());
+        helpers.push(handle);
+    }
+}
+
+pub fn shutting_down() -> bool {
+    SHUTDOWN.load(Ordering::Relaxed)
+}
+
+/// Sleep for `dur`, waking early if shutdown starts. Returns whether the
+/// whole time passed, i.e. whether the caller should carry on.
+pub fn sleep(dur: Duration) -> bool {
+    let end = Instant::now() + dur;
+    while !shutting_down() {
+        let now = Instant::now();
+        if now >= end {
+            return true;
+        }
+        thread::sleep((end - now).min(Duration::from_millis(100)));
+    }
+    false
+}
+
+/// Tell helpers to stop and wait up to `timeout` for them to finish.
+/// Any still running after that are abandoned.
+pub fn shutdown(timeout: Duration) {
+    SHUTDOWN.store(true, Ordering::Relaxed);
+    let deadline = Instant::now() + timeout;
+    let helpers = HELPERS
+        .lock()
+        .map(|mut helpers| mem::take(&mut *helpers))
+        .unwrap_or_default();
+
+    for helper in helpers {
+        while !helper.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        if helper.is_finished() {
+            helper.join().ok();
+        }
+    }
+}
+
+/// The message a panic was raised with, for logging a caught panic.
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => payload
+            .downcast_ref::<String>()
+            .map(String::as_str)
+            .unwrap_or("unknown panic"),
+    }
+}
@@ -0,0 +1,81 @@
+//! TLS for data streams served over HTTPS, e.g. a remote URL handed on by
+//! a server plugin or a server behind a TLS reverse proxy.
+//!
+//! Certificates are checked against the Mozilla roots built into the
+//! player, so no system certificate store is needed, and against the host
+//! named in the request's `Host` header, as the server only gives an
+//! address. The handshake is finished before the request is sent, so that
+//! a refused certificate is reported as such.
+
+use std::{
+    fmt,
+    net::TcpStream,
+    sync::{Arc, OnceLock},
+};
+
+use anyhow::Context;
+use rustls::{
+    crypto::ring, pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore, StreamOwned,
+};
+
+/// A data stream over TLS.
+pub(crate) type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+
+/// The server's certificate was not accepted.
+#[derive(Debug)]
+pub(crate) struct CertificateRefused {
+    host: String,
+    reason: rustls::Error,
+}
+
+impl fmt::Display for CertificateRefused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Refusing the TLS certificate of {}: {}",
+            self.host, self.reason
+        )
+    }
+}
+
+impl std::error::Error for CertificateRefused {}
+
+fn config() -> anyhow::Result<Arc<ClientConfig>> {
+    if let Some(config) = CONFIG.get() {
+        return Ok(config.clone());
+    }
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(CONFIG.get_or_init(|| Arc::new(config)).clone())
+}
+
+/// Start TLS with `host` on a connected socket.
+pub(crate) fn connect(host: &str, socket: TcpStream) -> anyhow::Result<TlsStream> {
+    let name = ServerName::try_from(host.to_owned())
+        .with_context(|| format!("{host} cannot be checked against a TLS certificate"))?;
+    let connection = ClientConnection::new(config()?, name)?;
+    let mut stream = StreamOwned::new(connection, socket);
+    while stream.conn.is_handshaking() {
+        if let Err(e) = stream.conn.complete_io(&mut stream.sock) {
+            let reason = e
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<rustls::Error>());
+            return Err(match reason {
+                Some(reason @ rustls::Error::InvalidCertificate(_)) => CertificateRefused {
+                    host: host.to_owned(),
+                    reason: reason.clone(),
+                }
+                .into(),
+                _ => anyhow::Error::new(e).context(format!("TLS handshake with {host} failed")),
+            });
+        }
+    }
+    Ok(stream)
+}
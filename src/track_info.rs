@@ -0,0 +1,101 @@
+//! What is known about a track from its tags, cleaned up once so that
+//! every place that shows or submits it sees the same, safe, text.
+//!
+//! Tags come from the stream and can hold anything: newlines, control
+//! characters, markup or very long comments. Each field has control
+//! characters removed, runs of whitespace collapsed to a single space and
+//! is cut short with an ellipsis past [`MAX_FIELD_CHARS`].
+
+use symphonia::core::meta::{MetadataRevision, StandardTagKey};
+
+pub const MAX_FIELD_CHARS: usize = 200;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrackInfo {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album_artist: Option<String>,
+    pub album: Option<String>,
+    pub year: Option<String>,
+}
+
+impl TrackInfo {
+    pub fn from_metadata(metadata: &MetadataRevision) -> Self {
+        let mut info = Self::default();
+        for tag in metadata.tags().iter().filter(|tag| tag.is_known()) {
+            let field = match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => &mut info.title,
+                Some(StandardTagKey::Artist) => &mut info.artist,
+                Some(StandardTagKey::AlbumArtist) => &mut info.album_artist,
+                Some(StandardTagKey::Album) => &mut info.album,
+                Some(StandardTagKey::Date) => {
+                    info.year = tag
+                        .value
+                        .to_string()
+                        .split('-')
+                        .find(|s| s.len() == 4 && s.chars().all(|c| c.is_ascii_digit()))
+                        .map(str::to_owned);
+                    continue;
+                }
+                _ => continue,
+            };
+
+            let value = clean(&tag.value.to_string());
+            if !value.is_empty() && field.is_none() {
+                *field = Some(value);
+            }
+        }
+        info
+    }
+
+    /// The artist credited on the track, or else on the album.
+    pub fn track_artist(&self) -> Option<&str> {
+        self.artist.as_deref().or(self.album_artist.as_deref())
+    }
+
+    /// The artist, title and album, as far as they are known.
+    pub fn summary(&self) -> String {
+        let title = self.title.as_deref().unwrap_or("(untitled)");
+        match (self.track_artist(), self.album.as_deref()) {
+            (Some(artist), Some(album)) => format!("{artist} - {title} ({album})"),
+            (Some(artist), None) => format!("{artist} - {title}"),
+            (None, _) => title.to_owned(),
+        }
+    }
+
+    /// The artist credited on the album, or else on the track.
+    pub fn album_artist(&self) -> Option<&str> {
+        self.album_artist.as_deref().or(self.artist.as_deref())
+    }
+}
+
+/// Remove control characters, collapse whitespace and limit the length of
+/// a tag value.
+pub fn clean(value: &str) -> String {
+    let mut cleaned = value
+        .split(|c: char| c.is_whitespace() || c.is_control())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    if let Some((end, _)) = cleaned.char_indices().nth(MAX_FIELD_CHARS) {
+        cleaned.truncate(end);
+        cleaned.truncate(cleaned.trim_end().len());
+        cleaned.push('…');
+    }
+    cleaned
+}
+
+/// Escape text for a notification body, which may be read as markup.
+pub fn escape_markup(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
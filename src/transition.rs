@@ -0,0 +1,98 @@
+//! Fades between tracks, as set for the player on the server.
+//!
+//! The server sends a transition with each stream and the player's own
+//! setting is only used when the server asks for none. A fade in is
+//! applied to the start of a track and a fade out to its end, which needs
+//! the track length from the container. A crossfade is played as a fade
+//! out followed by a fade in as the tracks do not overlap.
+
+use std::time::Duration;
+
+use slimproto::proto::TransType;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TransitionKind {
+    #[default]
+    None,
+    Crossfade,
+    FadeIn,
+    FadeOut,
+    FadeInOut,
+}
+
+impl TransitionKind {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "none" => Some(Self::None),
+            "crossfade" => Some(Self::Crossfade),
+            "fadein" => Some(Self::FadeIn),
+            "fadeout" => Some(Self::FadeOut),
+            "fadeinout" => Some(Self::FadeInOut),
+            _ => None,
+        }
+    }
+
+    fn fades_in(&self) -> bool {
+        matches!(self, Self::Crossfade | Self::FadeIn | Self::FadeInOut)
+    }
+
+    fn fades_out(&self) -> bool {
+        matches!(self, Self::Crossfade | Self::FadeOut | Self::FadeInOut)
+    }
+}
+
+impl From<TransType> for TransitionKind {
+    fn from(value: TransType) -> Self {
+        match value {
+            TransType::None => Self::None,
+            TransType::Crossfade => Self::Crossfade,
+            TransType::FadeIn => Self::FadeIn,
+            TransType::FadeOut => Self::FadeOut,
+            TransType::FadeInOut => Self::FadeInOut,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Transition {
+    pub kind: TransitionKind,
+    pub period: Duration,
+}
+
+impl Transition {
+    /// The transition for a stream given what the server sent, falling back
+    /// to the player's own setting when the server sent none.
+    pub fn from_server(trans_type: TransType, trans_period: Duration, own: Transition) -> Self {
+        let server = Self {
+            kind: trans_type.into(),
+            period: trans_period,
+        };
+        if server.is_none() {
+            own
+        } else {
+            server
+        }
+    }
+
+    pub fn is_none(&self) -> bool {
+        self.kind == TransitionKind::None || self.period.is_zero()
+    }
+
+    /// The gain for the frame at `frame` of a track `frames` long, where
+    /// the length is known.
+    pub fn gain(&self, frame: u64, frames: Option<u64>, sample_rate: u32) -> f32 {
+        if self.is_none() {
+            return 1.0;
+        }
+
+        let period = (self.period.as_secs_f64() * sample_rate as f64).max(1.0);
+        let mut gain = 1.0f64;
+        if self.kind.fades_in() {
+            gain = gain.min(frame as f64 / period);
+        }
+        if let (true, Some(frames)) = (self.kind.fades_out(), frames) {
+            gain = gain.min(frames.saturating_sub(frame) as f64 / period);
+        }
+        gain as f32
+    }
+}
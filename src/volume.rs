@@ -0,0 +1,97 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::threads;
+
+const FADE_STEPS: u32 = 20;
+
+/// The gains set by the server. The server only sends a left and a right
+/// gain, so any channel beyond the front pair, and a mono stream, is
+/// scaled by the average of the two.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Volume {
+    front: [f32; 2],
+    master: f32,
+}
+
+impl Default for Volume {
+    fn default() -> Self {
+        Self {
+            front: [1.0, 1.0],
+            master: 1.0,
+        }
+    }
+}
+
+impl Volume {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, left: f32, right: f32) {
+        self.front = [left, right];
+        self.master = (left + right) / 2.0;
+    }
+
+    /// This volume with every gain multiplied by `factor`.
+    pub fn scaled(&self, factor: f32) -> Self {
+        Self {
+            front: [self.front[0] * factor, self.front[1] * factor],
+            master: self.master * factor,
+        }
+    }
+
+    /// The gain for one channel of a stream with the given number of
+    /// channels. Never panics, whatever the channel layout.
+    pub fn gain(&self, channel: usize, channels: usize) -> f32 {
+        match (channel, channels) {
+            (_, 1) => self.master,
+            (0 | 1, _) => self.front[channel],
+            _ => self.master,
+        }
+    }
+
+    /// Whether any gain turns the audio down.
+    pub fn is_attenuating(&self) -> bool {
+        self.front.iter().any(|gain| *gain < 1.0) || self.master < 1.0
+    }
+}
+
+// A gain in dB, minus infinity when muted
+fn to_db(gain: f32) -> f32 {
+    20.0 * gain.log10()
+}
+
+impl fmt::Display for Volume {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "left {:.1} dB, right {:.1} dB",
+            to_db(self.front[0]),
+            to_db(self.front[1])
+        )
+    }
+}
+
+/// After `delay`, fade `volume` to silence over `length`. The gain is
+/// applied as audio is decoded, so the fade is heard once the audio
+/// already buffered by the output has played.
+pub fn fade_out(volume: Arc<Mutex<Volume>>, delay: Duration, length: Duration) {
+    threads::spawn(move || {
+        if !threads::sleep(delay) {
+            return;
+        }
+        let start = volume.lock().map(|v| *v).unwrap_or_default();
+        for step in 1..=FADE_STEPS {
+            if !threads::sleep(length / FADE_STEPS) {
+                return;
+            }
+            if let Ok(mut vol) = volume.lock() {
+                *vol = start.scaled(1.0 - step as f32 / FADE_STEPS as f32);
+            }
+        }
+    });
+}